@@ -0,0 +1,305 @@
+use db_api::{Client, ClientOrder, Order};
+use hmac::{Hmac, Mac};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use sha2::Sha256;
+use std::{
+    ops::RangeInclusive,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// What the generated orders look like.
+#[derive(Debug)]
+pub struct GeneratorConfig {
+    pub clients: usize,
+    /// Work pieces to order, each with its relative weight.
+    /// Must not be empty when generating orders.
+    pub pieces: Vec<(String, u32)>,
+    pub quantity: RangeInclusive<i32>,
+    /// Days after the current day the orders are due.
+    pub due_in: RangeInclusive<i32>,
+    /// Penalties, in cents.
+    pub penalty: RangeInclusive<i64>,
+    /// Share of the documents, from 0 to 1, that are malformed.
+    pub malformed: f64,
+    /// Shared secret of every client, orders are signed with it when set.
+    pub secret: Option<String>,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        Self {
+            clients: 5,
+            pieces: Vec::new(),
+            quantity: 1..=10,
+            due_in: 1..=30,
+            penalty: 0..=10_000,
+            malformed: 0.0,
+            secret: None,
+        }
+    }
+}
+
+/// A generated document, ready to be sent.
+#[derive(Debug)]
+pub enum Document {
+    Order {
+        client: String,
+        number: i32,
+        xml: String,
+    },
+    /// A document the listener can not decode.
+    Malformed { xml: String },
+}
+
+/// Generates random `ClientOrder` documents.
+///
+/// Every run gets its own client names, so order numbers never clash
+/// with orders placed by earlier runs.
+pub struct Generator {
+    config: GeneratorConfig,
+    rng: StdRng,
+    run_id: String,
+    current_day: i32,
+    next_numbers: Vec<i32>,
+}
+
+impl Generator {
+    pub fn new(
+        config: GeneratorConfig,
+        seed: u64,
+        run_id: String,
+        current_day: i32,
+    ) -> Self {
+        let next_numbers = vec![1; config.clients.max(1)];
+        Self {
+            config,
+            rng: StdRng::seed_from_u64(seed),
+            run_id,
+            current_day,
+            next_numbers,
+        }
+    }
+
+    pub fn client_name(&self, client: usize) -> String {
+        format!("LoadTest {} #{}", self.run_id, client + 1)
+    }
+
+    pub fn clients(&self) -> usize {
+        self.next_numbers.len()
+    }
+
+    pub fn next_document(&mut self) -> Document {
+        let client = self.rng.gen_range(0..self.clients());
+        let name = self.client_name(client);
+        let number = self.next_numbers[client];
+
+        if self.rng.gen_bool(self.config.malformed.clamp(0.0, 1.0)) {
+            return Document::Malformed {
+                xml: self.malformed(&name, number),
+            };
+        }
+        self.next_numbers[client] += 1;
+
+        let piece = self.piece();
+        let quantity = self.rng.gen_range(self.config.quantity.clone());
+        let due_date =
+            self.current_day + self.rng.gen_range(self.config.due_in.clone());
+        let late_pen = money(self.rng.gen_range(self.config.penalty.clone()));
+        let early_pen = money(self.rng.gen_range(self.config.penalty.clone()));
+
+        let mut order = ClientOrder {
+            client: Client { name_id: name },
+            order: Order {
+                number,
+                work_piece: piece,
+                quantity,
+                due_date,
+                late_pen,
+                early_pen,
+            },
+            signature: None,
+            timestamp: None,
+            nonce: None,
+        };
+        if let Some(secret) = &self.config.secret {
+            order.timestamp = Some(unix_now());
+            order.nonce = Some(format!("{}-{number}", self.run_id));
+            order.signature = Some(sign(secret, &order));
+        }
+
+        Document::Order {
+            xml: to_xml(&order),
+            client: order.client.name_id,
+            number,
+        }
+    }
+
+    fn piece(&mut self) -> String {
+        let total: u32 = self.config.pieces.iter().map(|(_, w)| w).sum();
+        let mut pick = self.rng.gen_range(0..total.max(1));
+        for (piece, weight) in &self.config.pieces {
+            if pick < *weight {
+                return piece.clone();
+            }
+            pick -= weight;
+        }
+        // Every weight is 0.
+        let (piece, _) = &self.config.pieces[0];
+        piece.clone()
+    }
+
+    /// A complete document that breaks the protocol in one of a few ways.
+    fn malformed(&mut self, name: &str, number: i32) -> String {
+        match self.rng.gen_range(0..3) {
+            0 => format!(
+                "<ClientOrder>\n  <Client NameId=\"{name}\"/>\n  \
+                <Order Number=\"n{number}\" WorkPiece=\"P5\" Quantity=\"1\" \
+                DueDate=\"1\" LatePen=\"€1,00\" EarlyPen=\"€1,00\"/>\n\
+                </ClientOrder>\n"
+            ),
+            1 => format!(
+                "<ClientOrder>\n  <Client NameId=\"{name}\"/>\n  \
+                <Order Number=\"{number}\" Quantity=\"1\" \
+                DueDate=\"1\" LatePen=\"€1,00\" EarlyPen=\"€1,00\"/>\n\
+                </ClientOrder>\n"
+            ),
+            _ => format!(
+                "<ClientOrder>\n  <Client NameId=\"{name}\"/>\n\
+                </ClientOrder>\n"
+            ),
+        }
+    }
+}
+
+/// Writes an order the way the listener reads it, along with its
+/// signature if it has one.
+fn to_xml(order: &ClientOrder) -> String {
+    let ClientOrder {
+        client,
+        order:
+            Order {
+                number,
+                work_piece,
+                quantity,
+                due_date,
+                late_pen,
+                early_pen,
+            },
+        ..
+    } = order;
+    let mut attributes = String::new();
+    if let (Some(signature), Some(timestamp), Some(nonce)) =
+        (&order.signature, order.timestamp, &order.nonce)
+    {
+        attributes = format!(
+            " Signature=\"{signature}\" Timestamp=\"{timestamp}\" \
+            Nonce=\"{nonce}\""
+        );
+    }
+
+    format!(
+        "<ClientOrder{attributes}>\n  \
+        <Client NameId=\"{}\"/>\n  \
+        <Order Number=\"{number}\" WorkPiece=\"{work_piece}\" \
+        Quantity=\"{quantity}\" DueDate=\"{due_date}\" \
+        LatePen=\"{late_pen}\" EarlyPen=\"{early_pen}\"/>\n\
+        </ClientOrder>\n",
+        client.name_id
+    )
+}
+
+/// Signs an order the way the listener checks it, with the hex encoded
+/// HMAC-SHA256 of its signing string.
+fn sign(secret: &str, order: &ClientOrder) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(order.signing_string().as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64)
+}
+
+/// Formats cents the way the mock client orders do, e.g. `€5,74`.
+fn money(cents: i64) -> String {
+    format!("€{},{:02}", cents / 100, cents % 100)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_orders_decode() {
+        let config = GeneratorConfig {
+            pieces: vec![("P5".to_string(), 3), ("P9".to_string(), 1)],
+            ..Default::default()
+        };
+        let mut generator = Generator::new(config, 7, "1".to_string(), 10);
+
+        for _ in 0..50 {
+            let Document::Order {
+                client,
+                number,
+                xml,
+            } = generator.next_document()
+            else {
+                panic!("no malformed documents were asked for");
+            };
+
+            let order: ClientOrder = serde_xml_rs::from_str(&xml).unwrap();
+            assert_eq!(order.client.name_id, client);
+            assert_eq!(order.order.number, number);
+            assert!(["P5", "P9"].contains(&order.order.work_piece.as_str()));
+            assert!(order.order.due_date > 10);
+            assert!(db_api::parse_money(&order.order.late_pen).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_signed_orders() {
+        let config = GeneratorConfig {
+            pieces: vec![("P5".to_string(), 1)],
+            secret: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let mut generator = Generator::new(config, 7, "1".to_string(), 10);
+
+        // Nonces only need to be unique for each client.
+        let mut nonces = std::collections::HashSet::new();
+        for _ in 0..20 {
+            let Document::Order { xml, .. } = generator.next_document() else {
+                panic!("no malformed documents were asked for");
+            };
+            let order: ClientOrder = serde_xml_rs::from_str(&xml).unwrap();
+            assert!(order.timestamp.is_some());
+            assert_eq!(order.signature, Some(sign("secret", &order)));
+            assert!(nonces.insert((order.client.name_id, order.nonce)));
+        }
+    }
+
+    #[test]
+    fn test_malformed_documents_do_not_decode() {
+        let config = GeneratorConfig {
+            malformed: 1.0,
+            ..Default::default()
+        };
+        let mut generator = Generator::new(config, 7, "1".to_string(), 10);
+
+        for _ in 0..20 {
+            let Document::Malformed { xml } = generator.next_document() else {
+                panic!("only malformed documents were asked for");
+            };
+            assert!(serde_xml_rs::from_str::<ClientOrder>(&xml).is_err());
+        }
+    }
+
+    #[test]
+    fn test_money() {
+        assert_eq!(money(574), "€5,74");
+        assert_eq!(money(5), "€0,05");
+    }
+}
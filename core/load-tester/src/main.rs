@@ -0,0 +1,357 @@
+mod generator;
+mod report;
+
+use anyhow::{anyhow, bail};
+use db_api::{
+    get_client_order_numbers, get_current_day, set_client_secret, Catalog,
+    Client, PieceKind,
+};
+use generator::{Document, Generator, GeneratorConfig};
+use report::Stats;
+use std::{
+    env,
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::net::UdpSocket;
+
+const USAGE: &str = "\
+Sends random client orders to udp-listener and reports how many made it.
+
+Usage: load-tester [OPTIONS]
+
+Options:
+  --target ADDR       listener address [default: 127.0.0.1:8080]
+  --count N           documents to send [default: 1000]
+  --rate N            documents per second [default: 100]
+  --clients N         number of clients [default: 5]
+  --pieces LIST       piece weights, e.g. P5=3,P9=1 [default: every final product]
+  --quantity MIN-MAX  order quantities [default: 1-10]
+  --due-in MIN-MAX    days from today orders are due [default: 1-30]
+  --penalty MIN-MAX   penalties, in cents [default: 0-10000]
+  --malformed RATIO   share of malformed documents, 0 to 1 [default: 0]
+  --secret SECRET     sign orders with this client secret [default: unsigned]
+  --drain SECS        time to wait for replies after sending [default: 5]
+  --seed N            random seed [default: random]";
+
+#[derive(Debug)]
+struct Config {
+    target: String,
+    count: u64,
+    rate: u64,
+    drain: Duration,
+    seed: u64,
+    generator: GeneratorConfig,
+}
+
+impl Config {
+    fn from_args(
+        mut args: impl Iterator<Item = String>,
+    ) -> Result<Config, anyhow::Error> {
+        let mut config = Config {
+            target: "127.0.0.1:8080".to_string(),
+            count: 1000,
+            rate: 100,
+            drain: Duration::from_secs(5),
+            seed: rand::random(),
+            generator: GeneratorConfig::default(),
+        };
+
+        while let Some(flag) = args.next() {
+            if flag == "--help" || flag == "-h" {
+                println!("{USAGE}");
+                std::process::exit(0);
+            }
+            let Some(value) = args.next() else {
+                bail!("missing value for {flag}\n\n{USAGE}");
+            };
+
+            let generator = &mut config.generator;
+            match flag.as_str() {
+                "--target" => config.target = value,
+                "--count" => config.count = value.parse()?,
+                "--rate" => config.rate = value.parse()?,
+                "--drain" => config.drain = Duration::from_secs(value.parse()?),
+                "--seed" => config.seed = value.parse()?,
+                "--clients" => generator.clients = value.parse()?,
+                "--pieces" => generator.pieces = parse_pieces(&value)?,
+                "--quantity" => generator.quantity = parse_range(&value)?,
+                "--due-in" => generator.due_in = parse_range(&value)?,
+                "--penalty" => generator.penalty = parse_range(&value)?,
+                "--malformed" => generator.malformed = value.parse()?,
+                "--secret" => generator.secret = Some(value),
+                _ => bail!("unknown option {flag}\n\n{USAGE}"),
+            }
+        }
+
+        if config.rate == 0 {
+            bail!("--rate must be greater than 0");
+        }
+        if config.generator.clients == 0 {
+            bail!("--clients must be greater than 0");
+        }
+        if !(0.0..=1.0).contains(&config.generator.malformed) {
+            bail!("--malformed must be between 0 and 1");
+        }
+        Ok(config)
+    }
+}
+
+/// Parses piece weights like `P5=3,P9`, where a missing weight is 1.
+/// The pieces are checked against the catalog by [`orderable_pieces`].
+fn parse_pieces(list: &str) -> Result<Vec<(String, u32)>, anyhow::Error> {
+    list.split(',')
+        .map(|item| {
+            let (name, weight) = item.split_once('=').unwrap_or((item, "1"));
+            Ok((name.trim().to_string(), weight.trim().parse()?))
+        })
+        .collect()
+}
+
+/// Checks that clients can order the pieces. Without pieces, every
+/// final product of the catalog is ordered with the same weight.
+fn orderable_pieces(
+    pieces: Vec<(String, u32)>,
+    catalog: &Catalog,
+) -> Result<Vec<(String, u32)>, anyhow::Error> {
+    if pieces.is_empty() {
+        return Ok(catalog
+            .final_products()
+            .map(|piece| (piece.name.clone(), 1))
+            .collect());
+    }
+
+    for (name, _) in &pieces {
+        if catalog.piece(name)?.kind != PieceKind::FinalProduct {
+            bail!("{name} is not a piece clients can order");
+        }
+    }
+    Ok(pieces)
+}
+
+/// Parses a range like `1-10`, or a single value like `5`.
+fn parse_range<T>(range: &str) -> Result<RangeInclusive<T>, anyhow::Error>
+where
+    T: std::str::FromStr + PartialOrd + Copy,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let (min, max) = range.split_once('-').unwrap_or((range, range));
+    let (min, max) = (min.trim().parse()?, max.trim().parse()?);
+    if min > max {
+        bail!("empty range {range}");
+    }
+    Ok(min..=max)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    dotenv::dotenv().ok();
+    tracing_subscriber::fmt::init();
+
+    let mut config = Config::from_args(env::args().skip(1))?;
+
+    let database_url = match env::var("DATABASE_URL") {
+        Ok(url) => url,
+        Err(e) => return Err(anyhow!(e)),
+    };
+    tracing::info!("Connecting to database...");
+    let pool = sqlx::PgPool::connect(&database_url).await?;
+
+    let catalog = Catalog::load(&pool).await?;
+    config.generator.pieces =
+        orderable_pieces(config.generator.pieces, &catalog)?;
+    if config.generator.pieces.is_empty() {
+        bail!("the catalog has no pieces clients can order");
+    }
+
+    let current_day = get_current_day(&pool).await?;
+    // The random part keeps runs started in the same millisecond apart.
+    let started_at = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let run_id =
+        format!("{}-{:04x}", started_at.as_millis(), rand::random::<u16>());
+    let secret = config.generator.secret.clone();
+    let mut generator = Generator::new(
+        config.generator,
+        config.seed,
+        run_id.clone(),
+        current_day,
+    );
+
+    // Clients are new every run, so they get the secret before any of
+    // their orders are sent.
+    if let Some(secret) = &secret {
+        for client in 0..generator.clients() {
+            let client = Client {
+                name_id: generator.client_name(client),
+            };
+            set_client_secret(&client, secret, &pool).await?;
+        }
+    }
+
+    let socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    socket.connect(&config.target).await?;
+    tracing::info!(
+        "Sending {} documents to {} at {} per second (run {run_id}, seed {})",
+        config.count,
+        config.target,
+        config.rate,
+        config.seed
+    );
+
+    let stats = Arc::new(Mutex::new(Stats::default()));
+    let receiver = tokio::spawn(receive_acks(socket.clone(), stats.clone()));
+
+    let mut ticks = tokio::time::interval(Duration::from_secs_f64(
+        1.0 / config.rate as f64,
+    ));
+    let started = Instant::now();
+    for _ in 0..config.count {
+        ticks.tick().await;
+        let document = generator.next_document();
+        let xml = match &document {
+            Document::Order { xml, .. } | Document::Malformed { xml } => xml,
+        };
+
+        // Register the order first, so a quick reply always finds it.
+        let sent = Instant::now();
+        match &document {
+            Document::Order { client, number, .. } => stats
+                .lock()
+                .unwrap()
+                .order_sent(client.clone(), *number, sent),
+            Document::Malformed { .. } => {
+                stats.lock().unwrap().malformed_sent()
+            }
+        }
+        if let Err(e) = socket.send(xml.as_bytes()).await {
+            tracing::error!("Error sending document: {e}");
+        }
+    }
+    let elapsed = started.elapsed();
+
+    tracing::info!("Waiting {:?} for the last replies", config.drain);
+    tokio::time::sleep(config.drain).await;
+    receiver.abort();
+
+    // The acknowledgements can be lost like any datagram, so the orders
+    // table is what tells which orders really were placed.
+    let mut placed = 0;
+    for client in 0..generator.clients() {
+        let client = Client {
+            name_id: generator.client_name(client),
+        };
+        placed += get_client_order_numbers(&client, &pool).await?.len();
+    }
+
+    let stats = stats.lock().unwrap();
+    print_report(&stats, placed, elapsed);
+    Ok(())
+}
+
+async fn receive_acks(socket: Arc<UdpSocket>, stats: Arc<Mutex<Stats>>) {
+    let mut buf = vec![0; 65_507];
+    loop {
+        match socket.recv(&mut buf).await {
+            Ok(length) => {
+                let reply = String::from_utf8_lossy(&buf[..length]);
+                stats.lock().unwrap().ack_received(&reply, Instant::now());
+            }
+            Err(e) => tracing::error!("Error receiving reply: {e}"),
+        }
+    }
+}
+
+fn print_report(stats: &Stats, placed: usize, elapsed: Duration) {
+    let sent = stats.orders_sent + stats.malformed_sent;
+    let percent = |part: f64, whole: f64| {
+        if whole == 0.0 {
+            0.0
+        } else {
+            100.0 * part / whole
+        }
+    };
+
+    println!("Sent {sent} documents in {elapsed:.2?}");
+    println!(
+        "  {:.1} documents per second",
+        sent as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "  {} orders, {} malformed",
+        stats.orders_sent, stats.malformed_sent
+    );
+    println!(
+        "Placed {placed} of {} orders ({:.1}%)",
+        stats.orders_sent,
+        percent(placed as f64, stats.orders_sent as f64)
+    );
+
+    println!("Acknowledged orders:");
+    for (status, count) in &stats.statuses {
+        println!("  {status}: {count}");
+    }
+    println!("  no reply: {}", stats.unacknowledged());
+
+    println!("Rejected documents:");
+    for (reason, count) in &stats.rejected_documents {
+        println!("  {count} x {reason}");
+    }
+
+    println!("Latency:");
+    for percentile in [50.0, 90.0, 99.0, 100.0] {
+        if let Some(latency) = stats.latency(percentile) {
+            println!("  p{percentile}: {latency:.2?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db_api::MemoryStorage;
+
+    #[test]
+    fn test_parse_args() {
+        let args = ["--count", "10", "--pieces", "P5=3,P9", "--due-in", "2-4"];
+        let config =
+            Config::from_args(args.into_iter().map(String::from)).unwrap();
+
+        assert_eq!(config.count, 10);
+        assert_eq!(
+            config.generator.pieces,
+            vec![("P5".to_string(), 3), ("P9".to_string(), 1)]
+        );
+        assert_eq!(config.generator.due_in, 2..=4);
+
+        let args = ["--pieces", "P5=x"].into_iter().map(String::from);
+        assert!(Config::from_args(args).is_err());
+        let args = ["--quantity", "5-1"].into_iter().map(String::from);
+        assert!(Config::from_args(args).is_err());
+        let args = ["--clients", "0"].into_iter().map(String::from);
+        assert!(Config::from_args(args).is_err());
+        for ratio in ["NaN", "-0.5", "2"] {
+            let args = ["--malformed", ratio].into_iter().map(String::from);
+            assert!(Config::from_args(args).is_err(), "{ratio}");
+        }
+    }
+
+    #[test]
+    fn test_orderable_pieces() {
+        let catalog = MemoryStorage::catalog();
+        let pieces = |names: &[&str]| {
+            names.iter().map(|name| (name.to_string(), 1)).collect()
+        };
+
+        assert_eq!(
+            orderable_pieces(Vec::new(), &catalog).unwrap(),
+            pieces(&["P5", "P6", "P7", "P9"])
+        );
+        assert_eq!(
+            orderable_pieces(pieces(&["P9"]), &catalog).unwrap(),
+            pieces(&["P9"])
+        );
+        assert!(orderable_pieces(pieces(&["P1"]), &catalog).is_err());
+        assert!(orderable_pieces(pieces(&["P10"]), &catalog).is_err());
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+/// What was sent and what came back during a run.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Orders waiting for an acknowledgement, with the time they were sent.
+    pending: HashMap<(String, i32), Instant>,
+    pub orders_sent: u64,
+    pub malformed_sent: u64,
+    pub latencies: Vec<Duration>,
+    /// Acknowledged orders by status.
+    pub statuses: BTreeMap<String, u64>,
+    /// Documents rejected as a whole, by reason.
+    pub rejected_documents: BTreeMap<String, u64>,
+}
+
+impl Stats {
+    pub fn order_sent(&mut self, client: String, number: i32, at: Instant) {
+        self.orders_sent += 1;
+        self.pending.insert((client, number), at);
+    }
+
+    pub fn malformed_sent(&mut self) {
+        self.malformed_sent += 1;
+    }
+
+    /// Records an `OrderAck` reply from the listener.
+    pub fn ack_received(&mut self, reply: &str, at: Instant) {
+        // Each entry runs to the end of the reply, but the first match of
+        // an attribute is always the entry's own.
+        let entries = reply
+            .match_indices("<Order ")
+            .map(|(start, _)| &reply[start..])
+            .collect::<Vec<_>>();
+        if entries.is_empty() {
+            let reason = attribute(reply, "Reason").unwrap_or_default();
+            *self
+                .rejected_documents
+                .entry(reason.to_string())
+                .or_default() += 1;
+            return;
+        }
+
+        for entry in entries {
+            let (Some(client), Some(number), Some(status)) = (
+                attribute(entry, "NameId"),
+                attribute(entry, "Number").and_then(|n| n.parse().ok()),
+                attribute(entry, "Status"),
+            ) else {
+                continue;
+            };
+
+            if let Some(sent) = self.pending.remove(&(client.into(), number)) {
+                self.latencies.push(at - sent);
+            }
+            *self.statuses.entry(status.to_string()).or_default() += 1;
+        }
+    }
+
+    /// Orders that never got an acknowledgement.
+    pub fn unacknowledged(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Latency at the given percentile, from 0 to 100.
+    pub fn latency(&self, percentile: f64) -> Option<Duration> {
+        let mut latencies = self.latencies.clone();
+        latencies.sort();
+        let rank = (percentile / 100.0 * latencies.len() as f64).ceil();
+        let index = (rank as usize).clamp(1, latencies.len().max(1)) - 1;
+        latencies.get(index).copied()
+    }
+}
+
+/// Gets the value of an attribute from a fragment of XML.
+/// Entities are left as they are, the listener only escapes reasons.
+fn attribute<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!(" {name}=\""))? + name.len() + 3;
+    let length = xml[start..].find('"')?;
+    Some(&xml[start..start + length])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ack_received() {
+        let now = Instant::now();
+        let mut stats = Stats::default();
+        stats.order_sent("Kling Inc".to_string(), 1, now);
+        stats.order_sent("Kling Inc".to_string(), 2, now);
+
+        let later = now + Duration::from_millis(20);
+        stats.ack_received(
+            "<OrderAck>\n  \
+            <Order NameId=\"Kling Inc\" Number=\"1\" Status=\"accepted\"/>\n\
+            </OrderAck>\n",
+            later,
+        );
+        stats.ack_received(
+            "<OrderAck Status=\"rejected\" Reason=\"bad\"/>\n",
+            later,
+        );
+
+        assert_eq!(stats.latencies, vec![Duration::from_millis(20)]);
+        assert_eq!(stats.statuses.get("accepted"), Some(&1));
+        assert_eq!(stats.rejected_documents.get("bad"), Some(&1));
+        assert_eq!(stats.unacknowledged(), 1);
+    }
+
+    #[test]
+    fn test_latency_percentiles() {
+        let mut stats = Stats::default();
+        assert_eq!(stats.latency(50.0), None);
+
+        stats.latencies = (1..=100).rev().map(Duration::from_millis).collect();
+        assert_eq!(stats.latency(50.0), Some(Duration::from_millis(50)));
+        assert_eq!(stats.latency(99.0), Some(Duration::from_millis(99)));
+        assert_eq!(stats.latency(100.0), Some(Duration::from_millis(100)));
+        assert_eq!(stats.latency(0.0), Some(Duration::from_millis(1)));
+    }
+}
@@ -20,15 +20,16 @@ async fn main() -> Result<(), anyhow::Error> {
         tracing::error!("Error running migrations: {e}");
         return Err(anyhow!(e));
     }
-    let notification_listener =
-        sqlx::postgres::PgListener::connect(&database_url).await?;
+    let subscriber = db_api::events::Subscriber::connect(&database_url).await?;
 
     let pool = sqlx::postgres::PgPool::connect(&database_url).await?;
     tracing::info!("DB connection and initializtion successfull.");
 
-    let mut resolver = resolver::Resolver::new(pool, notification_listener);
+    // Pieces added later are only known after a restart.
+    let catalog = db_api::Catalog::load(&pool).await?;
+    let resolver = resolver::Resolver::new(pool, catalog);
 
-    resolver.run().await?;
+    resolver.run(subscriber).await?;
 
     Ok(())
 }
@@ -1,88 +1,208 @@
-use std::collections::HashMap;
-
-use db_api::{Bom, Order, Recipe, Transformation};
-use sqlx::{postgres::PgListener, PgPool};
-
-pub struct Resolver {
-    pool: PgPool,
-    listener: PgListener,
+use std::{cmp::Ordering::Equal, collections::HashMap, time::Duration};
+
+use db_api::{
+    events::{
+        AnyEvent, NewBomEntry, NewOrder, OrderAmended, OrderCancelled,
+        Subscriber,
+    },
+    Bom, Catalog, Job, JobStatus, Order, PieceId, Recipe, Storage,
+    Transformation,
+};
+
+/// How often the job queue is checked when no notification arrives.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Turns client orders into BOM entries.
+///
+/// All data goes through a [`Storage`], which is a `PgPool` when running
+/// and a `MemoryStorage` in tests. Pieces are looked up in a [`Catalog`]
+/// loaded at startup.
+pub struct Resolver<S> {
+    storage: S,
+    catalog: Catalog,
 }
 
-impl Resolver {
-    pub fn new(pool: PgPool, listener: PgListener) -> Self {
-        Self { pool, listener }
+impl<S: Storage> Resolver<S> {
+    pub fn new(storage: S, catalog: Catalog) -> Self {
+        Self { storage, catalog }
     }
 
     pub async fn handle_new_order(
         &self,
-        payload: &str,
+        NewOrder { order_id }: NewOrder,
     ) -> Result<(), anyhow::Error> {
-        let order_id: i64 = payload.parse()?;
         tracing::info!("Received new order with id {}", order_id);
         self.generate_bom_entries(order_id).await?;
         Ok(())
     }
 
+    /// Handles the BOM entries generated for an order.
+    /// The event only has the id of the order, as the entries of a large
+    /// order would not fit in a notification.
     pub async fn handle_new_bom_entry(
         &self,
-        payload: &str,
+        NewBomEntry { order_id }: NewBomEntry,
     ) -> Result<(), anyhow::Error> {
-        tracing::info!("Received new bom entries {}", payload);
-
-        let ids = payload
-            .split(',')
-            .filter_map(|s| s.parse().ok())
-            .collect::<Vec<i64>>();
+        tracing::info!("Received new bom entries of order {}", order_id);
 
-        if ids.is_empty() {
-            return Err(anyhow::anyhow!("Invalid payload: {}", payload));
+        let entries = self.storage.get_bom_by_order(order_id).await?;
+        if entries.is_empty() {
+            // The order was cancelled or amended since, nothing to do.
+            tracing::debug!("Order {} has no BOM entries left", order_id);
+            return Ok(());
         }
 
-        tracing::debug!("Parsed new bom entries {:#?}", ids);
-
-        let entries = ids.iter().fold(Vec::new(), |mut acc, id| {
-            acc.push(Bom::get_by_id(*id, &self.pool));
-            acc
-        });
-
-        let entries = futures::future::try_join_all(entries).await?;
+        tracing::debug!("Got {} new bom entries", entries.len());
 
         //TODO: query the MES and check which lines are compatible with
         //      the new bom entries. If no line is compatible, log a warning
 
-        let order = db_api::get_order(entries[0].order_id, &self.pool).await?;
+        let _order = self.storage.get_order(order_id).await?;
+
+        Ok(())
+    }
+
+    pub async fn handle_order_cancelled(
+        &self,
+        OrderCancelled { order_id }: OrderCancelled,
+    ) -> Result<(), anyhow::Error> {
+        tracing::info!("Order with id {} was cancelled", order_id);
 
+        let deleted = self.storage.delete_bom_by_order(order_id).await?;
+        tracing::info!("Dropped {} BOM entries of order {}", deleted, order_id);
         Ok(())
     }
 
-    pub async fn run(&mut self) -> Result<(), anyhow::Error> {
-        // TODO: lauch a task to generate entries for existing orders
-        // should be done in a separate task to avoid blocking the main loop
-        // tokio::spawn(...)
+    /// Regenerates the BOM entries of an amended order when the
+    /// amount of final pieces no longer matches the ordered quantity.
+    pub async fn handle_order_amended(
+        &self,
+        OrderAmended { order_id }: OrderAmended,
+    ) -> Result<(), anyhow::Error> {
+        tracing::info!("Order with id {} was amended", order_id);
+
+        let order = self.storage.get_order(order_id).await?;
+        let entries = self.storage.get_bom_by_order(order_id).await?;
+        if entries
+            .iter()
+            .find(|entry| entry.step_number == entry.steps_total)
+            .is_some_and(|entry| entry.pieces_total == order.quantity)
+        {
+            tracing::debug!("BOM entries of order {} are up to date", order_id);
+            return Ok(());
+        }
+
+        self.generate_bom_entries(order_id).await?;
+        Ok(())
+    }
 
+    /// Works through the job queue, waking up when a notification says
+    /// there is new work, and every [`POLL_INTERVAL`] to pick up retries.
+    /// Jobs queued while the resolver was down are handled on start.
+    pub async fn run(
+        &self,
+        mut subscriber: Subscriber,
+    ) -> Result<(), anyhow::Error> {
         //start listening on the notification channels
-        use db_api::NotificationChannel as Nc;
-        self.listener.listen_all(Nc::ALL_STR).await?;
+        subscriber.subscribe::<NewOrder>().await?;
+        subscriber.subscribe::<NewBomEntry>().await?;
+        subscriber.subscribe::<OrderCancelled>().await?;
+        subscriber.subscribe::<OrderAmended>().await?;
 
-        // TODO: handle and log errors instead of returning them
         loop {
-            let notification = self.listener.recv().await?;
-            let payload = notification.payload();
-            let channel = Nc::from(notification.channel());
+            self.run_jobs().await?;
 
-            match channel {
-                Nc::NewOrder => {
-                    self.handle_new_order(payload).await?;
-                }
-                Nc::NewBomEntry => {
-                    self.handle_new_bom_entry(payload).await?;
+            // The event itself is not needed, the job has everything.
+            match tokio::time::timeout(POLL_INTERVAL, subscriber.recv()).await {
+                Ok(Ok(event)) => tracing::debug!("Woken up by {:?}", event),
+                Ok(Err(e @ db_api::Error::InvalidEvent { .. })) => {
+                    tracing::warn!("Ignoring notification: {}", e)
                 }
-                Nc::Unknown => {
-                    tracing::warn!(
-                        "Received notification on unknown channel: {:#?}",
-                        notification
-                    );
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Handles jobs until none is ready to run.
+    /// Returns the number of jobs handled.
+    ///
+    /// Without a database connection there is nothing left to do, so a
+    /// connection error is returned and the job is left to be claimed
+    /// again later. Any other error only fails the job that caused it.
+    /// A job whose lease ran out while it was handled belongs to the
+    /// worker that claimed it since, and is left alone.
+    pub async fn run_jobs(&self) -> Result<usize, db_api::Error> {
+        let mut handled = 0;
+        while let Some(job) = self.storage.claim_job().await? {
+            handled += 1;
+            let result = self.handle_job(&job).await;
+            let Job {
+                id,
+                channel,
+                ref payload,
+                attempts,
+            } = job;
+
+            let e = match result {
+                Ok(()) => {
+                    match self.storage.complete_job(&job).await {
+                        Err(db_api::Error::NotFound) => tracing::warn!(
+                            "Lease of {} job {} ran out before it was done",
+                            channel,
+                            id
+                        ),
+                        result => result?,
+                    }
+                    continue;
                 }
+                Err(e) => match e.downcast::<db_api::Error>() {
+                    Ok(e @ db_api::Error::Connection(_)) => return Err(e),
+                    Ok(e) => e.to_string(),
+                    Err(e) => e.to_string(),
+                },
+            };
+
+            match self.storage.fail_job(&job, &e).await {
+                Ok(JobStatus::Failed) => tracing::error!(
+                    "Giving up on {} job {} after {} attempts: {}",
+                    channel,
+                    payload,
+                    attempts,
+                    e
+                ),
+                Ok(_) => tracing::warn!(
+                    "Error handling {} job {}, will retry: {}",
+                    channel,
+                    payload,
+                    e
+                ),
+                Err(db_api::Error::NotFound) => tracing::warn!(
+                    "Lease of {} job {} ran out before it failed: {}",
+                    channel,
+                    id,
+                    e
+                ),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(handled)
+    }
+
+    async fn handle_job(&self, job: &Job) -> Result<(), anyhow::Error> {
+        match job.event()? {
+            AnyEvent::NewOrder(event) => self.handle_new_order(event).await,
+            AnyEvent::NewBomEntry(event) => {
+                self.handle_new_bom_entry(event).await
+            }
+            AnyEvent::OrderCancelled(event) => {
+                self.handle_order_cancelled(event).await
+            }
+            AnyEvent::OrderAmended(event) => {
+                self.handle_order_amended(event).await
+            }
+            AnyEvent::OrderStatusChanged(_) => {
+                Err(anyhow::anyhow!("No handler for {} jobs", job.channel))
             }
         }
     }
@@ -93,11 +213,11 @@ impl Resolver {
         bom: &mut [Bom],
     ) -> Result<(), anyhow::Error> {
         let deadline = order.due_date;
-        let max_concurrent_orders = 3; // TODO: get this from MES or config
+        let _max_concurrent_orders = 3; // TODO: get this from MES or config
 
         // Group the BOM entries by their stage in the production process
         // of the final piece.
-        bom.sort_by(|a, b| a.step_number.cmp(&b.step_number));
+        bom.sort_by_key(|entry| entry.step_number);
 
         // Calculate the ideal production plan
         //
@@ -110,14 +230,15 @@ impl Resolver {
         //
         // The plan is calculated in such a way that the production
         // process is as efficient as possible, and that the pieces
-        // are ready to be shipped by the due date.
+        // are ready to be shipped by the due date. A step takes
+        // `Transformation::timeslots_on` the line it is planned on.
 
         //run back from the due date and last stages and assign bom entries
         //production time slots
 
         //NOTE: for now lets try to complete the order a day before the due date
-        for day in (1..=deadline - 1).rev() {
-            for timeslot in (1..=12).rev() {
+        for _day in (1..=deadline - 1).rev() {
+            for _timeslot in (1..=12).rev() {
                 todo!("assing bom entries to production time slots");
             }
         }
@@ -128,7 +249,7 @@ impl Resolver {
     /// Assumes the recipe is flat and has no cycles.
     /// Also assumes that the recipe is ordered from the final
     /// to the root piece.
-    fn map_flat_recipe(recipe: Recipe) -> HashMap<i64, Recipe> {
+    fn map_flat_recipe(recipe: Recipe) -> HashMap<PieceId, Recipe> {
         let mut exploded = HashMap::new();
 
         for transformation in recipe {
@@ -150,24 +271,23 @@ impl Resolver {
     /// # Arguments
     /// * `starting_piece` - The piece to be produced
     /// * `recipe_map` - A map of pieces to the transformations that produce them
-    ///                  The root piece should not be present in the map as it
-    ///                  is not product of any transformation.
+    ///   The root piece should not be present in the map as it
+    ///   is not product of any transformation.
     ///
     /// The transformations are ordered based on their cost.
     fn get_cheapest_path(
-        starting_piece: i64,
-        recipe_map: HashMap<i64, Recipe>,
+        starting_piece: PieceId,
+        recipe_map: HashMap<PieceId, Recipe>,
     ) -> Vec<Transformation> {
         let mut bom_entries = Vec::new();
 
         let mut current_piece = starting_piece;
-        loop {
-            let Some(available_paths) = recipe_map.get(&current_piece) else {
-                break;
-            };
-
-            if let Some(cheapest_path) =
-                available_paths.iter().min_by_key(|t| t.cost.0)
+        while let Some(available_paths) = recipe_map.get(&current_piece) {
+            // Costs are all in the currency of the books, so they
+            // always compare.
+            if let Some(cheapest_path) = available_paths
+                .iter()
+                .min_by(|a, b| a.cost.partial_cmp(&b.cost).unwrap_or(Equal))
             {
                 current_piece = cheapest_path.from_piece;
                 bom_entries.push(cheapest_path.clone());
@@ -198,35 +318,59 @@ impl Resolver {
     ) -> Result<(), anyhow::Error> {
         tracing::debug!("Starting BOM resolution for order {}", order_id);
 
-        let order = db_api::get_order(order_id, &self.pool).await?;
-        let recipe =
-            db_api::get_repice_to_root(order.piece_id, &self.pool).await?;
+        let order = match self.storage.get_order(order_id).await {
+            Ok(order) => order,
+            Err(db_api::Error::NotFound) => {
+                tracing::warn!("Order {} no longer exists", order_id);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if !order.status.can_change_quantity() {
+            tracing::info!(
+                "Order {} is {}, skipping BOM",
+                order_id,
+                order.status
+            );
+            return Ok(());
+        }
+
+        let recipe = self.storage.get_repice_to_root(order.piece_id).await?;
 
         tracing::debug!("Recipe for order with id {}: {:#?}", order_id, recipe);
 
         //NOTE: A graph may be a better representation for the recipe.
         //      Since the decision algorithm is still very simple, this
         //      approach is sufficient for now
-        let recipe_map = Resolver::map_flat_recipe(recipe);
+        let recipe_map = Self::map_flat_recipe(recipe);
 
         // Decide on the path to take. For now, just take the least cost path.
         // Later, we may want to take into account the availability of the tools
         // and maybe real time data from the MES.
         let bom_entries = Self::get_cheapest_path(order.piece_id, recipe_map);
+        let cost = db_api::path_cost(&bom_entries, order.quantity)?;
 
-        let steps_total = bom_entries.len() as i32;
-        let pieces_total = order.quantity;
+        // Steps that take several units of their input make the steps
+        // before them run more often, so each step has its own count
+        // of pieces.
+        let runs = db_api::path_runs(&bom_entries, order.quantity)?;
+        let steps = bom_entries.iter().zip(runs).rev().collect::<Vec<_>>();
+        let steps_total = steps.len() as i32;
+        let most_runs = steps.iter().map(|(_, runs)| *runs).max();
 
         let mut batch = Vec::new();
-        for piece_number in 1..=pieces_total {
-            for (step_number, transformation) in
-                bom_entries.iter().rev().enumerate()
+        for piece_number in 1..=most_runs.unwrap_or(0) {
+            for (step_number, (transformation, pieces_total)) in
+                steps.iter().enumerate()
             {
+                if piece_number > *pieces_total {
+                    continue;
+                }
                 let bom = Bom::new(
                     order_id,
                     transformation.id,
                     piece_number,
-                    pieces_total,
+                    *pieces_total,
                     (step_number + 1) as i32,
                     steps_total,
                 );
@@ -234,8 +378,20 @@ impl Resolver {
             }
         }
 
-        Bom::insert_batch(&batch, &self.pool).await?;
-        tracing::info!("BOM entries generated for order {}", order_id);
+        // Replaces any entries of an earlier try, so a job that is run
+        // again does not add them twice.
+        self.storage.resolve_order_bom(order_id, &batch).await?;
+        let piece = match self.catalog.piece_by_id(order.piece_id) {
+            Ok(piece) => piece.name.clone(),
+            Err(_) => format!("piece {}", order.piece_id.0),
+        };
+        tracing::info!(
+            "BOM entries generated for order {} of {} {}, costing {}",
+            order_id,
+            order.quantity,
+            piece,
+            cost
+        );
 
         Ok(())
     }
@@ -244,52 +400,68 @@ impl Resolver {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use db_api::{Tools, Transformation};
-    use sqlx::postgres::types::PgMoney;
+    use db_api::{
+        events::OrderStatusChanged, MemoryStorage, Money, OrderStatus, PgOrder,
+        ToolId, Transformation,
+    };
+
+    type TestResolver = Resolver<MemoryStorage>;
 
     static RECIPE: [Transformation; 4] = [
         Transformation {
             id: 1,
-            from_piece: 1,
-            to_piece: 2,
-            cost: PgMoney(1),
-            tool: Tools::T1,
+            from_piece: PieceId(1),
+            to_piece: PieceId(2),
+            cost: Money::from_cents(1),
+            tool: ToolId(1),
             quantity: 1,
+            processing_time: 5,
+            setup_time: 0,
+            line_times: Vec::new(),
         },
         Transformation {
             id: 2,
-            from_piece: 2,
-            to_piece: 5,
-            cost: PgMoney(100),
-            tool: Tools::T2,
+            from_piece: PieceId(2),
+            to_piece: PieceId(5),
+            cost: Money::from_cents(100),
+            tool: ToolId(2),
             quantity: 1,
+            processing_time: 5,
+            setup_time: 0,
+            line_times: Vec::new(),
         },
         Transformation {
             id: 3,
-            from_piece: 2,
-            to_piece: 5,
-            cost: PgMoney(50),
-            tool: Tools::T3,
+            from_piece: PieceId(2),
+            to_piece: PieceId(5),
+            cost: Money::from_cents(50),
+            tool: ToolId(3),
             quantity: 1,
+            processing_time: 5,
+            setup_time: 0,
+            line_times: Vec::new(),
         },
         Transformation {
             id: 4,
-            from_piece: 5,
-            to_piece: 9,
-            cost: PgMoney(100),
-            tool: Tools::T2,
+            from_piece: PieceId(5),
+            to_piece: PieceId(9),
+            cost: Money::from_cents(100),
+            tool: ToolId(2),
             quantity: 1,
+            processing_time: 5,
+            setup_time: 0,
+            line_times: Vec::new(),
         },
     ];
 
     #[test]
     fn test_map_flat_recipe() {
         let mut expected = HashMap::new();
-        expected.insert(2, vec![RECIPE[0].clone()]);
-        expected.insert(5, vec![RECIPE[1].clone(), RECIPE[2].clone()]);
-        expected.insert(9, vec![RECIPE[3].clone()]);
+        expected.insert(PieceId(2), vec![RECIPE[0].clone()]);
+        expected.insert(PieceId(5), vec![RECIPE[1].clone(), RECIPE[2].clone()]);
+        expected.insert(PieceId(9), vec![RECIPE[3].clone()]);
 
-        let result = Resolver::map_flat_recipe(RECIPE.to_vec());
+        let result = TestResolver::map_flat_recipe(RECIPE.to_vec());
 
         assert_eq!(expected, result);
     }
@@ -297,13 +469,303 @@ mod tests {
     #[test]
     fn test_get_cheapest_path() {
         let mut map = HashMap::new();
-        map.insert(2, vec![RECIPE[0].clone()]);
-        map.insert(5, vec![RECIPE[1].clone(), RECIPE[2].clone()]);
-        map.insert(9, vec![RECIPE[3].clone()]);
+        map.insert(PieceId(2), vec![RECIPE[0].clone()]);
+        map.insert(PieceId(5), vec![RECIPE[1].clone(), RECIPE[2].clone()]);
+        map.insert(PieceId(9), vec![RECIPE[3].clone()]);
 
-        let result = get_cheapest_path(9, map);
+        let result = TestResolver::get_cheapest_path(PieceId(9), map);
         let expected =
             vec![RECIPE[3].clone(), RECIPE[2].clone(), RECIPE[0].clone()];
         assert_eq!(expected, result);
     }
+
+    fn order(piece: &str, quantity: i32) -> PgOrder {
+        PgOrder {
+            id: 0,
+            piece_id: MemoryStorage::piece_id(piece).unwrap(),
+            client_id: 1,
+            number: 1,
+            quantity,
+            due_date: 10,
+            late_pen: Money::from_cents(0),
+            early_pen: Money::from_cents(0),
+            status: OrderStatus::Received,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_new_order_generates_bom() {
+        let resolver =
+            Resolver::new(MemoryStorage::new(), MemoryStorage::catalog());
+        let order_id = resolver.storage.insert_order(order("P9", 2));
+
+        resolver
+            .handle_new_order(NewOrder { order_id })
+            .await
+            .unwrap();
+
+        // P9 is made from P2 in two steps, P2 -> P8 -> P9.
+        let bom = resolver.storage.bom();
+        assert_eq!(bom.len(), 4);
+        assert!(bom.iter().all(|entry| entry.order_id == order_id));
+        assert!(bom.iter().all(|entry| entry.steps_total == 2));
+        assert_eq!(
+            bom.iter()
+                .map(|entry| (entry.piece_number, entry.step_number))
+                .collect::<Vec<_>>(),
+            vec![(1, 1), (1, 2), (2, 1), (2, 2)]
+        );
+
+        let order = resolver.storage.get_order(order_id).await.unwrap();
+        assert_eq!(order.status, OrderStatus::BomResolved);
+
+        assert_eq!(
+            resolver.storage.take_events(),
+            vec![
+                AnyEvent::NewBomEntry(NewBomEntry { order_id }),
+                AnyEvent::OrderStatusChanged(OrderStatusChanged {
+                    order_id,
+                    from: OrderStatus::Received,
+                    to: OrderStatus::BomResolved,
+                }),
+            ]
+        );
+        resolver
+            .handle_new_bom_entry(NewBomEntry { order_id })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cheapest_recipe_is_used() {
+        let resolver =
+            Resolver::new(MemoryStorage::new(), MemoryStorage::catalog());
+        let order_id = resolver.storage.insert_order(order("P5", 1));
+
+        resolver
+            .handle_new_order(NewOrder { order_id })
+            .await
+            .unwrap();
+
+        // P1 -> P3 -> P4 -> P5, where P3 -> P4 is cheaper with T2.
+        let transformations = resolver
+            .storage
+            .bom()
+            .iter()
+            .map(|entry| entry.transformation_id)
+            .collect::<Vec<_>>();
+        assert_eq!(transformations, vec![9, 8, 6]);
+    }
+
+    #[tokio::test]
+    async fn test_multi_unit_transformations() {
+        let resolver =
+            Resolver::new(MemoryStorage::new(), MemoryStorage::catalog());
+        // Each P9 takes 3 P8, and each P8 takes 2 P2.
+        resolver
+            .storage
+            .update_transformation(1, |t| t.quantity = 3)
+            .unwrap();
+        resolver
+            .storage
+            .update_transformation(3, |t| t.quantity = 2)
+            .unwrap();
+        let order_id = resolver.storage.insert_order(order("P9", 2));
+
+        resolver
+            .handle_new_order(NewOrder { order_id })
+            .await
+            .unwrap();
+
+        // 6 runs of P2 -> P8, using 12 P2, then 2 runs of P8 -> P9.
+        let bom = resolver.storage.bom();
+        let steps = |step_number| {
+            bom.iter()
+                .filter(|entry| entry.step_number == step_number)
+                .map(|entry| (entry.piece_number, entry.pieces_total))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(steps(1), (1..=6).map(|n| (n, 6)).collect::<Vec<_>>());
+        assert_eq!(steps(2), vec![(1, 2), (2, 2)]);
+        assert!(bom.iter().all(|entry| entry.steps_total == 2));
+        assert_eq!(bom.len(), 8);
+
+        // Only the amount of final pieces decides if the BOM is stale.
+        resolver
+            .handle_order_amended(OrderAmended { order_id })
+            .await
+            .unwrap();
+        assert_eq!(resolver.storage.bom(), bom);
+    }
+
+    #[tokio::test]
+    async fn test_recipe_cycle_fails_the_job() {
+        let resolver =
+            Resolver::new(MemoryStorage::new(), MemoryStorage::catalog());
+        // P3 is made from P5 instead of P1: P5 <- P4 <- P3 <- P5.
+        let p5 = MemoryStorage::piece_id("P5").unwrap();
+        resolver
+            .storage
+            .update_transformation(9, |t| t.from_piece = p5)
+            .unwrap();
+
+        let error = resolver.storage.get_repice_to_root(p5).await.unwrap_err();
+        let db_api::Error::RecipeCycle(cycle) = error else {
+            panic!("expected a recipe cycle, got {error}");
+        };
+        assert_eq!(cycle, vec!["P5", "P3", "P4", "P5"]);
+
+        let order_id = resolver.storage.insert_order(order("P5", 1));
+        resolver.storage.enqueue_job(&NewOrder { order_id });
+        resolver.run_jobs().await.unwrap();
+        let (_, status) = resolver.storage.jobs().remove(0);
+        assert_eq!(status, JobStatus::Failed);
+        assert!(resolver.storage.bom().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_order_is_skipped() {
+        let resolver =
+            Resolver::new(MemoryStorage::new(), MemoryStorage::catalog());
+        let order_id = resolver.storage.insert_order(PgOrder {
+            status: OrderStatus::Cancelled,
+            ..order("P9", 3)
+        });
+
+        resolver
+            .handle_new_order(NewOrder { order_id })
+            .await
+            .unwrap();
+        assert!(resolver.storage.bom().is_empty());
+
+        // Missing orders are skipped too.
+        let missing = NewOrder { order_id: 42 };
+        resolver.handle_new_order(missing).await.unwrap();
+        assert!(resolver.storage.take_events().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_and_amend() {
+        let resolver =
+            Resolver::new(MemoryStorage::new(), MemoryStorage::catalog());
+        let order_id = resolver.storage.insert_order(order("P9", 2));
+        resolver
+            .handle_new_order(NewOrder { order_id })
+            .await
+            .unwrap();
+
+        // Nothing changed, so the entries are kept.
+        let amended = OrderAmended { order_id };
+        resolver
+            .handle_order_amended(amended.clone())
+            .await
+            .unwrap();
+        assert_eq!(resolver.storage.bom()[0].id, 1);
+
+        resolver
+            .storage
+            .update_order(order_id, |order| order.quantity = 3)
+            .unwrap();
+        resolver.handle_order_amended(amended).await.unwrap();
+        let bom = resolver.storage.bom();
+        assert_eq!(bom.len(), 6);
+        assert!(bom.iter().all(|entry| entry.pieces_total == 3));
+        let order = resolver.storage.get_order(order_id).await.unwrap();
+        assert_eq!(order.status, OrderStatus::BomResolved);
+
+        let cancelled = OrderCancelled { order_id };
+        resolver.handle_order_cancelled(cancelled).await.unwrap();
+        assert!(resolver.storage.bom().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_new_order_handled_twice() {
+        let resolver =
+            Resolver::new(MemoryStorage::new(), MemoryStorage::catalog());
+        let order_id = resolver.storage.insert_order(order("P9", 2));
+
+        // A job whose lease ran out is handled again by another worker.
+        for _ in 0..2 {
+            resolver
+                .handle_new_order(NewOrder { order_id })
+                .await
+                .unwrap();
+        }
+
+        let bom = resolver.storage.bom();
+        assert_eq!(bom.len(), 4);
+        assert_eq!(bom[0].id, 5);
+        let order = resolver.storage.get_order(order_id).await.unwrap();
+        assert_eq!(order.status, OrderStatus::BomResolved);
+
+        let changes =
+            resolver.storage.take_events().into_iter().filter(|event| {
+                matches!(event, AnyEvent::OrderStatusChanged(_))
+            });
+        assert_eq!(changes.count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_jobs() {
+        use db_api::NotificationChannel as Nc;
+        let resolver =
+            Resolver::new(MemoryStorage::new(), MemoryStorage::catalog());
+        let order_id = resolver.storage.insert_order(order("P6", 3));
+        resolver.storage.enqueue_job(&NewOrder { order_id });
+
+        // The new order queues the BOM entries it generated.
+        assert_eq!(resolver.run_jobs().await.unwrap(), 2);
+        assert_eq!(resolver.run_jobs().await.unwrap(), 0);
+
+        let jobs = resolver.storage.jobs();
+        let channels = jobs.iter().map(|(job, _)| job.channel);
+        assert_eq!(
+            channels.collect::<Vec<_>>(),
+            vec![Nc::NewOrder, Nc::NewBomEntry]
+        );
+        assert!(jobs.iter().all(|(_, status)| *status == JobStatus::Done));
+        assert_eq!(resolver.storage.bom().len(), 9);
+    }
+
+    #[tokio::test]
+    async fn test_failed_jobs_are_retried() {
+        let resolver =
+            Resolver::new(MemoryStorage::new(), MemoryStorage::catalog());
+        // There is no such order to amend.
+        resolver.storage.enqueue_job(&OrderAmended { order_id: 42 });
+
+        let attempts = MemoryStorage::MAX_ATTEMPTS as usize;
+        assert_eq!(resolver.run_jobs().await.unwrap(), attempts);
+
+        let (job, status) = resolver.storage.jobs().remove(0);
+        assert_eq!(job.attempts, MemoryStorage::MAX_ATTEMPTS);
+        assert_eq!(status, JobStatus::Failed);
+    }
+
+    #[tokio::test]
+    async fn test_large_order() {
+        let resolver =
+            Resolver::new(MemoryStorage::new(), MemoryStorage::catalog());
+        let order_id = resolver.storage.insert_order(order("P5", 1000));
+        resolver.storage.enqueue_job(&NewOrder { order_id });
+
+        assert_eq!(resolver.run_jobs().await.unwrap(), 2);
+        let bom = resolver.storage.bom();
+        assert_eq!(bom.len(), 3000);
+
+        // The ids alone would not fit in a notification.
+        let ids = bom.iter().map(|entry| entry.id.to_string());
+        assert!(ids.collect::<Vec<_>>().join(",").len() > 8000);
+
+        let bom_events = resolver
+            .storage
+            .take_events()
+            .into_iter()
+            .filter(|event| matches!(event, AnyEvent::NewBomEntry(_)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            bom_events,
+            vec![AnyEvent::NewBomEntry(NewBomEntry { order_id })]
+        );
+    }
 }
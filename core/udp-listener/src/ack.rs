@@ -0,0 +1,430 @@
+use crate::auth::AuthError;
+use db_api::{Client, ClientOrder, OrderProblem};
+
+/// What a message asked to do with an order.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AckAction {
+    #[default]
+    Place,
+    Cancel,
+    Amend,
+}
+
+impl std::fmt::Display for AckAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AckAction::Place => write!(f, "place"),
+            AckAction::Cancel => write!(f, "cancel"),
+            AckAction::Amend => write!(f, "amend"),
+        }
+    }
+}
+
+/// Outcome of handling a single order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckStatus {
+    Accepted,
+    Rejected,
+    Duplicate,
+}
+
+impl std::fmt::Display for AckStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AckStatus::Accepted => write!(f, "accepted"),
+            AckStatus::Rejected => write!(f, "rejected"),
+            AckStatus::Duplicate => write!(f, "duplicate"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrderAckEntry {
+    pub client: String,
+    pub number: i32,
+    pub action: AckAction,
+    pub status: AckStatus,
+    pub reason: Option<String>,
+}
+
+impl OrderAckEntry {
+    pub fn accepted(order: &ClientOrder) -> Self {
+        Self {
+            client: order.client.name_id.clone(),
+            number: order.order.number,
+            action: AckAction::Place,
+            status: AckStatus::Accepted,
+            reason: None,
+        }
+    }
+
+    /// Builds the entry for a cancellation or amendment of an order.
+    pub fn changed(
+        action: AckAction,
+        client: &Client,
+        number: i32,
+        result: &db_api::Result<i64>,
+    ) -> Self {
+        let (status, reason) = match result {
+            Ok(_) => (AckStatus::Accepted, None),
+            Err(e) => (AckStatus::Rejected, Some(e.to_string())),
+        };
+
+        Self {
+            client: client.name_id.clone(),
+            number,
+            action,
+            status,
+            reason,
+        }
+    }
+
+    /// Builds the entry for a message whose sender could not be verified.
+    pub fn unauthenticated(
+        action: AckAction,
+        client: &Client,
+        number: i32,
+        error: &AuthError,
+    ) -> Self {
+        Self {
+            client: client.name_id.clone(),
+            number,
+            action,
+            status: AckStatus::Rejected,
+            reason: Some(error.to_string()),
+        }
+    }
+
+    /// Builds the entry for an order that failed validation,
+    /// listing every problem found in it.
+    pub fn invalid(order: &ClientOrder, problems: &[OrderProblem]) -> Self {
+        let reason = problems
+            .iter()
+            .map(|problem| problem.to_string())
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        Self {
+            client: order.client.name_id.clone(),
+            number: order.order.number,
+            action: AckAction::Place,
+            status: AckStatus::Rejected,
+            reason: Some(reason),
+        }
+    }
+
+    /// Builds the entry for an order that `place_client_order` refused.
+    /// An order number the client already used is reported as a
+    /// duplicate, every other error is a plain rejection.
+    pub fn failed(order: &ClientOrder, error: &db_api::Error) -> Self {
+        let (status, reason) = match error {
+            db_api::Error::DuplicateOrder { .. } => (
+                AckStatus::Duplicate,
+                "order number already in use".to_string(),
+            ),
+            e => (AckStatus::Rejected, e.to_string()),
+        };
+
+        Self {
+            client: order.client.name_id.clone(),
+            number: order.order.number,
+            action: AckAction::Place,
+            status,
+            reason: Some(reason),
+        }
+    }
+}
+
+/// Reply sent back to the sender of a document.
+///
+/// ```xml
+/// <OrderAck>
+///   <Order NameId="Kling Inc" Number="1" Status="accepted"/>
+///   <Order NameId="Kling Inc" Number="2" Status="duplicate" Reason="..."/>
+/// </OrderAck>
+/// ```
+///
+/// Cancellations and amendments are listed the same way, with an extra
+/// `Action="cancel"` or `Action="amend"` attribute.
+///
+/// When the document itself could not be read there are no orders to
+/// list, so the reply carries the status and reason on the root element.
+///
+/// Over UDP a reply that does not fit in a datagram is split, see
+/// [`OrderAck::to_datagrams`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct OrderAck {
+    pub entries: Vec<OrderAckEntry>,
+    pub error: Option<String>,
+}
+
+impl OrderAck {
+    pub fn rejected(reason: impl Into<String>) -> Self {
+        Self {
+            entries: Vec::new(),
+            error: Some(reason.into()),
+        }
+    }
+
+    pub fn push(&mut self, entry: OrderAckEntry) {
+        self.entries.push(entry);
+    }
+
+    pub fn to_xml(&self) -> String {
+        if let Some(error) = &self.error {
+            return format!(
+                "<OrderAck Status=\"{}\" Reason=\"{}\"/>\n",
+                AckStatus::Rejected,
+                escape(error)
+            );
+        }
+
+        let mut xml = String::from("<OrderAck>\n");
+        for entry in &self.entries {
+            xml.push_str(&entry_xml(entry));
+        }
+        xml.push_str("</OrderAck>\n");
+        xml
+    }
+
+    /// Splits the reply into documents of at most `max_size` bytes.
+    ///
+    /// A reply that fits is sent as [`OrderAck::to_xml`] returns it.
+    /// Otherwise every part is an `OrderAck` of its own, listing some of
+    /// the entries, with `Part` and `Parts` attributes on the root element
+    /// so the client knows when it has them all, e.g.
+    /// `<OrderAck Part="2" Parts="3">`. Reasons too long to fit in a part
+    /// on their own are cut short.
+    pub fn to_datagrams(&self, max_size: usize) -> Vec<String> {
+        let xml = self.to_xml();
+        if xml.len() <= max_size {
+            return vec![xml];
+        }
+
+        // Room for the largest possible root element around the entries.
+        let root =
+            format!("<OrderAck Part=\"{0}\" Parts=\"{0}\">\n", usize::MAX);
+        let room = max_size.saturating_sub(root.len() + PART_END.len());
+
+        let mut parts = vec![String::new()];
+        for entry in &self.entries {
+            let mut line = entry_xml(entry);
+            if line.len() > room {
+                line = entry_xml(&shortened(entry, line.len() - room));
+            }
+
+            let part = parts.last_mut().expect("there is always a part");
+            if !part.is_empty() && part.len() + line.len() > room {
+                parts.push(line);
+            } else {
+                part.push_str(&line);
+            }
+        }
+
+        let count = parts.len();
+        parts
+            .into_iter()
+            .enumerate()
+            .map(|(index, entries)| {
+                format!(
+                    "<OrderAck Part=\"{}\" Parts=\"{count}\">\n{entries}{PART_END}",
+                    index + 1
+                )
+            })
+            .collect()
+    }
+}
+
+const PART_END: &str = "</OrderAck>\n";
+
+fn entry_xml(entry: &OrderAckEntry) -> String {
+    let mut xml = format!(
+        "  <Order NameId=\"{}\" Number=\"{}\" Status=\"{}\"",
+        escape(&entry.client),
+        entry.number,
+        entry.status
+    );
+    if entry.action != AckAction::Place {
+        xml.push_str(&format!(" Action=\"{}\"", entry.action));
+    }
+    if let Some(reason) = &entry.reason {
+        xml.push_str(&format!(" Reason=\"{}\"", escape(reason)));
+    }
+    xml.push_str("/>\n");
+    xml
+}
+
+/// The entry with its reason cut short by at least `excess` bytes of
+/// escaped text, and an ellipsis to show it was cut.
+fn shortened(entry: &OrderAckEntry, excess: usize) -> OrderAckEntry {
+    let reason = entry.reason.as_deref().unwrap_or_default();
+    let mut kept = String::new();
+    let mut budget = escape(reason).len().saturating_sub(excess + 3);
+    for c in reason.chars() {
+        let size = escape(c.encode_utf8(&mut [0; 4])).len();
+        if size > budget {
+            break;
+        }
+        budget -= size;
+        kept.push(c);
+    }
+    kept.push('…');
+
+    OrderAckEntry {
+        reason: Some(kept),
+        ..entry.clone()
+    }
+}
+
+/// Escapes text for use inside a double quoted attribute value.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\n' => escaped.push_str("&#10;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order_ack_to_xml() {
+        let mut ack = OrderAck::default();
+        ack.push(OrderAckEntry {
+            client: "Kling & Sons".to_string(),
+            number: 1,
+            action: AckAction::Place,
+            status: AckStatus::Accepted,
+            reason: None,
+        });
+        ack.push(OrderAckEntry {
+            client: "Kling & Sons".to_string(),
+            number: 2,
+            action: AckAction::Place,
+            status: AckStatus::Duplicate,
+            reason: Some("order number already in use".to_string()),
+        });
+        ack.push(OrderAckEntry {
+            client: "Kling & Sons".to_string(),
+            number: 1,
+            action: AckAction::Cancel,
+            status: AckStatus::Accepted,
+            reason: None,
+        });
+
+        let expected = "<OrderAck>\n  \
+            <Order NameId=\"Kling &amp; Sons\" Number=\"1\" Status=\"accepted\"/>\n  \
+            <Order NameId=\"Kling &amp; Sons\" Number=\"2\" Status=\"duplicate\" \
+            Reason=\"order number already in use\"/>\n  \
+            <Order NameId=\"Kling &amp; Sons\" Number=\"1\" Status=\"accepted\" \
+            Action=\"cancel\"/>\n\
+            </OrderAck>\n";
+        assert_eq!(ack.to_xml(), expected);
+    }
+
+    #[test]
+    fn test_failed_order_status() {
+        let order = ClientOrder {
+            client: Client {
+                name_id: "Kling Inc".to_string(),
+            },
+            order: db_api::Order {
+                number: 1,
+                work_piece: "P9".to_string(),
+                quantity: 3,
+                due_date: 4,
+                late_pen: "€5,74".to_string(),
+                early_pen: "€66,32".to_string(),
+            },
+            signature: None,
+            timestamp: None,
+            nonce: None,
+        };
+
+        let duplicate = db_api::Error::DuplicateOrder {
+            client: "Kling Inc".to_string(),
+            number: 1,
+        };
+        let entry = OrderAckEntry::failed(&order, &duplicate);
+        assert_eq!(entry.status, AckStatus::Duplicate);
+
+        let unknown = db_api::Error::UnknownPiece("P9".to_string());
+        let entry = OrderAckEntry::failed(&order, &unknown);
+        assert_eq!(entry.status, AckStatus::Rejected);
+        assert_eq!(
+            entry.reason.as_deref(),
+            Some("work piece P9 does not exist")
+        );
+    }
+
+    #[test]
+    fn test_large_ack_is_split() {
+        let mut ack = OrderAck::default();
+        for number in 1..=2000 {
+            ack.push(OrderAckEntry {
+                client: "Kling Inc".to_string(),
+                number,
+                action: AckAction::Place,
+                status: AckStatus::Rejected,
+                reason: Some(
+                    "due date 1 is not after the current day 3".repeat(2),
+                ),
+            });
+        }
+        ack.push(OrderAckEntry {
+            client: "Kling Inc".to_string(),
+            number: 2001,
+            action: AckAction::Place,
+            status: AckStatus::Rejected,
+            reason: Some("é".repeat(40_000)),
+        });
+
+        let max_size = 65_507;
+        assert!(ack.to_xml().len() > max_size);
+        let parts = ack.to_datagrams(max_size);
+        assert!(parts.len() > 1);
+
+        let mut numbers = Vec::new();
+        for (index, part) in parts.iter().enumerate() {
+            assert!(part.len() <= max_size);
+            let document = roxmltree::Document::parse(part).unwrap();
+            let root = document.root_element();
+            assert_eq!(root.attribute("Part"), Some(&*(index + 1).to_string()));
+            assert_eq!(
+                root.attribute("Parts"),
+                Some(&*parts.len().to_string())
+            );
+            numbers.extend(
+                root.children()
+                    .filter(|node| node.is_element())
+                    .map(|node| node.attribute("Number").unwrap().to_string()),
+            );
+        }
+        let expected = (1..=2001).map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(numbers, expected);
+        assert!(parts.last().unwrap().contains("éé…"));
+
+        // Small replies are left as they are.
+        let ack = OrderAck::rejected("busy");
+        assert_eq!(ack.to_datagrams(max_size), vec![ack.to_xml()]);
+    }
+
+    #[test]
+    fn test_rejected_document_to_xml() {
+        let ack = OrderAck::rejected("missing field \"Order\"");
+        assert_eq!(
+            ack.to_xml(),
+            "<OrderAck Status=\"rejected\" \
+            Reason=\"missing field &quot;Order&quot;\"/>\n"
+        );
+    }
+}
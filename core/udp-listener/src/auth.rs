@@ -0,0 +1,300 @@
+use crate::format::Message;
+use db_api::{get_client_secret, record_message_nonce};
+use hmac::{Hmac, Mac};
+use ipnet::IpNet;
+use sha2::Sha256;
+use sqlx::PgPool;
+use std::{
+    env,
+    net::IpAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How far, in seconds, the timestamp of a signed message may be from
+/// the clock of the server.
+pub const MAX_CLOCK_SKEW: i64 = 300;
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// Signatures are required but the message has none.
+    MissingSignature,
+    /// The client has no shared secret to verify the signature with.
+    UnknownSecret(String),
+    /// The signature does not match the message.
+    BadSignature,
+    /// The message was signed without a timestamp and nonce.
+    MissingNonce,
+    /// The message was signed too long ago, or in the future.
+    Stale {
+        timestamp: i64,
+    },
+    /// A message with the same nonce was already received.
+    Replayed,
+    Database(db_api::Error),
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::MissingSignature => write!(f, "message is not signed"),
+            AuthError::UnknownSecret(client) => {
+                write!(f, "client {client} has no shared secret")
+            }
+            AuthError::BadSignature => write!(f, "invalid signature"),
+            AuthError::MissingNonce => {
+                write!(f, "message has no timestamp and nonce")
+            }
+            AuthError::Stale { timestamp } => write!(
+                f,
+                "message timestamp {timestamp} is more than \
+                {MAX_CLOCK_SKEW} seconds off"
+            ),
+            AuthError::Replayed => write!(f, "message was already received"),
+            AuthError::Database(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Counts the messages refused by [`Auth`].
+#[derive(Debug, Default)]
+pub struct AuthMetrics {
+    pub blocked_sources: AtomicU64,
+    pub signature_failures: AtomicU64,
+    /// Signed messages refused for being stale or already received.
+    pub replays_refused: AtomicU64,
+}
+
+/// Sender authentication for every transport.
+///
+/// Configured through the environment:
+/// * `ALLOWED_NETWORKS` - comma separated networks, e.g.
+///   `10.0.0.0/8,127.0.0.1/32`, that may send messages.
+///   Every source is allowed when it is not set.
+/// * `REQUIRE_SIGNATURES` - when `true`, every message must carry a
+///   `Signature` with the hex encoded HMAC-SHA256 of its signing string,
+///   keyed with the client's shared secret. The signing string includes
+///   a `Timestamp`, within [`MAX_CLOCK_SKEW`] of the server clock, and a
+///   `Nonce` the client does not reuse, so captured messages can not be
+///   sent again.
+#[derive(Debug, Default)]
+pub struct Auth {
+    pub require_signatures: bool,
+    /// Whether signed messages must be fresh and carry a new nonce.
+    /// Off when replaying the dead-letter store, as those messages were
+    /// already seen.
+    pub reject_replays: bool,
+    pub allowed_networks: Vec<IpNet>,
+    pub metrics: AuthMetrics,
+}
+
+impl Auth {
+    pub fn from_env() -> Result<Auth, anyhow::Error> {
+        let require_signatures = match env::var("REQUIRE_SIGNATURES") {
+            Ok(value) => value.parse()?,
+            Err(_) => false,
+        };
+
+        let allowed_networks = match env::var("ALLOWED_NETWORKS") {
+            Ok(value) => value
+                .split(',')
+                .map(|net| net.trim().parse())
+                .collect::<Result<Vec<IpNet>, _>>()?,
+            Err(_) => Vec::new(),
+        };
+
+        Ok(Auth {
+            require_signatures,
+            reject_replays: true,
+            allowed_networks,
+            metrics: AuthMetrics::default(),
+        })
+    }
+
+    /// Checks the source address against the allowed networks.
+    /// Refused sources are counted and logged.
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        if self.allowed_networks.is_empty()
+            || self.allowed_networks.iter().any(|net| net.contains(&addr))
+        {
+            return true;
+        }
+
+        let blocked =
+            self.metrics.blocked_sources.fetch_add(1, Ordering::Relaxed) + 1;
+        tracing::warn!(
+            "Blocked message from {addr} ({blocked} blocked so far)"
+        );
+        false
+    }
+
+    /// Verifies the signature of a message when signatures are required.
+    /// Failures are counted and logged.
+    pub async fn verify(
+        &self,
+        pool: &PgPool,
+        message: &Message,
+    ) -> Result<(), AuthError> {
+        if !self.require_signatures {
+            return Ok(());
+        }
+
+        let result = verify_message(pool, message, self.reject_replays).await;
+        if let Err(e) = &result {
+            let counter = match e {
+                AuthError::Stale { .. } | AuthError::Replayed => {
+                    &self.metrics.replays_refused
+                }
+                _ => &self.metrics.signature_failures,
+            };
+            let failures = counter.fetch_add(1, Ordering::Relaxed) + 1;
+            tracing::warn!(
+                "Signature check failed for {}: {e} ({failures} so far)",
+                message.client().name_id
+            );
+        }
+        result
+    }
+}
+
+async fn verify_message(
+    pool: &PgPool,
+    message: &Message,
+    reject_replays: bool,
+) -> Result<(), AuthError> {
+    let Some(signature) = message.signature() else {
+        return Err(AuthError::MissingSignature);
+    };
+
+    let client = message.client();
+    let Some(secret) = get_client_secret(client, pool)
+        .await
+        .map_err(AuthError::Database)?
+    else {
+        return Err(AuthError::UnknownSecret(client.name_id.clone()));
+    };
+
+    let signature =
+        hex::decode(signature).map_err(|_| AuthError::BadSignature)?;
+    mac(&secret, message)
+        .verify_slice(&signature)
+        .map_err(|_| AuthError::BadSignature)?;
+
+    if !reject_replays {
+        return Ok(());
+    }
+    let (Some(timestamp), Some(nonce)) = (message.timestamp(), message.nonce())
+    else {
+        return Err(AuthError::MissingNonce);
+    };
+    check_timestamp(timestamp, unix_now())?;
+
+    // Nonces are kept while their messages could still pass the
+    // timestamp check.
+    let max_age = 2 * MAX_CLOCK_SKEW;
+    match record_message_nonce(client, nonce, max_age, pool).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(AuthError::Replayed),
+        Err(e) => Err(AuthError::Database(e)),
+    }
+}
+
+fn check_timestamp(timestamp: i64, now: i64) -> Result<(), AuthError> {
+    if timestamp.abs_diff(now) > MAX_CLOCK_SKEW as u64 {
+        return Err(AuthError::Stale { timestamp });
+    }
+    Ok(())
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64)
+}
+
+fn mac(secret: &str, message: &Message) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(message.signing_string().as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db_api::{CancelOrder, Client, OrderNumber};
+
+    fn cancel() -> Message {
+        Message::CancelOrder(CancelOrder {
+            client: Client {
+                name_id: "Kling Inc".to_string(),
+            },
+            order: OrderNumber { number: 1 },
+            signature: None,
+            timestamp: None,
+            nonce: None,
+        })
+    }
+
+    /// Signs a message with a client's shared secret.
+    /// Returns the hex encoded signature.
+    fn sign(secret: &str, message: &Message) -> String {
+        hex::encode(mac(secret, message).finalize().into_bytes())
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signature = sign("secret", &cancel());
+        assert_eq!(signature.len(), 64);
+
+        let signature = hex::decode(signature).unwrap();
+        assert!(mac("secret", &cancel()).verify_slice(&signature).is_ok());
+        assert!(mac("other", &cancel()).verify_slice(&signature).is_err());
+    }
+
+    #[test]
+    fn test_signed_fields() {
+        let unsigned = cancel();
+        let Message::CancelOrder(mut cancel) = cancel() else {
+            unreachable!()
+        };
+        cancel.timestamp = Some(1_700_000_000);
+        cancel.nonce = Some("n1".to_string());
+        let signed = Message::CancelOrder(cancel);
+
+        // The timestamp and nonce can not be changed without the secret.
+        let signature = hex::decode(sign("secret", &signed)).unwrap();
+        assert!(mac("secret", &signed).verify_slice(&signature).is_ok());
+        assert!(mac("secret", &unsigned).verify_slice(&signature).is_err());
+    }
+
+    #[test]
+    fn test_check_timestamp() {
+        let now = 1_700_000_000;
+        assert!(check_timestamp(now, now).is_ok());
+        assert!(check_timestamp(now - MAX_CLOCK_SKEW, now).is_ok());
+        assert!(check_timestamp(now + MAX_CLOCK_SKEW, now).is_ok());
+        assert!(matches!(
+            check_timestamp(now - MAX_CLOCK_SKEW - 1, now),
+            Err(AuthError::Stale { .. })
+        ));
+        assert!(check_timestamp(now + MAX_CLOCK_SKEW + 1, now).is_err());
+    }
+
+    #[test]
+    fn test_allowed_networks() {
+        let auth = Auth {
+            allowed_networks: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        };
+
+        assert!(auth.allows("10.1.2.3".parse().unwrap()));
+        assert!(!auth.allows("192.168.1.1".parse().unwrap()));
+        assert_eq!(auth.metrics.blocked_sources.load(Ordering::Relaxed), 1);
+        assert!(Auth::default().allows("192.168.1.1".parse().unwrap()));
+    }
+}
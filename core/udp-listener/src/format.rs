@@ -0,0 +1,477 @@
+use crate::schema::{Schema, SchemaErrors};
+use db_api::{AmendOrder, CancelOrder, Client, ClientOrder, Order};
+use serde::{Deserialize, Serialize};
+
+/// Encodings accepted for client order documents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Xml,
+    Json,
+    Csv,
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Format::Xml => write!(f, "XML"),
+            Format::Json => write!(f, "JSON"),
+            Format::Csv => write!(f, "CSV"),
+        }
+    }
+}
+
+impl Format {
+    /// Picks the format from an explicit name or media type,
+    /// e.g. the `Content-Type` header of an HTTP request.
+    pub fn from_name(name: &str) -> Option<Format> {
+        let name = name.split(';').next().unwrap_or_default().trim();
+        match name.to_ascii_lowercase().as_str() {
+            "xml" | "application/xml" | "text/xml" => Some(Format::Xml),
+            "json" | "application/json" => Some(Format::Json),
+            "csv" | "text/csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+
+    /// Reads the optional header line that names the format of a document,
+    /// e.g. `Format: csv`, for transports that have no header of their
+    /// own. Returns the format, if there is a header, and the rest of the
+    /// document. Fails with [`DecodeError::UnknownFormat`] if the header
+    /// names a format that is not supported.
+    pub fn split_header(
+        data: &[u8],
+    ) -> Result<(Option<Format>, &[u8]), DecodeError> {
+        let Some(line) = header_line(data) else {
+            return Ok((None, data));
+        };
+        let name = String::from_utf8_lossy(&line[HEADER.len()..]);
+        let name = name.trim();
+        match Format::from_name(name) {
+            Some(format) => Ok((Some(format), &data[line.len()..])),
+            None => Err(DecodeError::UnknownFormat(name.to_string())),
+        }
+    }
+
+    /// Guesses the format from the first significant byte of a document.
+    /// XML starts with `<`, JSON with `{` or `[`, anything else is CSV.
+    pub fn sniff(data: &[u8]) -> Format {
+        let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+        match data.iter().find(|byte| !byte.is_ascii_whitespace()) {
+            Some(b'<') => Format::Xml,
+            Some(b'{' | b'[') => Format::Json,
+            _ => Format::Csv,
+        }
+    }
+}
+
+/// Start of the line that names the format of a document.
+pub const HEADER: &str = "Format:";
+
+/// The header line at the start of `data`, including its line break.
+/// `None` if there is no header, or its line is not complete yet.
+pub fn header_line(data: &[u8]) -> Option<&[u8]> {
+    let prefix = data.get(..HEADER.len())?;
+    if !prefix.eq_ignore_ascii_case(HEADER.as_bytes()) {
+        return None;
+    }
+    let end = data.iter().position(|byte| *byte == b'\n')?;
+    Some(&data[..=end])
+}
+
+/// Messages of the client protocol, named after their root element.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub enum Message {
+    ClientOrder(ClientOrder),
+    CancelOrder(CancelOrder),
+    AmendOrder(AmendOrder),
+}
+
+impl Message {
+    pub fn client(&self) -> &Client {
+        match self {
+            Message::ClientOrder(order) => &order.client,
+            Message::CancelOrder(cancel) => &cancel.client,
+            Message::AmendOrder(amend) => &amend.client,
+        }
+    }
+
+    pub fn number(&self) -> i32 {
+        match self {
+            Message::ClientOrder(order) => order.order.number,
+            Message::CancelOrder(cancel) => cancel.order.number,
+            Message::AmendOrder(amend) => amend.order.number,
+        }
+    }
+
+    pub fn signature(&self) -> Option<&str> {
+        match self {
+            Message::ClientOrder(order) => order.signature.as_deref(),
+            Message::CancelOrder(cancel) => cancel.signature.as_deref(),
+            Message::AmendOrder(amend) => amend.signature.as_deref(),
+        }
+    }
+
+    /// Unix time the message was signed at, see [`ClientOrder::timestamp`].
+    pub fn timestamp(&self) -> Option<i64> {
+        match self {
+            Message::ClientOrder(order) => order.timestamp,
+            Message::CancelOrder(cancel) => cancel.timestamp,
+            Message::AmendOrder(amend) => amend.timestamp,
+        }
+    }
+
+    pub fn nonce(&self) -> Option<&str> {
+        match self {
+            Message::ClientOrder(order) => order.nonce.as_deref(),
+            Message::CancelOrder(cancel) => cancel.nonce.as_deref(),
+            Message::AmendOrder(amend) => amend.nonce.as_deref(),
+        }
+    }
+
+    /// The text the client signs, see [`crate::auth`].
+    pub fn signing_string(&self) -> String {
+        match self {
+            Message::ClientOrder(order) => order.signing_string(),
+            Message::CancelOrder(cancel) => cancel.signing_string(),
+            Message::AmendOrder(amend) => amend.signing_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The header names a format that is not supported.
+    UnknownFormat(String),
+    /// The XML document does not follow the protocol schema.
+    Schema(SchemaErrors),
+    Xml(serde_xml_rs::Error),
+    Json(serde_json::Error),
+    Csv(csv::Error),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::UnknownFormat(name) => {
+                write!(f, "Unknown document format \"{name}\"")
+            }
+            DecodeError::Schema(e) => write!(f, "Invalid XML: {e}"),
+            DecodeError::Xml(e) => write!(f, "Error parsing XML: {e}"),
+            DecodeError::Json(e) => write!(f, "Error parsing JSON: {e}"),
+            DecodeError::Csv(e) => write!(f, "Error parsing CSV: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decodes every message in a document.
+/// XML documents are checked against the protocol schema first, so
+/// clients are told where the document is wrong.
+/// CSV documents can only hold new orders.
+pub fn decode(
+    format: Format,
+    document: &str,
+) -> Result<Vec<Message>, DecodeError> {
+    match format {
+        Format::Xml => {
+            Schema::client_order()
+                .validate(document)
+                .map_err(DecodeError::Schema)?;
+            serde_xml_rs::from_str(document).map_err(DecodeError::Xml)
+        }
+        Format::Json => decode_json(document).map_err(DecodeError::Json),
+        Format::Csv => {
+            let orders = decode_csv(document).map_err(DecodeError::Csv)?;
+            Ok(orders.into_iter().map(Message::ClientOrder).collect())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonItem {
+    Message(Message),
+    Order(ClientOrder),
+    Many(Vec<JsonItem>),
+}
+
+impl JsonItem {
+    fn flatten_into(self, messages: &mut Vec<Message>) {
+        match self {
+            JsonItem::Message(message) => messages.push(message),
+            JsonItem::Order(order) => {
+                messages.push(Message::ClientOrder(order))
+            }
+            JsonItem::Many(items) => {
+                items.into_iter().for_each(|i| i.flatten_into(messages))
+            }
+        }
+    }
+}
+
+/// Encodes messages as a JSON document that [`decode`] reads back.
+pub fn encode_json(messages: &[&Message]) -> String {
+    serde_json::to_string(messages).expect("messages are valid JSON")
+}
+
+/// Accepts messages tagged with their type, e.g. `{"CancelOrder": {...}}`,
+/// untagged `ClientOrder` objects, arrays of either, or several of any of
+/// those one after the other.
+fn decode_json(document: &str) -> Result<Vec<Message>, serde_json::Error> {
+    let mut messages = Vec::new();
+    for item in serde_json::Deserializer::from_str(document).into_iter() {
+        let item: JsonItem = item?;
+        item.flatten_into(&mut messages);
+    }
+    Ok(messages)
+}
+
+/// One CSV record, in column order: client, number, work piece,
+/// quantity, due date, late penalty, early penalty and, optionally,
+/// the signature, its timestamp and its nonce.
+#[derive(Deserialize)]
+struct CsvRecord(
+    String,
+    i32,
+    String,
+    i32,
+    i32,
+    String,
+    String,
+    #[serde(default)] Option<String>,
+    #[serde(default)] Option<i64>,
+    #[serde(default)] Option<String>,
+);
+
+impl From<CsvRecord> for ClientOrder {
+    fn from(record: CsvRecord) -> Self {
+        let CsvRecord(
+            name_id,
+            number,
+            work_piece,
+            quantity,
+            due_date,
+            late_pen,
+            early_pen,
+            signature,
+            timestamp,
+            nonce,
+        ) = record;
+
+        ClientOrder {
+            client: Client { name_id },
+            order: Order {
+                number,
+                work_piece,
+                quantity,
+                due_date,
+                late_pen,
+                early_pen,
+            },
+            signature: signature.filter(|s| !s.is_empty()),
+            timestamp,
+            nonce: nonce.filter(|n| !n.is_empty()),
+        }
+    }
+}
+
+/// The header row is optional: a first row whose order number is not
+/// a number is taken to be the header and skipped.
+fn decode_csv(document: &str) -> Result<Vec<ClientOrder>, csv::Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(document.as_bytes());
+
+    let mut orders = Vec::new();
+    for (index, record) in reader.records().enumerate() {
+        let record = record?;
+        let is_header = index == 0
+            && record.get(1).is_some_and(|n| n.parse::<i32>().is_err());
+        if is_header {
+            continue;
+        }
+
+        let record: CsvRecord = record.deserialize(None)?;
+        orders.push(record.into());
+    }
+    Ok(orders)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expected(number: i32) -> Message {
+        Message::ClientOrder(ClientOrder {
+            client: Client {
+                name_id: "Kling Inc".to_string(),
+            },
+            order: Order {
+                number,
+                work_piece: "P9".to_string(),
+                quantity: 3,
+                due_date: 4,
+                late_pen: "€5,74".to_string(),
+                early_pen: "€66,32".to_string(),
+            },
+            signature: None,
+            timestamp: None,
+            nonce: None,
+        })
+    }
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(Format::sniff(b"  <ClientOrder>"), Format::Xml);
+        assert_eq!(Format::sniff(b"\xEF\xBB\xBF<ClientOrder>"), Format::Xml);
+        assert_eq!(Format::sniff(b"\n[{}]"), Format::Json);
+        assert_eq!(Format::sniff(b"{}"), Format::Json);
+        assert_eq!(Format::sniff(b"Kling Inc,1,P9"), Format::Csv);
+        assert_eq!(
+            Format::from_name("application/json; charset=utf-8"),
+            Some(Format::Json)
+        );
+        assert_eq!(Format::from_name("text/plain"), None);
+    }
+
+    #[test]
+    fn test_split_header() {
+        let (format, rest) =
+            Format::split_header(b"format: CSV\r\nKling").unwrap();
+        assert_eq!(format, Some(Format::Csv));
+        assert_eq!(rest, b"Kling");
+
+        let (format, rest) = Format::split_header(b"<ClientOrder>").unwrap();
+        assert_eq!(format, None);
+        assert_eq!(rest, b"<ClientOrder>");
+
+        // The line must be complete to be taken as a header.
+        assert_eq!(Format::split_header(b"Format: csv").unwrap().0, None);
+
+        let error = Format::split_header(b"Format: yaml\n").unwrap_err();
+        assert_eq!(error.to_string(), "Unknown document format \"yaml\"");
+    }
+
+    #[test]
+    fn test_decode_json() {
+        let order = r#"{"Client": {"NameId": "Kling Inc"}, "Order": {
+            "Number": 1, "WorkPiece": "P9", "Quantity": 3, "DueDate": 4,
+            "LatePen": "€5,74", "EarlyPen": "€66,32"}}"#;
+        let document = format!("{order}\n[{order}, {order}]");
+
+        let orders = decode(Format::Json, &document).unwrap();
+        assert_eq!(orders, vec![expected(1), expected(1), expected(1)]);
+
+        let cancel = r#"[{"CancelOrder":
+            {"Client": {"NameId": "Kling Inc"}, "Order": {"Number": 1}}}]"#;
+        assert!(matches!(
+            decode(Format::Json, cancel).unwrap().as_slice(),
+            [Message::CancelOrder(_)]
+        ));
+    }
+
+    #[test]
+    fn test_encode_json() {
+        let cancel = Message::CancelOrder(CancelOrder {
+            client: Client {
+                name_id: "Kling Inc".to_string(),
+            },
+            order: db_api::OrderNumber { number: 2 },
+            signature: Some("abc".to_string()),
+            timestamp: Some(1_700_000_000),
+            nonce: Some("n1".to_string()),
+        });
+        let messages = [&expected(1), &cancel];
+
+        let document = encode_json(&messages);
+        assert_eq!(Format::sniff(document.as_bytes()), Format::Json);
+        assert_eq!(
+            decode(Format::Json, &document).unwrap(),
+            vec![expected(1), cancel]
+        );
+    }
+
+    #[test]
+    fn test_decode_xml_messages() {
+        let document = r#"<ClientOrder>
+  <Client NameId="Kling Inc"/>
+  <Order Number="1" WorkPiece="P9" Quantity="3" DueDate="4" LatePen="€5,74" EarlyPen="€66,32"/>
+</ClientOrder>
+<CancelOrder>
+  <Client NameId="Kling Inc"/>
+  <Order Number="2"/>
+</CancelOrder>
+<AmendOrder>
+  <Client NameId="Kling Inc"/>
+  <Order Number="3" Quantity="5" LatePen="€1,00"/>
+</AmendOrder>"#;
+
+        let messages = decode(Format::Xml, document).unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0], expected(1));
+        assert!(matches!(
+            &messages[1],
+            Message::CancelOrder(CancelOrder { order, .. }) if order.number == 2
+        ));
+        let Message::AmendOrder(AmendOrder { order, .. }) = &messages[2] else {
+            panic!("expected an amendment, got {:?}", messages[2]);
+        };
+        assert_eq!(order.quantity, Some(5));
+        assert_eq!(order.due_date, None);
+        assert_eq!(order.late_pen.as_deref(), Some("€1,00"));
+    }
+
+    #[test]
+    fn test_decode_xml_schema_error() {
+        let document = r#"<ClientOrder Signature="ab12" Timestamp="17" Nonce="n1">
+  <Client NameId="Kling Inc"/>
+  <Order Number="1" WorkPiece="P9" Quantity="3" DueDate="4" LatePen="€5,74" EarlyPen="€66,32"/>
+</ClientOrder>"#;
+        let Message::ClientOrder(order) =
+            &decode(Format::Xml, document).unwrap()[0]
+        else {
+            panic!("expected an order");
+        };
+        assert_eq!(order.signature.as_deref(), Some("ab12"));
+        assert_eq!(order.timestamp, Some(17));
+        assert_eq!(order.nonce.as_deref(), Some("n1"));
+
+        let invalid = document.replace("Quantity=\"3\"", "Quantity=\"x\"");
+        let error = decode(Format::Xml, &invalid).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid XML: line 3, column 36: \
+            /ClientOrder[1]/Order[1]/@Quantity: expected xs:int, found \"x\""
+        );
+    }
+
+    #[test]
+    fn test_decode_csv() {
+        let document = "\
+            client,number,work piece,quantity,due date,late pen,early pen\n\
+            Kling Inc,1,P9,3,4,\"€5,74\",\"€66,32\"\n\
+            Kling Inc, 2, P9, 3, 4,\"€5,74\",\"€66,32\"\n";
+        let orders = decode(Format::Csv, document).unwrap();
+        assert_eq!(orders, vec![expected(1), expected(2)]);
+
+        let headless = "Kling Inc,1,P9,3,4,\"€5,74\",\"€66,32\"";
+        assert_eq!(decode(Format::Csv, headless).unwrap(), vec![expected(1)]);
+
+        let signed = "Kling Inc,1,P9,3,4,\"€5,74\",\"€66,32\",abc123,17,n1";
+        let Message::ClientOrder(order) =
+            &decode(Format::Csv, signed).unwrap()[0]
+        else {
+            panic!("expected an order");
+        };
+        assert_eq!(order.signature.as_deref(), Some("abc123"));
+        assert_eq!(order.timestamp, Some(17));
+        assert_eq!(order.nonce.as_deref(), Some("n1"));
+
+        let invalid = "Kling Inc,1,P9,three,4,\"€5,74\",\"€66,32\"";
+        assert!(decode(Format::Csv, invalid).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+use crate::{
+    ack::OrderAck,
+    auth::{Auth, AuthMetrics},
+    format::Format,
+    ingest::process_document,
+    udp::QueueMetrics,
+};
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Router,
+};
+use db_api::Catalog;
+use sqlx::PgPool;
+use std::{
+    fmt::Write,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::net::TcpListener;
+
+/// Serves `POST /orders`, which takes a client order document as the
+/// request body and answers with the `OrderAck` for it.
+///
+/// The format is taken from the `Content-Type` header when it names
+/// XML, JSON or CSV, and sniffed from the body otherwise.
+/// Requests from blocked sources are answered with `403 Forbidden`.
+///
+/// Also serves `GET /metrics`, the listener metrics in the Prometheus
+/// text format.
+pub async fn serve(
+    pool: PgPool,
+    auth: Arc<Auth>,
+    catalog: Arc<Catalog>,
+    queue: Arc<QueueMetrics>,
+    listener: TcpListener,
+) -> io::Result<()> {
+    let app = Router::new()
+        .route("/orders", post(post_orders))
+        .route("/metrics", get(get_metrics))
+        .with_state(AppState {
+            pool,
+            auth,
+            catalog,
+            queue,
+        });
+
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+}
+
+#[derive(Clone)]
+struct AppState {
+    pool: PgPool,
+    auth: Arc<Auth>,
+    catalog: Arc<Catalog>,
+    queue: Arc<QueueMetrics>,
+}
+
+async fn post_orders(
+    State(AppState {
+        pool,
+        auth,
+        catalog,
+        ..
+    }): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    if !auth.allows(addr.ip()) {
+        let ack = OrderAck::rejected("source address not allowed");
+        return (
+            StatusCode::FORBIDDEN,
+            [(header::CONTENT_TYPE, "application/xml")],
+            ack.to_xml(),
+        );
+    }
+
+    tracing::info!("Received {} bytes over HTTP from {addr}", body.len());
+
+    let format = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(Format::from_name);
+
+    let sender = format!("http://{addr}");
+    let ack =
+        process_document(&pool, &auth, &catalog, &body, format, &sender).await;
+    let status = match ack.error {
+        Some(_) => StatusCode::BAD_REQUEST,
+        None => StatusCode::OK,
+    };
+
+    (
+        status,
+        [(header::CONTENT_TYPE, "application/xml")],
+        ack.to_xml(),
+    )
+}
+
+async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render_metrics(&state.queue, &state.auth.metrics),
+    )
+}
+
+fn render_metrics(queue: &QueueMetrics, auth: &AuthMetrics) -> String {
+    let mut out = String::new();
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let metrics = [
+        (
+            "udp_queue_depth",
+            "gauge",
+            "UDP jobs queued and not processed yet.",
+            queue.depth(),
+        ),
+        (
+            "udp_jobs_queued_total",
+            "counter",
+            "UDP jobs queued for a worker.",
+            load(&queue.queued),
+        ),
+        (
+            "udp_jobs_processed_total",
+            "counter",
+            "UDP jobs processed by a worker.",
+            load(&queue.processed),
+        ),
+        (
+            "udp_jobs_dropped_total",
+            "counter",
+            "UDP jobs dropped because their queue was full.",
+            load(&queue.dropped),
+        ),
+        (
+            "auth_blocked_sources_total",
+            "counter",
+            "Messages refused for coming from a source that is not allowed.",
+            load(&auth.blocked_sources),
+        ),
+        (
+            "auth_signature_failures_total",
+            "counter",
+            "Messages refused for a missing or invalid signature.",
+            load(&auth.signature_failures),
+        ),
+        (
+            "auth_replays_refused_total",
+            "counter",
+            "Signed messages refused for being stale or already received.",
+            load(&auth.replays_refused),
+        ),
+    ];
+    for (name, kind, help, value) in metrics {
+        // Writing to a string can not fail.
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        let _ = writeln!(out, "{name} {value}");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_metrics() {
+        let queue = QueueMetrics::default();
+        queue.queued.store(5, Ordering::Relaxed);
+        queue.processed.store(3, Ordering::Relaxed);
+        queue.dropped.store(1, Ordering::Relaxed);
+        let auth = AuthMetrics::default();
+        auth.signature_failures.store(4, Ordering::Relaxed);
+
+        let text = render_metrics(&queue, &auth);
+        let lines = text.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&"# TYPE udp_queue_depth gauge"));
+        assert!(lines.contains(&"udp_queue_depth 2"));
+        assert!(lines.contains(&"udp_jobs_queued_total 5"));
+        assert!(lines.contains(&"udp_jobs_processed_total 3"));
+        assert!(lines.contains(&"udp_jobs_dropped_total 1"));
+        assert!(lines.contains(&"auth_signature_failures_total 4"));
+        assert!(lines.contains(&"auth_replays_refused_total 0"));
+    }
+}
@@ -0,0 +1,224 @@
+use crate::{
+    ack::{AckAction, OrderAck, OrderAckEntry},
+    auth::{Auth, AuthError},
+    format::{self, DecodeError, Format, Message},
+};
+use db_api::{
+    amend_client_order, cancel_client_order, place_client_order,
+    record_rejected_order, store_rejected_message, Catalog, ClientOrder, Error,
+};
+use sqlx::PgPool;
+
+/// Result of ingesting a document.
+pub struct Outcome {
+    pub ack: OrderAck,
+    /// Errors that kept the document, or some of its orders, from being
+    /// ingested and that may go away if the document is sent again, or
+    /// replayed once the problem is fixed. Orders refused for breaking
+    /// a business rule, see [`Error::is_refusal`], and messages that fail
+    /// authentication are not included.
+    pub failures: Vec<String>,
+}
+
+/// Decodes a client order document and places every order in it.
+/// Shared by all transports, which only differ in how the document
+/// arrives and how the returned acknowledgement is sent back.
+///
+/// Work pieces are looked up in `catalog`, loaded when the listener
+/// starts.
+///
+/// The format is taken from the transport when it provides one, then
+/// from a `Format:` header line at the start of the document, see
+/// [`Format::split_header`], and is sniffed from the document otherwise.
+/// Documents with failures are kept in the dead-letter store so they
+/// can be replayed later.
+pub async fn process_document(
+    pool: &PgPool,
+    auth: &Auth,
+    catalog: &Catalog,
+    data: &[u8],
+    format: Option<Format>,
+    sender: &str,
+) -> OrderAck {
+    let messages = decode_document(data, format);
+    process_messages(pool, auth, catalog, messages, data, sender).await
+}
+
+/// Same as [`process_document`], for a document that was already
+/// decoded. `data` is kept in the dead-letter store if there are
+/// failures, so it must hold the same messages.
+pub async fn process_messages(
+    pool: &PgPool,
+    auth: &Auth,
+    catalog: &Catalog,
+    messages: Result<Vec<Message>, DecodeError>,
+    data: &[u8],
+    sender: &str,
+) -> OrderAck {
+    let Outcome { ack, failures } =
+        ingest_messages(pool, auth, catalog, messages).await;
+
+    if !failures.is_empty() {
+        let error = failures.join("; ");
+        match store_rejected_message(data, sender, &error, pool).await {
+            Ok(id) => tracing::warn!("Stored rejected message {id}"),
+            Err(e) => tracing::error!("Error storing rejected message: {e}"),
+        }
+    }
+
+    ack
+}
+
+/// Decodes the messages of a document, see [`process_document`] for how
+/// its format is found.
+pub fn decode_document(
+    data: &[u8],
+    format: Option<Format>,
+) -> Result<Vec<Message>, DecodeError> {
+    Format::split_header(data).and_then(|(declared, data)| {
+        let format = format.or(declared).unwrap_or_else(|| Format::sniff(data));
+        format::decode(format, &String::from_utf8_lossy(data))
+    })
+}
+
+/// Decodes and places the orders in a document without
+/// touching the dead-letter store.
+/// Messages whose signature does not check out are rejected.
+pub async fn ingest(
+    pool: &PgPool,
+    auth: &Auth,
+    catalog: &Catalog,
+    data: &[u8],
+    format: Option<Format>,
+) -> Outcome {
+    let messages = decode_document(data, format);
+    ingest_messages(pool, auth, catalog, messages).await
+}
+
+async fn ingest_messages(
+    pool: &PgPool,
+    auth: &Auth,
+    catalog: &Catalog,
+    messages: Result<Vec<Message>, DecodeError>,
+) -> Outcome {
+    let messages = match messages {
+        Ok(vec) => vec,
+        Err(e) => {
+            tracing::error!("{e}");
+            return Outcome {
+                ack: OrderAck::rejected(e.to_string()),
+                failures: vec![e.to_string()],
+            };
+        }
+    };
+
+    tracing::info!("Parsed {:#?} messages", messages.len());
+
+    let mut outcome = Outcome {
+        ack: OrderAck::default(),
+        failures: Vec::new(),
+    };
+    for message in messages.iter() {
+        let failures = &mut outcome.failures;
+        if let Err(e) = auth.verify(pool, message).await {
+            if let AuthError::Database(e) = &e {
+                failures.push(e.to_string());
+            }
+            let action = match message {
+                Message::ClientOrder(_) => AckAction::Place,
+                Message::CancelOrder(_) => AckAction::Cancel,
+                Message::AmendOrder(_) => AckAction::Amend,
+            };
+            outcome.ack.push(OrderAckEntry::unauthenticated(
+                action,
+                message.client(),
+                message.number(),
+                &e,
+            ));
+            continue;
+        }
+
+        let entry = match message {
+            Message::ClientOrder(order) => {
+                process_order(pool, catalog, order, failures).await
+            }
+            Message::CancelOrder(cancel) => {
+                let result = cancel_client_order(pool, cancel).await;
+                let number = cancel.order.number;
+                log_change(AckAction::Cancel, &result, failures);
+                OrderAckEntry::changed(
+                    AckAction::Cancel,
+                    &cancel.client,
+                    number,
+                    &result,
+                )
+            }
+            Message::AmendOrder(amend) => {
+                let result = amend_client_order(pool, amend).await;
+                let number = amend.order.number;
+                log_change(AckAction::Amend, &result, failures);
+                OrderAckEntry::changed(
+                    AckAction::Amend,
+                    &amend.client,
+                    number,
+                    &result,
+                )
+            }
+        };
+        outcome.ack.push(entry);
+    }
+    outcome
+}
+
+/// Logs the result of a cancellation or amendment. Refusals are not
+/// worth replaying, broken rules will not change on resend.
+fn log_change(
+    action: AckAction,
+    result: &db_api::Result<i64>,
+    failures: &mut Vec<String>,
+) {
+    match result {
+        Ok(id) => tracing::info!("Order {id}: {action} successful"),
+        Err(e) if e.is_refusal() => {
+            tracing::warn!("Refused to {action} order: {e}")
+        }
+        Err(e) => {
+            tracing::error!("Error trying to {action} order: {e}");
+            failures.push(e.to_string());
+        }
+    }
+}
+
+/// Places a single order, which validates it first.
+/// Orders that fail validation are recorded along with the reasons.
+/// Failures other than refusals are added to `failures`.
+async fn process_order(
+    pool: &PgPool,
+    catalog: &Catalog,
+    order: &ClientOrder,
+    failures: &mut Vec<String>,
+) -> OrderAckEntry {
+    match place_client_order(pool, catalog, order).await {
+        Ok(_) => {
+            tracing::info!("Order successfully placed");
+            OrderAckEntry::accepted(order)
+        }
+        Err(Error::InvalidOrder(problems)) => {
+            tracing::warn!("Rejected invalid order: {:#?}", problems);
+            if let Err(e) = record_rejected_order(pool, order, &problems).await
+            {
+                tracing::error!("Error recording rejected order: {e}");
+            }
+            OrderAckEntry::invalid(order, &problems)
+        }
+        Err(e) if e.is_refusal() => {
+            tracing::warn!("Refused order: {e}");
+            OrderAckEntry::failed(order, &e)
+        }
+        Err(e) => {
+            tracing::error!("Error placing order: {e}");
+            failures.push(e.to_string());
+            OrderAckEntry::failed(order, &e)
+        }
+    }
+}
@@ -1,52 +1,18 @@
-use anyhow::anyhow;
-use db_api::{place_client_order, run_migrations, ClientOrder};
-use std::{env, io};
-use tokio::net::UdpSocket;
-
-struct Server {
-    pool: sqlx::PgPool,
-    socket: UdpSocket,
-    buf: Vec<u8>,
-}
+mod ack;
+mod auth;
+mod format;
+mod http;
+mod ingest;
+mod reassembly;
+mod replay;
+mod schema;
+mod tcp;
+mod udp;
 
-impl Server {
-    async fn run(self) -> Result<(), io::Error> {
-        let Server {
-            pool,
-            socket,
-            mut buf,
-        } = self;
-
-        loop {
-            let metadata = Some(socket.recv_from(&mut buf).await?);
-            let Some((length, _)) = metadata else {
-                continue;
-            };
-            let data = &buf[..length];
-            let raw_xml = String::from_utf8_lossy(data);
-
-            tracing::info!("Received {length} bytes");
-
-            let orders =
-                match serde_xml_rs::from_str::<Vec<ClientOrder>>(&raw_xml) {
-                    Ok(vec) => vec,
-                    Err(e) => {
-                        tracing::error!("Error parsing XML: {e}");
-                        continue;
-                    }
-                };
-
-            tracing::info!("Parsed {:#?} orders", orders.len());
-
-            for order in orders.iter() {
-                match place_client_order(&pool, order).await {
-                    Ok(_) => tracing::info!("Order successfully placed"),
-                    Err(e) => tracing::error!("Error placing order: {e}"),
-                }
-            }
-        }
-    }
-}
+use anyhow::anyhow;
+use db_api::{run_migrations, Catalog};
+use std::{env, sync::Arc};
+use tokio::net::{TcpListener, UdpSocket};
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
@@ -67,22 +33,76 @@ async fn main() -> Result<(), anyhow::Error> {
 
     tracing::info!("DB connection and initializtion successfull.");
 
-    let addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "127.0.0.1:8080".to_string());
+    // Pieces and tools added later are only known after a restart.
+    let catalog = Arc::new(Catalog::load(&pool).await?);
+
+    let mut auth = auth::Auth::from_env()?;
+    if auth.require_signatures {
+        tracing::info!("Requiring signed messages");
+    }
+
+    let mut args = env::args().skip(1).peekable();
+
+    // `replay [ID...]` runs stored rejected messages through the pipeline
+    // again instead of starting the servers.
+    if args.peek().is_some_and(|arg| arg == "replay") {
+        let ids = args
+            .skip(1)
+            .map(|id| id.parse::<i64>())
+            .collect::<Result<Vec<_>, _>>()?;
+        // Stored messages were already seen, so their nonces are too.
+        auth.reject_replays = false;
+        return replay::replay(&pool, &auth, &catalog, &ids).await;
+    }
+    let auth = Arc::new(auth);
 
-    let socket = UdpSocket::bind(&addr).await?;
-    tracing::info!("Listening on: {}", socket.local_addr()?);
+    let udp_addr = args.next().unwrap_or_else(|| "127.0.0.1:8080".to_string());
+    let tcp_addr = args.next().unwrap_or_else(|| "127.0.0.1:8081".to_string());
+    let http_addr = args.next().unwrap_or_else(|| "127.0.0.1:8082".to_string());
 
-    //TODO: Ask what are the expected file sizes
-    let server = Server {
-        pool,
+    let socket = UdpSocket::bind(&udp_addr).await?;
+    tracing::info!("Listening for UDP on: {}", socket.local_addr()?);
+
+    let tcp_listener = TcpListener::bind(&tcp_addr).await?;
+    tracing::info!("Listening for TCP on: {}", tcp_listener.local_addr()?);
+
+    let http_listener = TcpListener::bind(&http_addr).await?;
+    tracing::info!("Listening for HTTP on: {}", http_listener.local_addr()?);
+
+    let workers = env_or("UDP_WORKERS", udp::DEFAULT_WORKERS)?;
+    let queue_capacity =
+        env_or("UDP_QUEUE_CAPACITY", udp::DEFAULT_QUEUE_CAPACITY)?;
+    tracing::info!("Processing UDP documents with {workers} workers");
+
+    let queue_metrics = Arc::new(udp::QueueMetrics::default());
+
+    // Documents larger than a datagram are split by the client and
+    // joined back together by the server before parsing.
+    let udp_server = udp::Server {
+        pool: pool.clone(),
+        auth: auth.clone(),
+        catalog: catalog.clone(),
         socket,
-        buf: vec![0; 10024],
+        buf: vec![0; udp::MAX_DATAGRAM_SIZE],
+        workers,
+        queue_capacity,
+        metrics: queue_metrics.clone(),
     };
 
-    // This starts the server task.
-    server.run().await?;
+    // This starts the server tasks.
+    tokio::try_join!(
+        udp_server.run(),
+        tcp::serve(pool.clone(), auth.clone(), catalog.clone(), tcp_listener),
+        http::serve(pool, auth, catalog, queue_metrics, http_listener),
+    )?;
 
     Ok(())
 }
+
+/// Reads a number from the environment, or the default if it is not set.
+fn env_or(name: &str, default: usize) -> Result<usize, anyhow::Error> {
+    match env::var(name) {
+        Ok(value) => Ok(value.parse()?),
+        Err(_) => Ok(default),
+    }
+}
@@ -0,0 +1,422 @@
+use crate::format::{header_line, Format, HEADER};
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+use tokio::time::Instant;
+
+/// Time a sender has to deliver the rest of a document before the
+/// partially received data is discarded.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper bound for the data buffered for a single sender.
+pub const DEFAULT_MAX_PENDING: usize = 1024 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReassemblyError {
+    /// The sender exceeded the buffer limit without completing a document.
+    /// Everything buffered for it so far was discarded.
+    Overflow { buffered: usize },
+}
+
+impl std::fmt::Display for ReassemblyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Overflow { buffered } => write!(
+                f,
+                "incomplete document exceeded the buffer limit ({buffered} bytes)"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReassemblyError {}
+
+struct Pending {
+    data: Vec<u8>,
+    /// Format named by the header line of the document, which is taken
+    /// off `data` and put back in front of every part handed out.
+    format: Option<Format>,
+    last_seen: Instant,
+}
+
+/// Joins documents that were split over several datagrams.
+///
+/// Data is buffered per sender address. Every time a datagram arrives,
+/// the buffer is scanned for complete top-level XML elements, JSON values
+/// or CSV lines, depending on the format of the buffered data; the longest
+/// prefix made only of complete items is handed back to the caller and
+/// whatever is left waits for the next datagram from the same sender.
+///
+/// The format is the one named by a `Format:` header line, see
+/// [`Format::split_header`], or is sniffed from the data otherwise.
+/// The end of a datagram also ends the CSV record it is in, unless it is
+/// inside a quoted field, so a single line without a line break is a
+/// complete document. CSV documents split over several datagrams must
+/// be split at line breaks.
+pub struct Reassembler {
+    pending: HashMap<SocketAddr, Pending>,
+    timeout: Duration,
+    max_pending: usize,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration, max_pending: usize) -> Self {
+        Self {
+            pending: HashMap::new(),
+            timeout,
+            max_pending,
+        }
+    }
+
+    /// Feeds a datagram received from `addr`.
+    /// Returns the complete elements received so far, if any.
+    pub fn push(
+        &mut self,
+        addr: SocketAddr,
+        datagram: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, ReassemblyError> {
+        let pending = self.pending.entry(addr).or_insert_with(|| Pending {
+            data: Vec::new(),
+            format: None,
+            last_seen: now,
+        });
+        let buffered = pending.data.len();
+        pending.data.extend_from_slice(datagram);
+        pending.last_seen = now;
+
+        if buffered == 0 && pending.format.is_none() {
+            let header = header_line(&pending.data).map(<[u8]>::len);
+            if let Some(length) = header {
+                match Format::split_header(&pending.data) {
+                    Ok((format, _)) => {
+                        pending.format = format;
+                        pending.data.drain(..length);
+                    }
+                    // Handed out as it is, so the sender is told the
+                    // format is not supported.
+                    Err(_) => {
+                        let pending = self.pending.remove(&addr);
+                        return Ok(pending.map(|pending| pending.data));
+                    }
+                }
+            }
+        }
+
+        let format = pending
+            .format
+            .unwrap_or_else(|| Format::sniff(&pending.data));
+        let complete = complete_len(&pending.data, format);
+        let mut document: Vec<u8> = pending.data.drain(..complete).collect();
+        if let (Some(format), false) = (pending.format, document.is_empty()) {
+            let header = format!("{HEADER} {format}\n");
+            document.splice(..0, header.into_bytes());
+        }
+
+        // A header alone waits for the document it names.
+        let done = pending.format.is_none() || !document.is_empty();
+        if pending.data.iter().all(u8::is_ascii_whitespace) && done {
+            self.pending.remove(&addr);
+        } else if pending.data.len() > self.max_pending {
+            let buffered = pending.data.len();
+            self.pending.remove(&addr);
+            return Err(ReassemblyError::Overflow { buffered });
+        }
+
+        if document.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(document))
+    }
+
+    /// Discards the buffers of senders that have been silent for longer
+    /// than the timeout. Returns the senders and the amount of bytes dropped.
+    pub fn expire(&mut self, now: Instant) -> Vec<(SocketAddr, usize)> {
+        let timeout = self.timeout;
+        let mut expired = Vec::new();
+        self.pending.retain(|addr, pending| {
+            let alive = now.duration_since(pending.last_seen) < timeout;
+            if !alive {
+                expired.push((*addr, pending.data.len()));
+            }
+            alive
+        });
+        expired
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new(DEFAULT_TIMEOUT, DEFAULT_MAX_PENDING)
+    }
+}
+
+/// Length of the longest prefix of `buf` that only contains complete
+/// items of `format`, plus the whitespace that follows them.
+fn complete_len(buf: &[u8], format: Format) -> usize {
+    let end = match format {
+        Format::Xml => elements_len(buf),
+        Format::Json => values_len(buf),
+        Format::Csv => lines_len(buf),
+    };
+    end + buf[end..]
+        .iter()
+        .take_while(|byte| byte.is_ascii_whitespace())
+        .count()
+}
+
+/// Complete top-level XML elements, including any comments and
+/// declarations between them.
+fn elements_len(buf: &[u8]) -> usize {
+    let mut depth = 0usize;
+    let mut end = 0;
+    let mut cursor = 0;
+
+    while let Some(offset) = find(&buf[cursor..], b"<") {
+        let start = cursor + offset;
+        let rest = &buf[start..];
+
+        let special = [
+            (&b"<!--"[..], &b"-->"[..]),
+            (b"<![CDATA[", b"]]>"),
+            (b"<?", b"?>"),
+        ];
+        if let Some((open, close)) =
+            special.iter().find(|(open, _)| rest.starts_with(open))
+        {
+            let Some(stop) = find(&rest[open.len()..], close) else {
+                return end;
+            };
+            cursor = start + open.len() + stop + close.len();
+            if depth == 0 {
+                end = cursor;
+            }
+            continue;
+        }
+
+        let Some(stop) = tag_end(rest) else {
+            return end;
+        };
+        let tag = &rest[..=stop];
+        cursor = start + stop + 1;
+
+        if tag.starts_with(b"</") {
+            depth = depth.saturating_sub(1);
+        } else if !tag.ends_with(b"/>") && !tag.starts_with(b"<!") {
+            depth += 1;
+        }
+
+        if depth == 0 {
+            end = cursor;
+        }
+    }
+
+    end
+}
+
+/// Complete top-level JSON objects and arrays.
+fn values_len(buf: &[u8]) -> usize {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut end = 0;
+
+    for (i, byte) in buf.iter().enumerate() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' => {
+                depth = depth.saturating_sub(1);
+                if depth == 0 {
+                    end = i + 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    end
+}
+
+/// Complete CSV lines. Line breaks inside quoted fields do not count,
+/// and the end of `buf` ends the last line unless it is inside one.
+fn lines_len(buf: &[u8]) -> usize {
+    let mut quoted = false;
+    let mut end = 0;
+
+    for (i, byte) in buf.iter().enumerate() {
+        match byte {
+            b'"' => quoted = !quoted,
+            b'\n' if !quoted => end = i + 1,
+            _ => {}
+        }
+    }
+
+    if quoted {
+        return end;
+    }
+    buf.len()
+}
+
+/// Index of the `>` that closes the tag starting at `tag[0]`,
+/// ignoring any `>` inside quoted attribute values.
+fn tag_end(tag: &[u8]) -> Option<usize> {
+    let mut quote = None;
+    for (i, byte) in tag.iter().enumerate() {
+        match (quote, byte) {
+            (None, b'"' | b'\'') => quote = Some(*byte),
+            (Some(q), _) if q == *byte => quote = None,
+            (None, b'>') => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCUMENT: &str = r#"<ClientOrder>
+  <Client NameId="Kling Inc"/>
+  <Order Number="1" WorkPiece="P9" Quantity="3" DueDate="4" LatePen="€5,74" EarlyPen="€66,32"/>
+</ClientOrder>
+<ClientOrder>
+  <Client NameId="Kling > Inc"/>
+  <Order Number="2" WorkPiece="P9" Quantity="3" DueDate="4" LatePen="€5,74" EarlyPen="€66,32"/>
+</ClientOrder>
+"#;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_complete_len() {
+        let doc = DOCUMENT.as_bytes();
+        assert_eq!(complete_len(doc, Format::Xml), DOCUMENT.len());
+
+        let first = DOCUMENT.find("</ClientOrder>").unwrap() + 14;
+        assert_eq!(complete_len(&doc[..first + 20], Format::Xml), first + 1);
+        assert_eq!(complete_len(&doc[..first - 1], Format::Xml), 0);
+
+        let comment = b"<!-- <ClientOrder> -->";
+        assert_eq!(complete_len(comment, Format::Xml), comment.len());
+
+        let json = br#"[{"NameId": "Kling ]}"}] {"Number": "#;
+        assert_eq!(complete_len(json, Format::Json), 25);
+
+        let csv = b"Kling Inc,1,P9,3,4,\"\n\",\"\"\nKling Inc,2";
+        assert_eq!(complete_len(csv, Format::Csv), csv.len());
+        let quoted = b"Kling Inc,1,P9,3,4,\"\n\",\"\"\nKling Inc,\"2";
+        assert_eq!(complete_len(quoted, Format::Csv), 26);
+    }
+
+    #[test]
+    fn test_csv_line_without_line_break() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        let line =
+            b"Kling Inc,1,P9,3,4,\"\xe2\x82\xac5,74\",\"\xe2\x82\xac66,32\"";
+
+        assert_eq!(
+            reassembler.push(addr(1), line, now),
+            Ok(Some(line.to_vec()))
+        );
+        assert!(reassembler.expire(now + DEFAULT_TIMEOUT).is_empty());
+    }
+
+    #[test]
+    fn test_format_header() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        let (head, tail) = DOCUMENT.as_bytes().split_at(40);
+
+        // The header is put back in front of the document once complete.
+        let first = [b"format: xml\n", head].concat();
+        assert_eq!(reassembler.push(addr(1), &first, now), Ok(None));
+        let document = reassembler.push(addr(1), tail, now).unwrap().unwrap();
+        assert_eq!(document, [b"Format: XML\n", DOCUMENT.as_bytes()].concat());
+
+        // A header on its own waits for the document.
+        let header = b"Format: csv\n";
+        assert_eq!(reassembler.push(addr(2), header, now), Ok(None));
+        let document = reassembler.push(addr(2), b"<x>,1", now).unwrap();
+        assert_eq!(document.unwrap(), b"Format: CSV\n<x>,1");
+
+        // Unknown formats are handed out so they can be rejected.
+        let unknown = b"Format: yaml\n- order";
+        let document = reassembler.push(addr(3), unknown, now).unwrap();
+        assert_eq!(document.unwrap(), unknown);
+        assert!(reassembler.expire(now + DEFAULT_TIMEOUT).is_empty());
+    }
+
+    #[test]
+    fn test_reassemble_split_document() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        let doc = DOCUMENT.as_bytes();
+
+        let mut received = Vec::new();
+        for chunk in doc.chunks(17) {
+            if let Some(part) = reassembler.push(addr(1), chunk, now).unwrap() {
+                received.extend(part);
+            }
+        }
+
+        assert_eq!(String::from_utf8(received).unwrap(), DOCUMENT);
+        assert!(reassembler.expire(now + DEFAULT_TIMEOUT).is_empty());
+    }
+
+    #[test]
+    fn test_senders_are_independent() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        let (head, tail) = DOCUMENT.as_bytes().split_at(40);
+
+        assert_eq!(reassembler.push(addr(1), head, now), Ok(None));
+        assert_eq!(reassembler.push(addr(2), head, now), Ok(None));
+        assert_eq!(
+            reassembler.push(addr(1), tail, now),
+            Ok(Some(DOCUMENT.as_bytes().to_vec()))
+        );
+        assert_eq!(
+            reassembler.expire(now + DEFAULT_TIMEOUT),
+            vec![(addr(2), head.len())]
+        );
+    }
+
+    #[test]
+    fn test_expire_and_overflow() {
+        let mut reassembler = Reassembler::new(DEFAULT_TIMEOUT, 64);
+        let now = Instant::now();
+
+        assert_eq!(reassembler.push(addr(1), b"<ClientOrder>", now), Ok(None));
+        assert!(reassembler.expire(now + Duration::from_secs(1)).is_empty());
+        assert_eq!(
+            reassembler.expire(now + DEFAULT_TIMEOUT),
+            vec![(addr(1), 13)]
+        );
+
+        let big = [b' '; 64];
+        reassembler.push(addr(1), b"<ClientOrder>", now).unwrap();
+        assert_eq!(
+            reassembler.push(addr(1), &big, now),
+            Err(ReassemblyError::Overflow { buffered: 77 })
+        );
+    }
+}
@@ -0,0 +1,63 @@
+use crate::{
+    auth::Auth,
+    ingest::{ingest, Outcome},
+};
+use db_api::{
+    get_pending_rejected_messages, get_rejected_message, mark_message_replayed,
+    record_failed_replay, Catalog,
+};
+use sqlx::PgPool;
+
+/// Runs stored rejected messages through the ingest pipeline again.
+/// Replays the given message ids, or every pending message if none are
+/// given. Messages that go through without failures are marked as
+/// replayed, the others keep their place in the store with the new error.
+///
+/// Signatures are checked again, but the source allowlist, timestamps
+/// and nonces are not: the message was already let through when it was
+/// first received.
+pub async fn replay(
+    pool: &PgPool,
+    auth: &Auth,
+    catalog: &Catalog,
+    ids: &[i64],
+) -> Result<(), anyhow::Error> {
+    let messages = if ids.is_empty() {
+        get_pending_rejected_messages(pool).await?
+    } else {
+        let mut messages = Vec::new();
+        for id in ids {
+            messages.push(get_rejected_message(*id, pool).await?);
+        }
+        messages
+    };
+
+    tracing::info!("Replaying {} rejected messages", messages.len());
+
+    let mut recovered = 0;
+    for message in messages {
+        tracing::info!(
+            "Replaying message {} from {} received at {}",
+            message.id,
+            message.sender,
+            message.received_at
+        );
+
+        let Outcome { ack, failures } =
+            ingest(pool, auth, catalog, &message.payload, None).await;
+        tracing::debug!("Replay result: {}", ack.to_xml());
+
+        if failures.is_empty() {
+            mark_message_replayed(message.id, pool).await?;
+            recovered += 1;
+            tracing::info!("Message {} replayed successfully", message.id);
+        } else {
+            let error = failures.join("; ");
+            record_failed_replay(message.id, &error, pool).await?;
+            tracing::warn!("Message {} failed again: {error}", message.id);
+        }
+    }
+
+    tracing::info!("Recovered {recovered} messages");
+    Ok(())
+}
@@ -0,0 +1,582 @@
+use roxmltree::{Document, Node, TextPos};
+use std::{collections::HashMap, sync::OnceLock};
+
+/// XSD of the client order protocol.
+pub const CLIENT_ORDER_XSD: &str = include_str!("../schema/client_order.xsd");
+
+/// Name of the element documents are wrapped in before validation,
+/// as a document may hold several root elements one after the other.
+const WRAPPER: &str = "Messages";
+
+/// Where a document breaks the schema and how.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaError {
+    pub line: u32,
+    pub column: u32,
+    /// Path to the offending element or attribute,
+    /// e.g. `/ClientOrder[2]/Order/@Quantity`.
+    pub path: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}: {}",
+            self.line, self.column, self.path, self.message
+        )
+    }
+}
+
+/// The errors found in a document, in document order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaErrors(pub Vec<SchemaError>);
+
+impl std::fmt::Display for SchemaErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let errors = self.0.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        write!(f, "{}", errors.join("; "))
+    }
+}
+
+impl std::error::Error for SchemaErrors {}
+
+/// Built in XSD types used by the protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Builtin {
+    String,
+    Int,
+    Long,
+}
+
+impl Builtin {
+    fn from_name(name: &str) -> Option<Builtin> {
+        match name {
+            "xs:string" => Some(Builtin::String),
+            "xs:int" => Some(Builtin::Int),
+            "xs:long" => Some(Builtin::Long),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SimpleType {
+    name: String,
+    base: Builtin,
+    enumeration: Vec<String>,
+    min_length: Option<usize>,
+}
+
+#[derive(Debug)]
+struct AttributeDecl {
+    name: String,
+    ty: String,
+    required: bool,
+}
+
+#[derive(Debug)]
+struct ChildDecl {
+    name: String,
+    ty: String,
+    min_occurs: usize,
+    /// `None` for unbounded.
+    max_occurs: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+struct ComplexType {
+    children: Vec<ChildDecl>,
+    attributes: Vec<AttributeDecl>,
+}
+
+/// A schema loaded from an XSD.
+///
+/// Only the part of XSD the protocol schema uses is supported: top level
+/// elements, named complex types with a sequence of elements and
+/// attributes, and named simple types restricting `xs:string`, `xs:int`
+/// or `xs:long` with enumerations or a minimum length.
+#[derive(Debug, Default)]
+pub struct Schema {
+    roots: HashMap<String, String>,
+    complex_types: HashMap<String, ComplexType>,
+    simple_types: HashMap<String, SimpleType>,
+}
+
+impl Schema {
+    /// The schema of the client order protocol, loaded once.
+    pub fn client_order() -> &'static Schema {
+        static SCHEMA: OnceLock<Schema> = OnceLock::new();
+        SCHEMA.get_or_init(|| {
+            Schema::parse(CLIENT_ORDER_XSD)
+                .expect("the client order XSD is valid")
+        })
+    }
+
+    pub fn parse(xsd: &str) -> Result<Schema, String> {
+        let document = Document::parse(xsd).map_err(|e| e.to_string())?;
+        let mut schema = Schema::default();
+
+        for node in document.root_element().children().filter(Node::is_element)
+        {
+            let name = required(node, "name")?;
+            match node.tag_name().name() {
+                "element" => {
+                    let ty = required(node, "type")?;
+                    schema.roots.insert(name, ty);
+                }
+                "complexType" => {
+                    schema.complex_types.insert(name, complex_type(node)?);
+                }
+                "simpleType" => {
+                    let ty = simple_type(node, name.clone())?;
+                    schema.simple_types.insert(name, ty);
+                }
+                other => return Err(format!("unsupported element {other}")),
+            }
+        }
+
+        Ok(schema)
+    }
+
+    /// Validates a document holding one or more root elements.
+    pub fn validate(&self, document: &str) -> Result<(), SchemaErrors> {
+        let wrapped = wrap(document);
+        let shift = WRAPPER.len() as u32 + 2;
+        let locate = |pos: TextPos| {
+            // The opening wrapper tag sits before the first line.
+            let column = match pos.row {
+                1 => pos.col.saturating_sub(shift).max(1),
+                _ => pos.col,
+            };
+            (pos.row, column)
+        };
+
+        let parsed = match Document::parse(&wrapped) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                let (line, column) = locate(e.pos());
+                return Err(SchemaErrors(vec![SchemaError {
+                    line,
+                    column,
+                    path: "/".to_string(),
+                    message: format!("not well-formed: {e}"),
+                }]));
+            }
+        };
+
+        let mut validator = Validator {
+            schema: self,
+            document: &parsed,
+            locate: &locate,
+            errors: Vec::new(),
+        };
+
+        let roots = parsed.root_element().children().filter(Node::is_element);
+        for (node, path) in with_paths(roots, "") {
+            let name = node.tag_name().name();
+            match self.roots.get(name) {
+                Some(ty) => validator.element(node, ty, &path),
+                None => {
+                    let expected = self.root_names().join(", ");
+                    validator.error(
+                        node.range().start,
+                        path,
+                        format!(
+                            "unexpected element {name}, expected one of \
+                            {expected}"
+                        ),
+                    )
+                }
+            }
+        }
+
+        let text = parsed.root_element().children().filter(Node::is_text);
+        for node in text {
+            validator.text(node, "/");
+        }
+
+        match validator.errors.is_empty() {
+            true => Ok(()),
+            false => Err(SchemaErrors(validator.errors)),
+        }
+    }
+
+    fn root_names(&self) -> Vec<&str> {
+        let mut names =
+            self.roots.keys().map(|n| n.as_str()).collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn describe(&self, ty: &str) -> String {
+        match self.simple_types.get(ty) {
+            Some(simple) if !simple.enumeration.is_empty() => format!(
+                "{} (one of {})",
+                simple.name,
+                simple.enumeration.join(", ")
+            ),
+            Some(simple) => simple.name.clone(),
+            None => ty.to_string(),
+        }
+    }
+}
+
+/// Puts the document inside a wrapper element so several root elements
+/// can be parsed at once. An XML declaration is blanked out, keeping
+/// every other character where it was.
+fn wrap(document: &str) -> String {
+    let document = document.strip_prefix('\u{feff}').unwrap_or(document);
+    let trimmed = document.trim_start();
+    let declaration = match trimmed.starts_with("<?xml") {
+        true => trimmed.find("?>").map(|end| {
+            let start = document.len() - trimmed.len();
+            start..start + end + 2
+        }),
+        false => None,
+    };
+
+    let mut wrapped = format!("<{WRAPPER}>");
+    match declaration {
+        Some(range) => {
+            wrapped.push_str(&document[..range.start]);
+            let blank = document[range.clone()].chars().map(|c| {
+                if c == '\n' {
+                    '\n'
+                } else {
+                    ' '
+                }
+            });
+            wrapped.extend(blank);
+            wrapped.push_str(&document[range.end..]);
+        }
+        None => wrapped.push_str(document),
+    }
+    wrapped.push_str(&format!("</{WRAPPER}>"));
+    wrapped
+}
+
+/// Pairs elements with their path, numbering those that share a name.
+fn with_paths<'a, 'input>(
+    nodes: impl Iterator<Item = Node<'a, 'input>>,
+    parent: &str,
+) -> Vec<(Node<'a, 'input>, String)> {
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    nodes
+        .map(|node| {
+            let name = node.tag_name().name();
+            let index = seen.entry(name).or_default();
+            *index += 1;
+            (node, format!("{parent}/{name}[{index}]"))
+        })
+        .collect()
+}
+
+struct Validator<'s, 'd, 'input, L> {
+    schema: &'s Schema,
+    document: &'d Document<'input>,
+    locate: &'s L,
+    errors: Vec<SchemaError>,
+}
+
+impl<L: Fn(TextPos) -> (u32, u32)> Validator<'_, '_, '_, L> {
+    fn error(&mut self, position: usize, path: String, message: String) {
+        let (line, column) = (self.locate)(self.document.text_pos_at(position));
+        self.errors.push(SchemaError {
+            line,
+            column,
+            path,
+            message,
+        });
+    }
+
+    fn element(&mut self, node: Node, ty: &str, path: &str) {
+        let Some(complex) = self.schema.complex_types.get(ty) else {
+            let message = format!("unknown type {ty} in the schema");
+            return self.error(node.range().start, path.to_string(), message);
+        };
+
+        for attribute in node.attributes() {
+            let attribute_path = format!("{path}/@{}", attribute.name());
+            let decl = complex
+                .attributes
+                .iter()
+                .find(|decl| decl.name == attribute.name());
+            match decl {
+                Some(decl) => {
+                    if let Err(message) =
+                        self.check_value(&decl.ty, attribute.value())
+                    {
+                        let position = attribute.range().start;
+                        self.error(position, attribute_path, message);
+                    }
+                }
+                None => self.error(
+                    attribute.range().start,
+                    attribute_path,
+                    "unexpected attribute".to_string(),
+                ),
+            }
+        }
+
+        for decl in complex.attributes.iter().filter(|decl| decl.required) {
+            if node.attribute(decl.name.as_str()).is_none() {
+                let message = format!(
+                    "missing required attribute {} of type {}",
+                    decl.name,
+                    self.schema.describe(&decl.ty)
+                );
+                self.error(node.range().start, path.to_string(), message);
+            }
+        }
+
+        let children = node.children().filter(Node::is_element);
+        let children = with_paths(children, path);
+        let mut children = children.into_iter().peekable();
+        for decl in &complex.children {
+            let mut count = 0;
+            while let Some((child, child_path)) = children.peek() {
+                let within_max = decl.max_occurs.is_none_or(|max| count < max);
+                if child.tag_name().name() != decl.name || !within_max {
+                    break;
+                }
+                self.element(*child, &decl.ty, child_path);
+                children.next();
+                count += 1;
+            }
+
+            if count < decl.min_occurs {
+                let (position, found) = match children.peek() {
+                    Some((child, _)) => (
+                        child.range().start,
+                        format!(", found {}", child.tag_name().name()),
+                    ),
+                    None => (node.range().start, String::new()),
+                };
+                let message = format!("missing element {}{found}", decl.name);
+                self.error(position, path.to_string(), message);
+            }
+        }
+
+        for (child, child_path) in children {
+            let message =
+                format!("unexpected element {}", child.tag_name().name());
+            self.error(child.range().start, child_path, message);
+        }
+
+        for text in node.children().filter(Node::is_text) {
+            self.text(text, path);
+        }
+    }
+
+    /// Elements of the protocol only hold other elements.
+    fn text(&mut self, node: Node, path: &str) {
+        let text = node.text().unwrap_or_default();
+        if let Some(offset) = text.find(|c: char| !c.is_whitespace()) {
+            let message = "unexpected text".to_string();
+            self.error(node.range().start + offset, path.to_string(), message);
+        }
+    }
+
+    fn check_value(&self, ty: &str, value: &str) -> Result<(), String> {
+        let (base, simple) = match Builtin::from_name(ty) {
+            Some(builtin) => (builtin, None),
+            None => match self.schema.simple_types.get(ty) {
+                Some(simple) => (simple.base, Some(simple)),
+                None => return Err(format!("unknown type {ty} in the schema")),
+            },
+        };
+
+        let expected = || {
+            format!("expected {}, found \"{value}\"", self.schema.describe(ty))
+        };
+        let valid = match base {
+            Builtin::String => true,
+            Builtin::Int => value.trim().parse::<i32>().is_ok(),
+            Builtin::Long => value.trim().parse::<i64>().is_ok(),
+        };
+        if !valid {
+            return Err(expected());
+        }
+
+        let Some(simple) = simple else {
+            return Ok(());
+        };
+        if !simple.enumeration.is_empty()
+            && !simple.enumeration.iter().any(|v| v == value)
+        {
+            return Err(expected());
+        }
+        if simple
+            .min_length
+            .is_some_and(|min| value.chars().count() < min)
+        {
+            return Err(expected());
+        }
+        Ok(())
+    }
+}
+
+fn required(node: Node, attribute: &str) -> Result<String, String> {
+    node.attribute(attribute)
+        .map(str::to_string)
+        .ok_or_else(|| {
+            format!("{} without {attribute}", node.tag_name().name())
+        })
+}
+
+fn complex_type(node: Node) -> Result<ComplexType, String> {
+    let mut complex = ComplexType::default();
+    for child in node.children().filter(Node::is_element) {
+        match child.tag_name().name() {
+            "sequence" => {
+                for element in child.children().filter(Node::is_element) {
+                    complex.children.push(child_decl(element)?);
+                }
+            }
+            "attribute" => complex.attributes.push(AttributeDecl {
+                name: required(child, "name")?,
+                ty: required(child, "type")?,
+                required: child.attribute("use") == Some("required"),
+            }),
+            other => return Err(format!("unsupported element {other}")),
+        }
+    }
+    Ok(complex)
+}
+
+fn child_decl(node: Node) -> Result<ChildDecl, String> {
+    if node.tag_name().name() != "element" {
+        let name = node.tag_name().name();
+        return Err(format!("unsupported element {name} in sequence"));
+    }
+
+    let occurs = |attribute: &str| match node.attribute(attribute) {
+        None => Ok(Some(1)),
+        Some("unbounded") => Ok(None),
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("invalid {attribute} \"{value}\"")),
+    };
+
+    Ok(ChildDecl {
+        name: required(node, "name")?,
+        ty: required(node, "type")?,
+        min_occurs: occurs("minOccurs")?.unwrap_or_default(),
+        max_occurs: occurs("maxOccurs")?,
+    })
+}
+
+fn simple_type(node: Node, name: String) -> Result<SimpleType, String> {
+    let restriction = node
+        .children()
+        .find(|child| child.has_tag_name("restriction"))
+        .ok_or_else(|| format!("simple type {name} without restriction"))?;
+    let base = required(restriction, "base")?;
+    let base = Builtin::from_name(&base)
+        .ok_or_else(|| format!("unsupported base type {base}"))?;
+
+    let mut simple = SimpleType {
+        name,
+        base,
+        enumeration: Vec::new(),
+        min_length: None,
+    };
+    for facet in restriction.children().filter(Node::is_element) {
+        let value = required(facet, "value")?;
+        match facet.tag_name().name() {
+            "enumeration" => simple.enumeration.push(value),
+            "minLength" => {
+                let min = value
+                    .parse()
+                    .map_err(|_| format!("invalid minLength \"{value}\""))?;
+                simple.min_length = Some(min);
+            }
+            other => return Err(format!("unsupported facet {other}")),
+        }
+    }
+    Ok(simple)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(document: &str) -> Vec<SchemaError> {
+        match Schema::client_order().validate(document) {
+            Ok(()) => Vec::new(),
+            Err(SchemaErrors(errors)) => errors,
+        }
+    }
+
+    #[test]
+    fn test_valid_documents() {
+        let document = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ClientOrder Signature="ab12">
+  <Client NameId="Kling Inc"/>
+  <Order Number="1" WorkPiece="P9" Quantity="3" DueDate="4" LatePen="€5,74" EarlyPen="€66,32"/>
+</ClientOrder>
+<CancelOrder>
+  <Client NameId="Kling Inc"/>
+  <Order Number="2"/>
+</CancelOrder>
+<AmendOrder>
+  <Client NameId="Kling Inc"/>
+  <Order Number="3" Quantity="5"/>
+</AmendOrder>"#;
+        assert_eq!(errors(document), vec![]);
+
+        let mock = include_str!("../mock_client_orders.xml");
+        assert_eq!(errors(mock), vec![]);
+    }
+
+    #[test]
+    fn test_attribute_errors() {
+        let document = r#"<ClientOrder>
+  <Client NameId="Kling Inc"/>
+  <Order Number="1" WorkPiece="P9" Quantity="3" DueDate="4" LatePen="€5,74" EarlyPen="€66,32"/>
+</ClientOrder>
+<ClientOrder>
+  <Client NameId="Kling Inc"/>
+  <Order Number="2" WorkPiece="" Quantity="three" DueDate="4" LatePen="€5,74"/>
+</ClientOrder>"#;
+
+        let errors = errors(document);
+        assert_eq!(errors.len(), 3);
+
+        assert_eq!(errors[0].path, "/ClientOrder[2]/Order[1]/@WorkPiece");
+        assert_eq!((errors[0].line, errors[0].column), (7, 21));
+        assert_eq!(errors[0].message, "expected WorkPiece, found \"\"");
+
+        assert_eq!(errors[1].path, "/ClientOrder[2]/Order[1]/@Quantity");
+        assert_eq!(errors[1].message, "expected xs:int, found \"three\"");
+
+        assert_eq!(errors[2].path, "/ClientOrder[2]/Order[1]");
+        assert_eq!((errors[2].line, errors[2].column), (7, 3));
+        assert!(errors[2].message.contains("EarlyPen"));
+    }
+
+    #[test]
+    fn test_element_errors() {
+        let document =
+            "<ClientOrder><Client NameId=\"Kling Inc\"/></ClientOrder>\
+            <Unknown/>";
+
+        let errors = errors(document);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].path, "/ClientOrder[1]");
+        assert_eq!((errors[0].line, errors[0].column), (1, 1));
+        assert_eq!(errors[0].message, "missing element Order");
+        assert_eq!(errors[1].path, "/Unknown[1]");
+        assert_eq!((errors[1].line, errors[1].column), (1, 56));
+    }
+
+    #[test]
+    fn test_not_well_formed() {
+        let errors = errors("<ClientOrder>\n  <Client NameId=\"Kling\">\n");
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.starts_with("not well-formed"));
+    }
+}
@@ -0,0 +1,158 @@
+use crate::{auth::Auth, ingest::process_document};
+use db_api::Catalog;
+use sqlx::PgPool;
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Largest document accepted in a single frame.
+pub const MAX_FRAME_SIZE: u32 = 16 * 1024 * 1024;
+
+/// How long to wait before accepting again after an accept error, e.g.
+/// when the process ran out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Accepts length-prefixed client order documents over TCP.
+///
+/// Every frame is a big-endian `u32` byte count followed by the document,
+/// which may start with a `Format:` header line naming its format.
+/// Each frame is answered with an `OrderAck` using the same framing, so a
+/// client can keep the connection open and send several documents.
+/// Connections from blocked sources are closed right away.
+///
+/// Errors accepting a connection are logged and the listener keeps
+/// going, so the other transports are not stopped by them. Only an
+/// error that means the listener itself is broken is returned.
+pub async fn serve(
+    pool: PgPool,
+    auth: Arc<Auth>,
+    catalog: Arc<Catalog>,
+    listener: TcpListener,
+) -> io::Result<()> {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) if is_connection_error(&e) => {
+                tracing::debug!("TCP connection lost before accept: {e}");
+                continue;
+            }
+            // EINVAL, the socket is no longer listening.
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => return Err(e),
+            Err(e) => {
+                tracing::error!("Error accepting TCP connection: {e}");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        if !auth.allows(addr.ip()) {
+            continue;
+        }
+        tracing::info!("Accepted TCP connection from {addr}");
+
+        let pool = pool.clone();
+        let auth = auth.clone();
+        let catalog = catalog.clone();
+        tokio::spawn(async move {
+            let handled =
+                handle_connection(&pool, &auth, &catalog, stream, addr).await;
+            match handled {
+                Ok(()) => tracing::info!("TCP connection from {addr} closed"),
+                Err(e) => tracing::error!("TCP connection from {addr}: {e}"),
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    pool: &PgPool,
+    auth: &Auth,
+    catalog: &Catalog,
+    mut stream: TcpStream,
+    addr: SocketAddr,
+) -> io::Result<()> {
+    while let Some(data) = read_frame(&mut stream).await? {
+        tracing::info!("Received {} bytes from {addr}", data.len());
+
+        let sender = format!("tcp://{addr}");
+        let ack =
+            process_document(pool, auth, catalog, &data, None, &sender).await;
+        let reply = ack.to_xml();
+
+        stream.write_u32(reply.len() as u32).await?;
+        stream.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Whether an accept error only concerns the connection being accepted,
+/// which the client closed or reset before it could be accepted.
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+    )
+}
+
+/// Reads the next frame, `None` if the connection was closed before it.
+async fn read_frame(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> io::Result<Option<Vec<u8>>> {
+    let length = match stream.read_u32().await {
+        Ok(length) => length,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    if length > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {length} bytes exceeds {MAX_FRAME_SIZE}"),
+        ));
+    }
+
+    // The buffer grows with the data that actually arrives, so a large
+    // length alone does not allocate anything.
+    let mut data = Vec::new();
+    stream.take(length.into()).read_to_end(&mut data).await?;
+    if data.len() < length as usize {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_frame() {
+        let mut stream = &b"\0\0\0\x03abc\0\0\0\x01d"[..];
+        assert_eq!(read_frame(&mut stream).await.unwrap().unwrap(), b"abc");
+        assert_eq!(read_frame(&mut stream).await.unwrap().unwrap(), b"d");
+        assert!(read_frame(&mut stream).await.unwrap().is_none());
+
+        // A frame that claims more data than is sent.
+        let mut stream = &b"\0\xff\0\0abc"[..];
+        let error = read_frame(&mut stream).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut stream = &b"\x7f\0\0\0abc"[..];
+        let error = read_frame(&mut stream).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_connection_errors() {
+        let error = |kind: io::ErrorKind| io::Error::from(kind);
+        assert!(is_connection_error(&error(
+            io::ErrorKind::ConnectionAborted
+        )));
+        assert!(is_connection_error(&error(io::ErrorKind::ConnectionReset)));
+        // Out of file descriptors, EMFILE.
+        assert!(!is_connection_error(&io::Error::from_raw_os_error(24)));
+    }
+}
@@ -0,0 +1,424 @@
+use crate::{
+    ack::OrderAck,
+    auth::Auth,
+    format::{self, DecodeError, Message},
+    ingest::{decode_document, process_messages},
+    reassembly::{self, Reassembler},
+};
+use db_api::Catalog;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, error::TrySendError},
+    time::Instant,
+};
+
+/// Largest payload a single UDP datagram can carry.
+pub const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+/// Number of worker tasks processing documents.
+pub const DEFAULT_WORKERS: usize = 4;
+
+/// Number of jobs each worker can have waiting before new ones
+/// for the clients it serves are dropped.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 256;
+
+/// The messages of one client from a document, waiting to be processed.
+struct Job {
+    addr: SocketAddr,
+    messages: Result<Vec<Message>, DecodeError>,
+    /// What is kept in the dead-letter store if the messages fail.
+    data: Vec<u8>,
+}
+
+/// Counters for the jobs passing through the worker queues.
+#[derive(Debug, Default)]
+pub struct QueueMetrics {
+    pub queued: AtomicU64,
+    pub processed: AtomicU64,
+    pub dropped: AtomicU64,
+}
+
+impl QueueMetrics {
+    /// Jobs queued but not yet processed.
+    pub fn depth(&self) -> u64 {
+        let queued = self.queued.load(Ordering::Relaxed);
+        queued.saturating_sub(self.processed.load(Ordering::Relaxed))
+    }
+}
+
+/// Receives documents over UDP and hands them to a pool of workers.
+///
+/// Receiving never waits on the database: complete documents are decoded,
+/// queued for a worker and the socket is read again right away. Each
+/// client is always served by the same worker, so its messages are
+/// processed in the order they arrived, whichever address sent them.
+/// Documents with messages of several clients are split, one job per
+/// client, and each job is acknowledged on its own. When a worker's
+/// queue is full the job is dropped and the sender is told to try
+/// again later.
+pub struct Server {
+    pub pool: sqlx::PgPool,
+    pub auth: Arc<Auth>,
+    pub catalog: Arc<Catalog>,
+    pub socket: UdpSocket,
+    pub buf: Vec<u8>,
+    pub workers: usize,
+    pub queue_capacity: usize,
+    pub metrics: Arc<QueueMetrics>,
+}
+
+impl Server {
+    pub async fn run(self) -> Result<(), io::Error> {
+        let Server {
+            pool,
+            auth,
+            catalog,
+            socket,
+            mut buf,
+            workers,
+            queue_capacity,
+            metrics,
+        } = self;
+
+        let socket = Arc::new(socket);
+        let queues = (0..workers.max(1))
+            .map(|_| {
+                let (tx, rx) = mpsc::channel(queue_capacity.max(1));
+                tokio::spawn(work(
+                    pool.clone(),
+                    auth.clone(),
+                    catalog.clone(),
+                    socket.clone(),
+                    metrics.clone(),
+                    rx,
+                ));
+                tx
+            })
+            .collect::<Vec<_>>();
+        let dispatcher = Dispatcher {
+            queues,
+            metrics: metrics.clone(),
+        };
+
+        let mut reassembler = Reassembler::default();
+        let mut expiry = tokio::time::interval(reassembly::DEFAULT_TIMEOUT);
+        let mut reported_drops = 0;
+
+        loop {
+            let (length, addr) = tokio::select! {
+                received = socket.recv_from(&mut buf) => received?,
+                _ = expiry.tick() => {
+                    for (addr, length) in reassembler.expire(Instant::now()) {
+                        tracing::error!(
+                            "Dropped {length} bytes from {addr}: \
+                            document was not completed in time"
+                        );
+                        let ack = OrderAck::rejected(
+                            "document was not completed in time",
+                        );
+                        reply(&socket, addr, &ack).await;
+                    }
+                    reported_drops = report(&metrics, reported_drops);
+                    continue;
+                }
+            };
+
+            // Datagrams from blocked sources are dropped without a reply.
+            if !auth.allows(addr.ip()) {
+                continue;
+            }
+
+            tracing::info!("Received {length} bytes from {addr}");
+
+            let data =
+                match reassembler.push(addr, &buf[..length], Instant::now()) {
+                    Ok(Some(data)) => data,
+                    Ok(None) => {
+                        tracing::debug!("Waiting for more data from {addr}");
+                        continue;
+                    }
+                    Err(e) => {
+                        tracing::error!("Dropped data from {addr}: {e}");
+                        reply(
+                            &socket,
+                            addr,
+                            &OrderAck::rejected(e.to_string()),
+                        )
+                        .await;
+                        continue;
+                    }
+                };
+
+            for job in dispatcher.dispatch(addr, data)? {
+                tracing::warn!("Dropped job from {}: queue full", job.addr);
+                let ack = OrderAck::rejected("server busy, try again");
+                reply(&socket, job.addr, &ack).await;
+            }
+        }
+    }
+}
+
+/// Splits documents by client and queues each part for the worker
+/// that serves the client.
+struct Dispatcher {
+    queues: Vec<mpsc::Sender<Job>>,
+    metrics: Arc<QueueMetrics>,
+}
+
+impl Dispatcher {
+    /// Queues the messages of a document.
+    /// Returns the jobs that were dropped because their queue was full.
+    fn dispatch(
+        &self,
+        addr: SocketAddr,
+        data: Vec<u8>,
+    ) -> io::Result<Vec<Job>> {
+        let mut dropped = Vec::new();
+        for (worker, job) in split(addr, data, self.queues.len()) {
+            match self.queues[worker].try_send(job) {
+                Ok(()) => {
+                    self.metrics.queued.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(TrySendError::Full(job)) => {
+                    self.metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                    dropped.push(job);
+                }
+                Err(TrySendError::Closed(_)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "worker stopped",
+                    ));
+                }
+            }
+        }
+        Ok(dropped)
+    }
+}
+
+/// Decodes a document into one job per client, along with the worker
+/// that serves the client. Clients keep the order of their messages.
+///
+/// Documents that can not be decoded, or have no messages, make a
+/// single job for the worker of the sender address. The parts of a
+/// document with several clients are dead-lettered as JSON, so a
+/// replay only repeats the messages of that client.
+fn split(addr: SocketAddr, data: Vec<u8>, workers: usize) -> Vec<(usize, Job)> {
+    let messages = match decode_document(&data, None) {
+        Ok(messages) => messages,
+        Err(e) => {
+            let job = Job {
+                addr,
+                messages: Err(e),
+                data,
+            };
+            return vec![(shard(&addr.ip(), workers), job)];
+        }
+    };
+
+    let mut clients: Vec<(String, Vec<Message>)> = Vec::new();
+    for message in messages {
+        let name = &message.client().name_id;
+        match clients.iter_mut().find(|(client, _)| client == name) {
+            Some((_, messages)) => messages.push(message),
+            None => clients.push((name.clone(), vec![message])),
+        }
+    }
+
+    if clients.len() <= 1 {
+        let worker = match clients.first() {
+            Some((client, _)) => shard(client, workers),
+            None => shard(&addr.ip(), workers),
+        };
+        let messages = clients.pop().map(|(_, m)| m).unwrap_or_default();
+        let job = Job {
+            addr,
+            messages: Ok(messages),
+            data,
+        };
+        return vec![(worker, job)];
+    }
+
+    clients
+        .into_iter()
+        .map(|(client, messages)| {
+            let data =
+                format::encode_json(&messages.iter().collect::<Vec<_>>());
+            let job = Job {
+                addr,
+                messages: Ok(messages),
+                data: data.into_bytes(),
+            };
+            (shard(&client, workers), job)
+        })
+        .collect()
+}
+
+/// Processes the jobs of one queue, one at a time.
+async fn work(
+    pool: sqlx::PgPool,
+    auth: Arc<Auth>,
+    catalog: Arc<Catalog>,
+    socket: Arc<UdpSocket>,
+    metrics: Arc<QueueMetrics>,
+    mut queue: mpsc::Receiver<Job>,
+) {
+    while let Some(Job {
+        addr,
+        messages,
+        data,
+    }) = queue.recv().await
+    {
+        let sender = format!("udp://{addr}");
+        let ack =
+            process_messages(&pool, &auth, &catalog, messages, &data, &sender)
+                .await;
+        metrics.processed.fetch_add(1, Ordering::Relaxed);
+        reply(&socket, addr, &ack).await;
+    }
+}
+
+/// Picks the worker that serves a client, or a sender address.
+fn shard(key: &impl Hash, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
+
+/// Logs the queue depth, and a warning if documents were dropped since
+/// the last report. Returns the number of drops reported so far.
+fn report(metrics: &QueueMetrics, reported_drops: u64) -> u64 {
+    let dropped = metrics.dropped.load(Ordering::Relaxed);
+    if dropped > reported_drops {
+        tracing::warn!(
+            "Dropped {} jobs with full queues ({dropped} in total)",
+            dropped - reported_drops
+        );
+    }
+    tracing::debug!("Queue depth: {}", metrics.depth());
+    dropped
+}
+
+/// Sends an acknowledgement back to the sender of a document, split
+/// over as many datagrams as it needs.
+/// Failing to reply is logged but does not stop the server.
+async fn reply(socket: &UdpSocket, addr: SocketAddr, ack: &OrderAck) {
+    for part in ack.to_datagrams(MAX_DATAGRAM_SIZE) {
+        if let Err(e) = socket.send_to(part.as_bytes(), addr).await {
+            tracing::error!("Error sending acknowledgement to {addr}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_numbers(queue: &mut mpsc::Receiver<Job>) -> Vec<(String, i32)> {
+        let mut numbers = Vec::new();
+        while let Ok(job) = queue.try_recv() {
+            for message in job.messages.unwrap() {
+                numbers
+                    .push((message.client().name_id.clone(), message.number()));
+            }
+        }
+        numbers
+    }
+
+    #[test]
+    fn test_dispatch_keeps_client_order() {
+        let (senders, mut receivers): (Vec<_>, Vec<_>) =
+            (0..4).map(|_| mpsc::channel(16)).unzip();
+        let dispatcher = Dispatcher {
+            queues: senders,
+            metrics: Default::default(),
+        };
+        let order = |client: &str, number: i32| {
+            format!("{client},{number},P9,3,4,€5,€6\n")
+        };
+
+        // The same clients, sending from several addresses.
+        let documents = [
+            ("10.0.0.1:5000", order("A", 1) + &order("B", 1)),
+            ("10.0.0.2:5000", order("A", 2)),
+            ("10.0.0.1:5000", order("B", 2) + &order("A", 3)),
+            ("10.0.0.3:5000", order("A", 4)),
+        ];
+        for (addr, document) in documents {
+            let addr = addr.parse().unwrap();
+            let dropped = dispatcher.dispatch(addr, document.into()).unwrap();
+            assert!(dropped.is_empty());
+        }
+        assert_eq!(dispatcher.metrics.queued.load(Ordering::Relaxed), 6);
+
+        let queued =
+            receivers.iter_mut().map(client_numbers).collect::<Vec<_>>();
+        for client in ["A", "B"] {
+            let queues = queued
+                .iter()
+                .filter_map(|numbers| {
+                    let numbers = numbers
+                        .iter()
+                        .filter(|(name, _)| name == client)
+                        .map(|(_, number)| *number)
+                        .collect::<Vec<_>>();
+                    (!numbers.is_empty()).then_some(numbers)
+                })
+                .collect::<Vec<_>>();
+            let expected = if client == "A" {
+                vec![1, 2, 3, 4]
+            } else {
+                vec![1, 2]
+            };
+            assert_eq!(queues, vec![expected], "messages of client {client}");
+        }
+    }
+
+    #[test]
+    fn test_split_by_client() {
+        let addr = "10.0.0.1:5000".parse().unwrap();
+        let document = "A,1,P9,3,4,€5,€6\nB,1,P9,3,4,€5,€6\n";
+
+        let jobs = split(addr, document.into(), 4);
+        assert_eq!(jobs.len(), 2);
+        for (_, job) in jobs {
+            // Each part is kept with only the messages of its client.
+            let stored = decode_document(&job.data, None).unwrap();
+            assert_eq!(stored, job.messages.unwrap());
+        }
+
+        let single = document.lines().next().unwrap();
+        let jobs = split(addr, single.into(), 4);
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].1.data, single.as_bytes());
+
+        let jobs = split(addr, b"<ClientOrder".to_vec(), 4);
+        assert!(matches!(
+            jobs.as_slice(),
+            [(
+                _,
+                Job {
+                    messages: Err(_),
+                    ..
+                }
+            )]
+        ));
+    }
+
+    #[test]
+    fn test_queue_depth() {
+        let metrics = QueueMetrics::default();
+        metrics.queued.store(5, Ordering::Relaxed);
+        metrics.processed.store(3, Ordering::Relaxed);
+        assert_eq!(metrics.depth(), 2);
+    }
+}
@@ -0,0 +1,131 @@
+//! Compares `Bom::insert_batch` with inserting the entries one by one,
+//! as it was done before. Both do the same work: the entries are
+//! inserted and a `NewBomEntry` job is queued for their order.
+//!
+//! Needs a database with the migrations applied, found through
+//! `DATABASE_URL`. Everything runs in transactions that are rolled back,
+//! so the database is left as it was. Run with `cargo bench -p db-api`.
+
+use db_api::{events::NewBomEntry, tx_enqueue_job, tx_insert_test_order, Bom};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::{
+    collections::BTreeSet,
+    time::{Duration, Instant},
+};
+
+/// Orders of 50, 500 and 5000 pieces on a 3 step recipe.
+const BATCH_SIZES: [i32; 3] = [50, 500, 5000];
+const STEPS: i32 = 3;
+const RUNS: u32 = 5;
+
+async fn insert_one_by_one(
+    batch: &[Bom],
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<Vec<i64>, anyhow::Error> {
+    let mut ids = Vec::with_capacity(batch.len());
+    for entry in batch {
+        let (id,): (i64,) = sqlx::query_as(
+            "INSERT INTO bom(
+                order_id,
+                transformation_id,
+                piece_number,
+                pieces_total,
+                step_number,
+                steps_total
+            )
+            VALUES($1, $2, $3, $4, $5, $6)
+            RETURNING id
+            ",
+        )
+        .bind(entry.order_id)
+        .bind(entry.transformation_id)
+        .bind(entry.piece_number)
+        .bind(entry.pieces_total)
+        .bind(entry.step_number)
+        .bind(entry.steps_total)
+        .fetch_one(&mut **tx)
+        .await?;
+        ids.push(id);
+    }
+
+    let orders = batch.iter().map(|entry| entry.order_id);
+    for order_id in orders.collect::<BTreeSet<_>>() {
+        tx_enqueue_job(&NewBomEntry { order_id }, tx).await?;
+    }
+    Ok(ids)
+}
+
+/// Adds an order to refer to, and returns its BOM.
+async fn setup(
+    pieces: i32,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<Vec<Bom>, db_api::Error> {
+    let order =
+        tx_insert_test_order("insert_batch bench", pieces, STEPS.into(), tx)
+            .await?;
+
+    let mut batch = Vec::new();
+    for piece_number in 1..=pieces {
+        for (step, transformation_id) in
+            order.transformation_ids.iter().enumerate()
+        {
+            batch.push(Bom::new(
+                order.order_id,
+                *transformation_id,
+                piece_number,
+                pieces,
+                step as i32 + 1,
+                STEPS,
+            ));
+        }
+    }
+    Ok(batch)
+}
+
+async fn bench(
+    pool: &PgPool,
+    pieces: i32,
+    bulk: bool,
+) -> Result<Duration, anyhow::Error> {
+    let mut total = Duration::ZERO;
+    for _ in 0..RUNS {
+        let mut tx = pool.begin().await?;
+        let batch = setup(pieces, &mut tx).await?;
+
+        let start = Instant::now();
+        let ids = if bulk {
+            Bom::tx_insert_batch(&batch, &mut tx).await?
+        } else {
+            insert_one_by_one(&batch, &mut tx).await?
+        };
+        total += start.elapsed();
+
+        assert_eq!(ids.len(), batch.len());
+        tx.rollback().await?;
+    }
+    Ok(total / RUNS)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    dotenv::dotenv().ok();
+    let database_url = std::env::var("DATABASE_URL")
+        .map_err(|_| anyhow::anyhow!("DATABASE_URL is not set"))?;
+    let pool = PgPool::connect(&database_url).await?;
+
+    println!(
+        "{:>8} {:>14} {:>14}",
+        "entries", "one by one", "insert_batch"
+    );
+    for pieces in BATCH_SIZES {
+        let one_by_one = bench(&pool, pieces, false).await?;
+        let bulk = bench(&pool, pieces, true).await?;
+        println!(
+            "{:>8} {:>14.2?} {:>14.2?}",
+            pieces * STEPS,
+            one_by_one,
+            bulk
+        );
+    }
+    Ok(())
+}
@@ -1,11 +1,11 @@
-CREATE IF NOT EXISTS TABLE production {
+CREATE TABLE IF NOT EXISTS production (
 
   id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
   order_id BIGINT NOT NULL,
   bom_id BIGINT NOT NULL,
   day INT NOT NULL,
   timeslot INT NOT NULL,
-  prod_line_id INT NOT NULL,
+  prod_line_id BIGINT NOT NULL,
 
   FOREIGN KEY (order_id) REFERENCES orders (id)
   ON DELETE CASCADE,
@@ -14,5 +14,12 @@ CREATE IF NOT EXISTS TABLE production {
 
   CHECK (day > 0),
   CHECK (timeslot > 0 AND timeslot <= 12),
-  CHECK (prod_line > 0 AND prod_line <= 6),
-}
+  CHECK (prod_line_id > 0 AND prod_line_id <= 6),
+
+  -- A BOM step is made once, in a single slot.
+  UNIQUE (bom_id)
+);
+
+-- Double bookings are found by looking up rows of the same line slot.
+CREATE INDEX production_slot ON production (day, timeslot, prod_line_id);
+CREATE INDEX production_order_id ON production (order_id);
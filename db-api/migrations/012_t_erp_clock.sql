@@ -0,0 +1,10 @@
+-- Single row table holding the current day of the ERP simulation.
+CREATE TABLE IF NOT EXISTS erp_clock (
+  id BOOLEAN PRIMARY KEY DEFAULT TRUE,
+  current_day INT NOT NULL DEFAULT 1,
+
+  CHECK (id),
+  CHECK (current_day > 0)
+);
+
+INSERT INTO erp_clock DEFAULT VALUES;
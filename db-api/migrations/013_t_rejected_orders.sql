@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS rejected_orders (
+  id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+
+  client_name VARCHAR NOT NULL,
+  number INT NOT NULL,
+  work_piece VARCHAR NOT NULL,
+  quantity INT NOT NULL,
+  due_date INT NOT NULL,
+  late_pen VARCHAR NOT NULL,
+  early_pen VARCHAR NOT NULL,
+  reasons TEXT[] NOT NULL,
+  rejected_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,14 @@
+-- Dead-letter store for documents that could not be fully ingested.
+CREATE TABLE IF NOT EXISTS rejected_messages (
+  id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+
+  payload BYTEA NOT NULL,
+  sender VARCHAR NOT NULL,
+  error TEXT NOT NULL,
+  received_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  replay_attempts INT NOT NULL DEFAULT 0,
+  replayed_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS rejected_messages_pending
+  ON rejected_messages (id) WHERE replayed_at IS NULL;
@@ -0,0 +1 @@
+ALTER TABLE orders ADD COLUMN cancelled BOOLEAN NOT NULL DEFAULT FALSE;
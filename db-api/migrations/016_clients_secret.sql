@@ -0,0 +1,2 @@
+-- Shared secret used to verify the signatures of a client's messages.
+ALTER TABLE clients ADD COLUMN secret VARCHAR;
@@ -0,0 +1,36 @@
+-- Where an order is in its lifecycle, see `db_api::OrderStatus` for the
+-- transitions that are allowed.
+CREATE TYPE order_status AS ENUM (
+  'received',
+  'bom_resolved',
+  'scheduled',
+  'in_production',
+  'completed',
+  'shipped',
+  'cancelled'
+);
+
+ALTER TABLE orders ADD COLUMN status order_status NOT NULL DEFAULT 'received';
+UPDATE orders SET status = 'cancelled' WHERE cancelled;
+ALTER TABLE orders DROP COLUMN cancelled;
+
+CREATE TABLE IF NOT EXISTS order_status_history (
+  id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+
+  order_id BIGINT NOT NULL,
+  -- NULL when the order was placed.
+  from_status order_status,
+  to_status order_status NOT NULL,
+  changed_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+  FOREIGN KEY(order_id) REFERENCES orders(id)
+    ON DELETE CASCADE
+);
+
+CREATE INDEX order_status_history_order_id ON order_status_history(order_id);
+
+INSERT INTO order_status_history (order_id, to_status)
+SELECT id, 'received' FROM orders;
+
+INSERT INTO order_status_history (order_id, from_status, to_status)
+SELECT id, 'received', status FROM orders WHERE status = 'cancelled';
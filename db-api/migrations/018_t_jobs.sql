@@ -0,0 +1,28 @@
+-- Work for the background components, written in the same transaction
+-- as the change that caused it. See `db_api::claim_job`.
+CREATE TYPE job_status AS ENUM (
+  'pending',
+  'running',
+  'done',
+  'failed'
+);
+
+CREATE TABLE IF NOT EXISTS jobs (
+  id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+
+  -- Name of the notification channel the job was announced on.
+  channel VARCHAR NOT NULL,
+  payload VARCHAR NOT NULL,
+  status job_status NOT NULL DEFAULT 'pending',
+  attempts INT NOT NULL DEFAULT 0,
+  max_attempts INT NOT NULL DEFAULT 5,
+  -- When a pending job may run, or when the lease of a running job ends.
+  run_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  last_error TEXT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+  CHECK (max_attempts > 0)
+);
+
+CREATE INDEX jobs_claimable ON jobs(run_at)
+  WHERE status IN ('pending', 'running');
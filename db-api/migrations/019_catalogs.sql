@@ -0,0 +1,58 @@
+-- Pieces and tools are rows of catalog tables instead of values of a
+-- domain, so new ones can be registered without a migration. See
+-- `db_api::Catalog`.
+
+-- The view depends on `pieces.name`, it is created again below.
+DROP VIEW order_details;
+
+ALTER TABLE pieces ALTER COLUMN name TYPE VARCHAR;
+DROP DOMAIN piece_names;
+
+CREATE TYPE piece_kind AS ENUM (
+  'raw_material',
+  'intermediate',
+  'final_product'
+);
+
+ALTER TABLE pieces
+  ALTER COLUMN kind TYPE piece_kind
+  USING replace(kind, ' ', '_')::piece_kind;
+DROP DOMAIN piece_kinds;
+
+CREATE VIEW order_details AS
+SELECT
+  c.name AS client_name,
+  o.number,
+  p.name AS piece_name,
+  o.quantity,
+  o.due_date,
+  o.early_pen,
+  o.late_pen
+FROM orders o
+INNER JOIN clients c ON c.id = o.client_id
+INNER JOIN pieces p ON p.id = o.piece_id;
+
+-- The table takes the name of the domain, which goes first.
+ALTER TABLE transformations ALTER COLUMN tool TYPE VARCHAR;
+DROP DOMAIN tools;
+
+CREATE TABLE IF NOT EXISTS tools (
+  id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+
+  name VARCHAR NOT NULL,
+
+  UNIQUE(name)
+);
+
+INSERT INTO tools (name) VALUES ('T1'), ('T2'), ('T3'), ('T4'), ('T5'), ('T6');
+
+ALTER TABLE transformations ADD COLUMN tool_id BIGINT REFERENCES tools(id);
+UPDATE transformations t SET tool_id = tools.id
+FROM tools
+WHERE tools.name = t.tool;
+ALTER TABLE transformations ALTER COLUMN tool_id SET NOT NULL;
+
+ALTER TABLE transformations DROP COLUMN tool;
+
+ALTER TABLE transformations
+  ADD UNIQUE(from_piece, to_piece, tool_id);
@@ -0,0 +1,3 @@
+-- BOM explosion multiplies the runs of earlier steps by the quantity,
+-- see `db_api::path_runs`, so it must be at least one.
+ALTER TABLE transformations ADD CHECK (quantity > 0);
@@ -0,0 +1,48 @@
+-- How long transformations take, in seconds, see
+-- `db_api::Transformation::timeslots_on`.
+ALTER TABLE transformations
+  ADD COLUMN processing_time INT,
+  -- Time to get a line ready for the transformation, e.g. to change
+  -- its tool, before the first run.
+  ADD COLUMN setup_time INT NOT NULL DEFAULT 0 CHECK (setup_time >= 0);
+
+UPDATE transformations t SET processing_time = times.processing_time
+FROM (VALUES
+  ('P8', 'P9', 'T5', 45),
+  ('P8', 'P7', 'T6', 15),
+  ('P2', 'P8', 'T1', 45),
+  ('P4', 'P7', 'T3', 15),
+  ('P4', 'P6', 'T2', 25),
+  ('P4', 'P5', 'T4', 25),
+  ('P3', 'P4', 'T3', 25),
+  ('P3', 'P4', 'T2', 15),
+  ('P1', 'P3', 'T1', 45)
+) AS times(from_piece, to_piece, tool, processing_time),
+  pieces f, pieces p, tools
+WHERE f.name = times.from_piece AND f.id = t.from_piece
+  AND p.name = times.to_piece AND p.id = t.to_piece
+  AND tools.name = times.tool AND tools.id = t.tool_id;
+
+-- Transformations added since the seed take a single timeslot until
+-- they are given a time.
+UPDATE transformations SET processing_time = 5 WHERE processing_time IS NULL;
+
+ALTER TABLE transformations
+  ALTER COLUMN processing_time SET NOT NULL,
+  ADD CHECK (processing_time > 0);
+
+-- Lines that are faster or slower than usual at a transformation.
+-- A NULL time keeps the one of the transformation.
+CREATE TABLE IF NOT EXISTS transformation_line_times (
+  transformation_id BIGINT NOT NULL,
+  prod_line_id BIGINT NOT NULL,
+  processing_time INT CHECK (processing_time > 0),
+  setup_time INT CHECK (setup_time >= 0),
+
+  FOREIGN KEY (transformation_id) REFERENCES transformations (id)
+  ON DELETE CASCADE,
+
+  CHECK (prod_line_id > 0 AND prod_line_id <= 6),
+
+  PRIMARY KEY (transformation_id, prod_line_id)
+);
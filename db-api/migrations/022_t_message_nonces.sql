@@ -0,0 +1,12 @@
+-- Nonces of recent signed messages, so a captured message can not be
+-- sent again, see `db_api::record_message_nonce`.
+CREATE TABLE IF NOT EXISTS message_nonces (
+  client VARCHAR NOT NULL,
+  nonce VARCHAR NOT NULL,
+  seen_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+  PRIMARY KEY (client, nonce)
+);
+
+CREATE INDEX IF NOT EXISTS message_nonces_seen_at
+  ON message_nonces (seen_at);
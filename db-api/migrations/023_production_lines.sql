@@ -0,0 +1,28 @@
+-- Production lines are rows of a catalog table, like pieces and tools,
+-- so lines can be added without a migration. See `db_api::Catalog`.
+CREATE TABLE IF NOT EXISTS production_lines (
+  id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+
+  name VARCHAR NOT NULL,
+
+  UNIQUE(name)
+);
+
+-- Takes the ids 1 to 6 the plan already refers to.
+INSERT INTO production_lines (name)
+VALUES ('L1'), ('L2'), ('L3'), ('L4'), ('L5'), ('L6');
+
+ALTER TABLE production
+  DROP CONSTRAINT production_prod_line_id_check,
+  ADD FOREIGN KEY (prod_line_id) REFERENCES production_lines (id);
+
+ALTER TABLE transformation_line_times
+  DROP CONSTRAINT transformation_line_times_prod_line_id_check,
+  ADD FOREIGN KEY (prod_line_id) REFERENCES production_lines (id)
+  ON DELETE CASCADE;
+
+-- A line slot holds a single BOM step. Plans stored at the same time
+-- can not both take it, see `db_api::Production::insert_batch`.
+DROP INDEX production_slot;
+CREATE UNIQUE INDEX production_line_slot
+ON production (day, timeslot, prod_line_id);
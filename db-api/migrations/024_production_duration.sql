@@ -0,0 +1,28 @@
+-- A BOM step runs for `duration` timeslots from its start, see
+-- `db_api::Transformation::timeslots_on`. Runs may go on into the
+-- next day.
+ALTER TABLE production
+  ADD COLUMN duration INT NOT NULL DEFAULT 1 CHECK (duration > 0);
+ALTER TABLE production ALTER COLUMN duration DROP DEFAULT;
+
+-- The timeslots a run takes, counted from the first timeslot of day 1.
+CREATE FUNCTION production_run(day INT, timeslot INT, duration INT)
+RETURNS int4range
+LANGUAGE SQL IMMUTABLE
+AS $$
+  SELECT int4range(
+    (day - 1) * 12 + timeslot,
+    (day - 1) * 12 + timeslot + duration
+  )
+$$;
+
+CREATE EXTENSION IF NOT EXISTS btree_gist;
+
+-- Runs on a line do not overlap. Plans stored at the same time can not
+-- both take a timeslot, see `db_api::Production::insert_batch`.
+DROP INDEX production_line_slot;
+ALTER TABLE production ADD CONSTRAINT production_line_runs
+EXCLUDE USING gist (
+  prod_line_id WITH =,
+  production_run(day, timeslot, duration) WITH &&
+);
@@ -1,88 +1,266 @@
-use sqlx::{postgres::types::PgMoney, PgPool};
+use crate::production::Production;
+use crate::{Currency, Error, Money, OrderStatus, PieceId, Result, ToolId};
+use sqlx::PgPool;
+use std::collections::{BTreeSet, HashMap, HashSet};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Tools {
-    T1,
-    T2,
-    T3,
-    T4,
-    T5,
-    T6,
-    INVALID,
+pub struct Transformation {
+    pub id: i64,
+    pub from_piece: PieceId,
+    pub to_piece: PieceId,
+    pub tool: ToolId,
+    /// Units of `from_piece` used to make one `to_piece`.
+    pub quantity: i32,
+    pub cost: Money,
+    /// Seconds a run takes, on lines without a time of their own.
+    pub processing_time: i32,
+    /// Seconds to get a line ready for the transformation, e.g. to change
+    /// its tool, on lines without a time of their own.
+    pub setup_time: i32,
+    /// Lines that are faster or slower than usual, by line id.
+    pub line_times: Vec<LineTimes>,
+}
+
+/// Times of a transformation on one production line.
+/// A `None` time keeps the one of the transformation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineTimes {
+    pub prod_line_id: i64,
+    pub processing_time: Option<i32>,
+    pub setup_time: Option<i32>,
 }
 
-impl From<String> for Tools {
-    fn from(value: String) -> Self {
-        match value.as_str() {
-            "T1" => Tools::T1,
-            "T2" => Tools::T2,
-            "T3" => Tools::T3,
-            "T4" => Tools::T4,
-            "T5" => Tools::T5,
-            "T6" => Tools::T6,
-            _ => Tools::INVALID,
+impl Transformation {
+    /// Seconds a run takes on a line.
+    pub fn processing_time_on(&self, prod_line_id: i64) -> i32 {
+        self.times_on(prod_line_id)
+            .and_then(|times| times.processing_time)
+            .unwrap_or(self.processing_time)
+    }
+
+    /// Seconds to get a line ready for the transformation.
+    pub fn setup_time_on(&self, prod_line_id: i64) -> i32 {
+        self.times_on(prod_line_id)
+            .and_then(|times| times.setup_time)
+            .unwrap_or(self.setup_time)
+    }
+
+    /// Timeslots a run takes on a line, at least one. Runs that follow
+    /// a different transformation on the line also take its setup time.
+    pub fn timeslots_on(&self, prod_line_id: i64, with_setup: bool) -> i32 {
+        let mut seconds = i64::from(self.processing_time_on(prod_line_id));
+        if with_setup {
+            seconds += i64::from(self.setup_time_on(prod_line_id));
         }
+        let unit = i64::from(Production::TIME_SLOT_UNIT);
+        let timeslots = (seconds + unit - 1) / unit;
+        timeslots.clamp(1, i32::MAX.into()) as i32
     }
-}
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Transformation {
-    pub id: i64,
-    pub from_piece: i64,
-    pub to_piece: i64,
-    pub tool: Tools,
-    pub quantity: i32,
-    pub cost: PgMoney,
+    fn times_on(&self, prod_line_id: i64) -> Option<&LineTimes> {
+        self.line_times
+            .iter()
+            .find(|times| times.prod_line_id == prod_line_id)
+    }
 }
 
 pub type Recipe = Vec<Transformation>;
 
-/// Gets transformations that output "to_piece".
-pub async fn get_imediate_recipe(
-    to_piece: i64,
-    pool: &PgPool,
-) -> Result<Recipe, sqlx::Error> {
-    sqlx::query_as!(
-        Transformation,
-        "SELECT * FROM transformations WHERE to_piece = $1",
-        to_piece
+/// How many times each transformation of a path runs to make `quantity`
+/// pieces. The path goes from the final piece to the root piece, and
+/// so do the counts.
+///
+/// A transformation that takes N units of its input piece makes every
+/// transformation further down the path run N times as often. Fails
+/// with [`Error::QuantityOverflow`] if a count does not fit in an `i32`.
+pub fn path_runs(path: &[Transformation], quantity: i32) -> Result<Vec<i32>> {
+    let mut runs = Vec::with_capacity(path.len());
+    // `None` once the count overflowed, which only matters if there is a
+    // step left to run that many times.
+    let mut needed = Some(quantity);
+    for transformation in path {
+        let count = needed.ok_or(Error::QuantityOverflow)?;
+        runs.push(count);
+        needed = count.checked_mul(transformation.quantity);
+    }
+    Ok(runs)
+}
+
+/// Cost of making `quantity` pieces with every transformation of a path,
+/// each run as many times as [`path_runs`] says.
+/// Fails with [`Error::Money`] if the cost overflows.
+pub fn path_cost(path: &[Transformation], quantity: i32) -> Result<Money> {
+    let runs = path_runs(path, quantity)?;
+    let costs = path
+        .iter()
+        .zip(runs)
+        .map(|(transformation, runs)| {
+            transformation.cost.checked_mul(runs.into())
+        })
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(Money::checked_sum(costs, Currency::default())?)
+}
+
+/// Fills in the line times of the transformations.
+async fn with_line_times(mut recipe: Recipe, pool: &PgPool) -> Result<Recipe> {
+    let ids = recipe.iter().map(|t| t.id).collect::<Vec<_>>();
+    let mut line_times = HashMap::<_, Vec<_>>::new();
+    for row in sqlx::query!(
+        "SELECT * FROM transformation_line_times
+        WHERE transformation_id = ANY($1)
+        ORDER BY transformation_id, prod_line_id
+        ",
+        &ids
     )
     .fetch_all(pool)
-    .await
+    .await?
+    {
+        line_times
+            .entry(row.transformation_id)
+            .or_default()
+            .push(LineTimes {
+                prod_line_id: row.prod_line_id,
+                processing_time: row.processing_time,
+                setup_time: row.setup_time,
+            });
+    }
+
+    for transformation in &mut recipe {
+        if let Some(times) = line_times.remove(&transformation.id) {
+            transformation.line_times = times;
+        }
+    }
+    Ok(recipe)
+}
+
+/// A transformation of a recipe, with how far it is from the final piece.
+/// Transformations that output the final piece have a depth of 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecipeStep {
+    pub depth: i32,
+    pub transformation: Transformation,
 }
 
 /// Gets the full recipe required to produce the piece.
-/// Traverses the transformations table until it finds the root piece.
-/// Return a flat list of transformations that represents the recipe tree.
+/// Return a flat list of transformations that represents the recipe tree,
+/// ordered from the final piece to the root pieces.
 pub async fn get_repice_to_root(
-    final_piece_id: i64,
+    final_piece_id: PieceId,
     pool: &PgPool,
-) -> Result<Recipe, sqlx::Error> {
-    let mut targets = vec![final_piece_id];
-    let mut recipe: Recipe = Vec::new();
-
-    loop {
-        let mut transforms = Vec::new();
+) -> Result<Recipe> {
+    let steps = get_recipe_steps(final_piece_id, pool).await?;
+    Ok(steps.into_iter().map(|step| step.transformation).collect())
+}
 
-        for piece in targets.iter() {
-            let mut tfs = get_imediate_recipe(*piece, pool).await?;
-            transforms.append(&mut tfs);
-        }
+/// Gets every transformation needed to produce the piece, in a single
+/// query that walks the transformations table down to the root pieces.
+/// Transformations reached through several paths are listed once, at
+/// the smallest depth they are found. Steps are ordered by depth.
+///
+/// Fails with [`Error::RecipeCycle`] if a piece is needed to make itself.
+pub async fn get_recipe_steps(
+    final_piece_id: PieceId,
+    pool: &PgPool,
+) -> Result<Vec<RecipeStep>> {
+    // `path` holds the pieces from the final piece down to the input of
+    // the transformation. The walk stops at the first repeated piece.
+    let rows = sqlx::query!(
+        r#"WITH RECURSIVE recipe AS (
+            SELECT
+                t.*,
+                1 AS depth,
+                ARRAY[t.to_piece, t.from_piece] AS path,
+                t.from_piece = t.to_piece AS is_cycle
+            FROM transformations t
+            WHERE t.to_piece = $1
+        UNION ALL
+            SELECT
+                t.*,
+                r.depth + 1,
+                r.path || t.from_piece,
+                t.from_piece = ANY(r.path)
+            FROM transformations t
+            INNER JOIN recipe r ON t.to_piece = r.from_piece
+            WHERE NOT r.is_cycle
+        )
+        SELECT
+            id AS "id!",
+            from_piece AS "from_piece!: PieceId",
+            to_piece AS "to_piece!: PieceId",
+            tool_id AS "tool!: ToolId",
+            quantity AS "quantity!",
+            cost AS "cost!: Money",
+            processing_time AS "processing_time!",
+            setup_time AS "setup_time!",
+            depth AS "depth!",
+            path AS "path!",
+            is_cycle AS "is_cycle!"
+        FROM recipe
+        ORDER BY depth, id
+        "#,
+        final_piece_id as PieceId
+    )
+    .fetch_all(pool)
+    .await?;
 
-        if transforms.is_empty() {
-            break;
-        }
+    if let Some(row) = rows.iter().find(|row| row.is_cycle) {
+        let cycle = cycle_of(&row.path);
+        let names = sqlx::query!(
+            "SELECT id, name FROM pieces WHERE id = ANY($1)",
+            &cycle
+        )
+        .fetch_all(pool)
+        .await?;
+        let name = |id: &i64| {
+            names
+                .iter()
+                .find(|piece| piece.id == *id)
+                .map(|piece| piece.name.clone())
+                .unwrap_or_else(|| id.to_string())
+        };
+        return Err(Error::RecipeCycle(cycle.iter().map(name).collect()));
+    }
 
-        transforms.dedup();
-        targets.clear();
-        for t in transforms.iter() {
-            targets.push(t.from_piece);
-        }
+    let mut seen = HashSet::new();
+    let (depths, recipe): (Vec<_>, Recipe) = rows
+        .into_iter()
+        .filter(|row| seen.insert(row.id))
+        .map(|row| {
+            let transformation = Transformation {
+                id: row.id,
+                from_piece: row.from_piece,
+                to_piece: row.to_piece,
+                tool: row.tool,
+                quantity: row.quantity,
+                cost: row.cost,
+                processing_time: row.processing_time,
+                setup_time: row.setup_time,
+                line_times: Vec::new(),
+            };
+            (row.depth, transformation)
+        })
+        .unzip();
 
-        recipe.append(&mut transforms);
-    }
+    let recipe = with_line_times(recipe, pool).await?;
+    Ok(depths
+        .into_iter()
+        .zip(recipe)
+        .map(|(depth, transformation)| RecipeStep {
+            depth,
+            transformation,
+        })
+        .collect())
+}
 
-    Ok(recipe)
+/// Takes the cycle at the end of a recipe path, in production order.
+/// For the path `[P9, P8, P2, P1, P8]` that is `[P8, P1, P2, P8]`:
+/// P8 is made from P2, which is made from P1, which is made from P8.
+pub(crate) fn cycle_of(path: &[i64]) -> Vec<i64> {
+    let Some((last, rest)) = path.split_last() else {
+        return Vec::new();
+    };
+    let start = rest.iter().position(|piece| piece == last).unwrap_or(0);
+    path[start..].iter().rev().copied().collect()
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -116,76 +294,272 @@ impl Bom {
         }
     }
 
-    pub async fn get_by_id(id: i64, pool: &PgPool) -> Result<Bom, sqlx::Error> {
-        sqlx::query_as!(Bom, "SELECT * FROM bom WHERE id = $1", id)
+    pub async fn get_by_id(id: i64, pool: &PgPool) -> Result<Bom> {
+        Ok(sqlx::query_as!(Bom, "SELECT * FROM bom WHERE id = $1", id)
             .fetch_one(pool)
-            .await
+            .await?)
+    }
+
+    /// Gets the BOM entries of an order, ordered by piece and step.
+    pub async fn get_by_order(
+        order_id: i64,
+        pool: &PgPool,
+    ) -> Result<Vec<Bom>> {
+        Ok(sqlx::query_as!(
+            Bom,
+            "SELECT * FROM bom
+            WHERE order_id = $1
+            ORDER BY piece_number, step_number
+            ",
+            order_id
+        )
+        .fetch_all(pool)
+        .await?)
+    }
+
+    /// Deletes every BOM entry of an order.
+    /// Returns the number of deleted entries.
+    pub async fn delete_by_order(order_id: i64, pool: &PgPool) -> Result<u64> {
+        Ok(
+            sqlx::query!("DELETE FROM bom WHERE order_id = $1", order_id)
+                .execute(pool)
+                .await?
+                .rows_affected(),
+        )
     }
 
     /// Inserts a batch of BOM entries into the database.
     /// The entries are inserted in a single transaction.
     /// If any of the entries fail to be inserted, the transaction
     /// is rolled back and the error is returned.
-    /// If all entries are inserted successfully, a notification is
-    /// send to the `NewBomEntry` channel with the ids of the inserted
-    /// entries separated by commas.
+    /// If all entries are inserted successfully, a `NewBomEntry` job is
+    /// queued with the id of the order, one for each order in the batch.
+    /// The entries can then be read with [`Bom::get_by_order`].
+    ///
+    /// Returns the ids given to the entries, in the order of the batch.
     pub async fn insert_batch(
         batch: &[Bom],
         pool: &PgPool,
-    ) -> Result<(), sqlx::Error> {
+    ) -> Result<Vec<i64>> {
         let mut tx = pool.begin().await?;
+        let ids = Bom::tx_insert_batch(batch, &mut tx).await?;
+        tx.commit().await?;
+        Ok(ids)
+    }
 
-        let mut ids = Vec::new();
+    /// Replaces the BOM entries of an order with a batch of new ones, and
+    /// moves the order from received to BOM resolved. The entries are
+    /// written in a single transaction with the status, under a lock on
+    /// the order, so doing it again for the same order gives the same
+    /// entries instead of a duplicate.
+    ///
+    /// Fails with [`Error::InvalidTransition`] if the quantity of the
+    /// order can no longer change, as its entries are then in use.
+    ///
+    /// Returns the ids given to the entries, in the order of the batch.
+    pub async fn resolve_order(
+        order_id: i64,
+        batch: &[Bom],
+        pool: &PgPool,
+    ) -> Result<Vec<i64>> {
+        let mut tx = pool.begin().await?;
+        let status = sqlx::query!(
+            r#"SELECT status AS "status: OrderStatus"
+            FROM orders
+            WHERE id = $1
+            FOR UPDATE
+            "#,
+            order_id
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .status;
+        if !status.can_change_quantity() {
+            return Err(Error::InvalidTransition {
+                order_id,
+                from: status,
+                to: OrderStatus::BomResolved,
+            });
+        }
 
+        sqlx::query!("DELETE FROM bom WHERE order_id = $1", order_id)
+            .execute(&mut *tx)
+            .await?;
+        let ids = Bom::tx_insert_batch(batch, &mut tx).await?;
+        if status == OrderStatus::Received {
+            let resolved = OrderStatus::BomResolved;
+            crate::tx_set_order_status(order_id, resolved, &mut tx).await?;
+        }
+
+        tx.commit().await?;
+        Ok(ids)
+    }
+
+    /// Same as [`Bom::insert_batch`]. Use with a transaction type connection
+    pub async fn tx_insert_batch(
+        batch: &[Bom],
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<Vec<i64>> {
+        // One array per column, so the whole batch is a single statement.
+        let mut order_ids = Vec::with_capacity(batch.len());
+        let mut transformation_ids = Vec::with_capacity(batch.len());
+        let mut piece_numbers = Vec::with_capacity(batch.len());
+        let mut pieces_totals = Vec::with_capacity(batch.len());
+        let mut step_numbers = Vec::with_capacity(batch.len());
+        let mut steps_totals = Vec::with_capacity(batch.len());
         for entry in batch {
-            let Bom {
-                id: _, // id is auto-generated by the database upon insertion
+            order_ids.push(entry.order_id);
+            transformation_ids.push(entry.transformation_id);
+            piece_numbers.push(entry.piece_number);
+            pieces_totals.push(entry.pieces_total);
+            step_numbers.push(entry.step_number);
+            steps_totals.push(entry.steps_total);
+        }
+
+        // Rows are inserted in the order of the batch, so they get
+        // increasing ids. RETURNING has no order of its own, sorting the
+        // ids puts them back in the order of the batch.
+        let mut ids = sqlx::query!(
+            "INSERT INTO bom(
                 order_id,
                 transformation_id,
                 piece_number,
                 pieces_total,
                 step_number,
-                steps_total,
-            } = entry;
-            let id = sqlx::query!(
-                "
-            INSERT INTO bom(
+                steps_total
+            )
+            SELECT
                 order_id,
                 transformation_id,
                 piece_number,
                 pieces_total,
                 step_number,
                 steps_total
-            )
-            VALUES($1, $2, $3, $4, $5, $6)
-            RETURNING id
-            ",
+            FROM UNNEST(
+                $1::BIGINT[],
+                $2::BIGINT[],
+                $3::INT[],
+                $4::INT[],
+                $5::INT[],
+                $6::INT[]
+            ) WITH ORDINALITY AS batch(
                 order_id,
                 transformation_id,
                 piece_number,
                 pieces_total,
                 step_number,
-                steps_total
+                steps_total,
+                position
             )
-            .fetch_one(&mut *tx)
-            .await?
-            .id;
+            ORDER BY position
+            RETURNING id
+            ",
+            &order_ids,
+            &transformation_ids,
+            &piece_numbers,
+            &pieces_totals,
+            &step_numbers,
+            &steps_totals
+        )
+        .fetch_all(&mut **tx)
+        .await?
+        .into_iter()
+        .map(|row| row.id)
+        .collect::<Vec<_>>();
+        ids.sort_unstable();
 
-            ids.push(id);
+        let orders = order_ids.into_iter().collect::<BTreeSet<_>>();
+        for order_id in orders {
+            let event = crate::events::NewBomEntry { order_id };
+            crate::tx_enqueue_job(&event, tx).await?;
         }
 
-        let channel = crate::NotificationChannel::NewBomEntry;
-        let ids = ids
-            .into_iter()
-            .map(|id| id.to_string())
-            .collect::<Vec<_>>()
-            .join(",");
+        Ok(ids)
+    }
+}
 
-        let query = format!("NOTIFY {}, '{}'", channel, ids);
-        sqlx::query(&query).execute(&mut *tx).await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        tx.commit().await?;
+    #[test]
+    fn test_cycle_of() {
+        assert_eq!(cycle_of(&[9, 8, 2, 1, 8]), vec![8, 1, 2, 8]);
+        assert_eq!(cycle_of(&[4, 4]), vec![4, 4]);
+        assert_eq!(cycle_of(&[]), Vec::<i64>::new());
+    }
+
+    fn step(quantity: i32, cents: i64) -> Transformation {
+        Transformation {
+            id: 1,
+            from_piece: PieceId(1),
+            to_piece: PieceId(2),
+            tool: ToolId(1),
+            quantity,
+            cost: Money::from_cents(cents),
+            processing_time: 25,
+            setup_time: 10,
+            line_times: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_path_cost() {
+        let path = [step(1, 4500), step(1, 2500)];
+        assert_eq!(path_cost(&path, 3).unwrap(), Money::from_cents(21000));
+        assert_eq!(path_cost(&[], 3).unwrap(), Money::from_cents(0));
+        assert!(matches!(
+            path_cost(&[step(1, i64::MAX)], 2),
+            Err(Error::Money(crate::MoneyError::Overflow))
+        ));
+
+        // 3 runs of the last step, and 6 of the first one.
+        let path = [step(2, 4500), step(1, 2500)];
+        assert_eq!(path_cost(&path, 3).unwrap(), Money::from_cents(28500));
+    }
+
+    #[test]
+    fn test_timeslots_on() {
+        let mut transformation = step(1, 0);
+        transformation.line_times = vec![
+            LineTimes {
+                prod_line_id: 2,
+                processing_time: Some(15),
+                setup_time: None,
+            },
+            LineTimes {
+                prod_line_id: 3,
+                processing_time: None,
+                setup_time: Some(0),
+            },
+        ];
+
+        // 25 seconds take 5 timeslots, plus 2 to set up the line.
+        assert_eq!(transformation.timeslots_on(1, false), 5);
+        assert_eq!(transformation.timeslots_on(1, true), 7);
+        assert_eq!(transformation.processing_time_on(2), 15);
+        assert_eq!(transformation.setup_time_on(2), 10);
+        assert_eq!(transformation.timeslots_on(2, true), 5);
+        assert_eq!(transformation.timeslots_on(3, true), 5);
+
+        transformation.processing_time = 1;
+        assert_eq!(transformation.timeslots_on(1, false), 1);
+    }
+
+    #[test]
+    fn test_path_runs() {
+        let path = [step(3, 0), step(2, 0), step(1, 0)];
+        assert_eq!(path_runs(&path, 2).unwrap(), vec![2, 6, 12]);
+        assert_eq!(path_runs(&path[2..], 5).unwrap(), vec![5]);
+        assert!(path_runs(&[], 5).unwrap().is_empty());
 
-        Ok(())
+        // The input of the last step is not counted, so it may not fit.
+        let path = [step(2, 0), step(i32::MAX, 0)];
+        let half = i32::MAX / 2;
+        assert_eq!(path_runs(&path, half).unwrap(), vec![half, half * 2]);
+        assert!(matches!(
+            path_runs(&path, half + 1),
+            Err(Error::QuantityOverflow)
+        ));
     }
 }
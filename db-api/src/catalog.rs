@@ -0,0 +1,319 @@
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+/// Id of a row of the `pieces` table.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    sqlx::Type,
+)]
+#[sqlx(transparent)]
+pub struct PieceId(pub i64);
+
+/// Id of a row of the `tools` table.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    sqlx::Type,
+)]
+#[sqlx(transparent)]
+pub struct ToolId(pub i64);
+
+impl std::fmt::Display for PieceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::fmt::Display for ToolId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Where a piece stands in production.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type,
+)]
+#[sqlx(type_name = "piece_kind", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum PieceKind {
+    /// Bought from suppliers.
+    RawMaterial,
+    /// Made and used by the plant itself.
+    Intermediate,
+    /// Sold to clients.
+    FinalProduct,
+}
+
+impl std::fmt::Display for PieceKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PieceKind::RawMaterial => write!(f, "raw material"),
+            PieceKind::Intermediate => write!(f, "intermediate"),
+            PieceKind::FinalProduct => write!(f, "final product"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Piece {
+    pub id: PieceId,
+    pub name: String,
+    pub kind: PieceKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tool {
+    pub id: ToolId,
+    pub name: String,
+}
+
+/// A row of the `production_lines` table.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProdLine {
+    pub id: i64,
+    pub name: String,
+}
+
+/// The pieces, tools and production lines known to the plant.
+///
+/// Loaded once with [`Catalog::load`], so it does not see rows
+/// registered after that.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Catalog {
+    pieces: Vec<Piece>,
+    tools: Vec<Tool>,
+    prod_lines: Vec<ProdLine>,
+}
+
+impl Catalog {
+    pub fn new(
+        pieces: Vec<Piece>,
+        tools: Vec<Tool>,
+        prod_lines: Vec<ProdLine>,
+    ) -> Self {
+        Self {
+            pieces,
+            tools,
+            prod_lines,
+        }
+    }
+
+    /// Reads every piece, tool and production line, in id order.
+    pub async fn load(pool: &PgPool) -> Result<Self> {
+        let pieces = sqlx::query_as!(
+            Piece,
+            r#"SELECT
+                id AS "id: PieceId",
+                name,
+                kind AS "kind: PieceKind"
+            FROM pieces
+            ORDER BY id
+            "#
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let tools = sqlx::query_as!(
+            Tool,
+            r#"SELECT id AS "id: ToolId", name FROM tools ORDER BY id"#
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let prod_lines = sqlx::query_as!(
+            ProdLine,
+            "SELECT id, name FROM production_lines ORDER BY id"
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(Self {
+            pieces,
+            tools,
+            prod_lines,
+        })
+    }
+
+    pub fn pieces(&self) -> &[Piece] {
+        &self.pieces
+    }
+
+    pub fn tools(&self) -> &[Tool] {
+        &self.tools
+    }
+
+    pub fn prod_lines(&self) -> &[ProdLine] {
+        &self.prod_lines
+    }
+
+    /// Pieces clients can order.
+    pub fn final_products(&self) -> impl Iterator<Item = &Piece> {
+        self.pieces
+            .iter()
+            .filter(|piece| piece.kind == PieceKind::FinalProduct)
+    }
+
+    /// Fails with [`Error::UnknownPiece`] if there is no piece `name`.
+    pub fn piece(&self, name: &str) -> Result<&Piece> {
+        self.pieces
+            .iter()
+            .find(|piece| piece.name == name)
+            .ok_or_else(|| Error::UnknownPiece(name.to_string()))
+    }
+
+    /// Fails with [`Error::UnknownPiece`] if there is no piece `id`.
+    pub fn piece_by_id(&self, id: PieceId) -> Result<&Piece> {
+        self.pieces
+            .iter()
+            .find(|piece| piece.id == id)
+            .ok_or_else(|| Error::UnknownPiece(id.to_string()))
+    }
+
+    /// Fails with [`Error::UnknownTool`] if there is no tool `name`.
+    pub fn tool(&self, name: &str) -> Result<&Tool> {
+        self.tools
+            .iter()
+            .find(|tool| tool.name == name)
+            .ok_or_else(|| Error::UnknownTool(name.to_string()))
+    }
+
+    /// Fails with [`Error::UnknownTool`] if there is no tool `id`.
+    pub fn tool_by_id(&self, id: ToolId) -> Result<&Tool> {
+        self.tools
+            .iter()
+            .find(|tool| tool.id == id)
+            .ok_or_else(|| Error::UnknownTool(id.to_string()))
+    }
+}
+
+/// Gets a piece by name, `None` if it does not exist.
+pub async fn get_piece(name: &str, pool: &PgPool) -> Result<Option<Piece>> {
+    Ok(sqlx::query_as!(
+        Piece,
+        r#"SELECT
+            id AS "id: PieceId",
+            name,
+            kind AS "kind: PieceKind"
+        FROM pieces
+        WHERE name = $1
+        "#,
+        name
+    )
+    .fetch_optional(pool)
+    .await?)
+}
+
+/// Adds a piece to the catalog.
+/// Fails with [`Error::UniqueViolation`] if the name is taken.
+pub async fn register_piece(
+    name: &str,
+    kind: PieceKind,
+    pool: &PgPool,
+) -> Result<Piece> {
+    Ok(sqlx::query_as!(
+        Piece,
+        r#"INSERT INTO pieces (name, kind) VALUES ($1, $2)
+        RETURNING id AS "id: PieceId", name, kind AS "kind: PieceKind"
+        "#,
+        name,
+        kind as PieceKind
+    )
+    .fetch_one(pool)
+    .await?)
+}
+
+/// Adds a tool to the catalog.
+/// Fails with [`Error::UniqueViolation`] if the name is taken.
+pub async fn register_tool(name: &str, pool: &PgPool) -> Result<Tool> {
+    Ok(sqlx::query_as!(
+        Tool,
+        r#"INSERT INTO tools (name) VALUES ($1)
+        RETURNING id AS "id: ToolId", name
+        "#,
+        name
+    )
+    .fetch_one(pool)
+    .await?)
+}
+
+/// Adds a production line to the catalog.
+/// Fails with [`Error::UniqueViolation`] if the name is taken.
+pub async fn register_prod_line(name: &str, pool: &PgPool) -> Result<ProdLine> {
+    Ok(sqlx::query_as!(
+        ProdLine,
+        "INSERT INTO production_lines (name) VALUES ($1) RETURNING id, name",
+        name
+    )
+    .fetch_one(pool)
+    .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catalog_lookups() {
+        let catalog = Catalog::new(
+            vec![
+                Piece {
+                    id: PieceId(1),
+                    name: "P1".to_string(),
+                    kind: PieceKind::RawMaterial,
+                },
+                Piece {
+                    id: PieceId(5),
+                    name: "P5".to_string(),
+                    kind: PieceKind::FinalProduct,
+                },
+            ],
+            vec![Tool {
+                id: ToolId(1),
+                name: "T1".to_string(),
+            }],
+            vec![ProdLine {
+                id: 1,
+                name: "L1".to_string(),
+            }],
+        );
+
+        assert_eq!(catalog.piece("P5").unwrap().id, PieceId(5));
+        assert_eq!(catalog.piece_by_id(PieceId(1)).unwrap().name, "P1");
+        assert_eq!(catalog.tool("T1").unwrap().id, ToolId(1));
+        assert_eq!(
+            catalog
+                .final_products()
+                .map(|p| &p.name)
+                .collect::<Vec<_>>(),
+            ["P5"]
+        );
+
+        assert!(matches!(
+            catalog.piece("P10"),
+            Err(Error::UnknownPiece(name)) if name == "P10"
+        ));
+        assert!(matches!(
+            catalog.tool_by_id(ToolId(7)),
+            Err(Error::UnknownTool(id)) if id == "7"
+        ));
+    }
+}
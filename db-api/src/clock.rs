@@ -0,0 +1,18 @@
+use crate::Result;
+use sqlx::PgPool;
+
+/// Gets the current day of the ERP simulation.
+pub async fn get_current_day(pool: &PgPool) -> Result<i32> {
+    Ok(sqlx::query!("SELECT current_day FROM erp_clock")
+        .fetch_one(pool)
+        .await?
+        .current_day)
+}
+
+/// Sets the current day of the ERP simulation.
+pub async fn set_current_day(day: i32, pool: &PgPool) -> Result<()> {
+    sqlx::query!("UPDATE erp_clock SET current_day = $1", day)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
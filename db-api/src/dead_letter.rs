@@ -0,0 +1,102 @@
+use crate::Result;
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgPool,
+};
+
+/// A document that could not be fully ingested, kept so it can be
+/// replayed once the cause of the failure is fixed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectedMessage {
+    pub id: i64,
+    pub payload: Vec<u8>,
+    pub sender: String,
+    pub error: String,
+    pub received_at: DateTime<Utc>,
+    pub replay_attempts: i32,
+    pub replayed_at: Option<DateTime<Utc>>,
+}
+
+/// Stores the raw payload of a rejected document along with
+/// who sent it and why it was rejected.
+pub async fn store_rejected_message(
+    payload: &[u8],
+    sender: &str,
+    error: &str,
+    pool: &PgPool,
+) -> Result<i64> {
+    Ok(sqlx::query!(
+        "INSERT INTO rejected_messages (payload, sender, error)
+        VALUES ($1, $2, $3)
+        RETURNING id
+        ",
+        payload,
+        sender,
+        error
+    )
+    .fetch_one(pool)
+    .await?
+    .id)
+}
+
+pub async fn get_rejected_message(
+    id: i64,
+    pool: &PgPool,
+) -> Result<RejectedMessage> {
+    Ok(sqlx::query_as!(
+        RejectedMessage,
+        "SELECT * FROM rejected_messages WHERE id = $1",
+        id
+    )
+    .fetch_one(pool)
+    .await?)
+}
+
+/// Gets every rejected message that was not successfully replayed yet,
+/// oldest first.
+pub async fn get_pending_rejected_messages(
+    pool: &PgPool,
+) -> Result<Vec<RejectedMessage>> {
+    Ok(sqlx::query_as!(
+        RejectedMessage,
+        "SELECT * FROM rejected_messages
+        WHERE replayed_at IS NULL
+        ORDER BY id
+        "
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Marks a message as successfully replayed.
+pub async fn mark_message_replayed(id: i64, pool: &PgPool) -> Result<()> {
+    sqlx::query!(
+        "UPDATE rejected_messages
+        SET replay_attempts = replay_attempts + 1, replayed_at = NOW()
+        WHERE id = $1
+        ",
+        id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Records a replay that failed again, keeping the latest error.
+pub async fn record_failed_replay(
+    id: i64,
+    error: &str,
+    pool: &PgPool,
+) -> Result<()> {
+    sqlx::query!(
+        "UPDATE rejected_messages
+        SET replay_attempts = replay_attempts + 1, error = $2
+        WHERE id = $1
+        ",
+        id,
+        error
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,340 @@
+use crate::{
+    production::DoubleBooking, MoneyError, MoneyParseError,
+    NotificationChannel, OrderProblem, OrderStatus,
+};
+
+/// Errors returned by every fallible function of the crate.
+///
+/// Database errors that mean something to the caller, like a duplicate
+/// order number or a missing row, get their own variant so callers can
+/// react to them. Anything else is kept as it came from sqlx.
+#[derive(Debug)]
+pub enum Error {
+    /// The work piece does not exist.
+    UnknownPiece(String),
+    /// The tool does not exist.
+    UnknownTool(String),
+    /// The client already placed an order with the same number.
+    DuplicateOrder { client: String, number: i32 },
+    /// The client has no order with the given number.
+    UnknownOrder { client: String, number: i32 },
+    /// The order was cancelled and can no longer change.
+    OrderCancelled { client: String, number: i32 },
+    /// The order is too far along to make the requested change, e.g. its
+    /// production started or its quantity was already scheduled.
+    OrderLocked {
+        client: String,
+        number: i32,
+        status: OrderStatus,
+    },
+    /// The order can not go from its status to the requested one.
+    InvalidTransition {
+        order_id: i64,
+        from: OrderStatus,
+        to: OrderStatus,
+    },
+    /// The order breaks the rules checked by validation.
+    InvalidOrder(Vec<OrderProblem>),
+    /// A notification payload does not match the event of its channel.
+    InvalidEvent {
+        channel: NotificationChannel,
+        reason: String,
+    },
+    /// A money string could not be parsed.
+    InvalidMoney(MoneyParseError),
+    /// Arithmetic on amounts overflowed or mixed currencies.
+    Money(MoneyError),
+    /// The transformations make a piece out of itself, the pieces of
+    /// the cycle are listed in production order.
+    RecipeCycle(Vec<String>),
+    /// The pieces needed to make an order do not fit in an `i32`.
+    QuantityOverflow,
+    /// Runs of a production plan overlap on a line.
+    DoubleBooked(Vec<DoubleBooking>),
+    /// The requested row does not exist.
+    NotFound,
+    /// A row with the same unique key already exists.
+    UniqueViolation { constraint: Option<String> },
+    /// A row refers to a row that does not exist.
+    ForeignKeyViolation { constraint: Option<String> },
+    /// The database could not be reached, or the connection was lost.
+    Connection(sqlx::Error),
+    /// The database refused the request for a reason that goes away on
+    /// its own, like a serialization failure, a deadlock or a shutdown.
+    Transient(sqlx::Error),
+    /// Any other database error.
+    Database(sqlx::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// SQLSTATE codes of the errors that are [`Error::Transient`].
+const TRANSIENT_CODES: [&str; 5] = [
+    "40001", // serialization_failure
+    "40P01", // deadlock_detected
+    "57P01", // admin_shutdown
+    "57P03", // cannot_connect_now
+    "53300", // too_many_connections
+];
+
+impl Error {
+    /// Whether the same request may succeed if it is tried again later.
+    /// Only problems reaching the database, or conflicts with other
+    /// requests, are transient. Domain errors and other database errors,
+    /// like a failed check or a bad query, will not change on a retry.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::Connection(_) | Error::Transient(_))
+    }
+
+    /// Whether the request was refused because it breaks a business
+    /// rule, like an invalid or duplicate order, and will be refused the
+    /// same way however often it is sent. Any other error is a failure
+    /// of the system, which may be worth replaying once it is fixed.
+    pub fn is_refusal(&self) -> bool {
+        matches!(
+            self,
+            Error::InvalidOrder(_)
+                | Error::DuplicateOrder { .. }
+                | Error::UnknownOrder { .. }
+                | Error::OrderCancelled { .. }
+                | Error::OrderLocked { .. }
+                | Error::InvalidTransition { .. }
+        )
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::UnknownPiece(piece) => {
+                write!(f, "work piece {piece} does not exist")
+            }
+            Error::UnknownTool(tool) => write!(f, "tool {tool} does not exist"),
+            Error::DuplicateOrder { client, number } => {
+                write!(f, "client {client} already has an order {number}")
+            }
+            Error::UnknownOrder { client, number } => {
+                write!(f, "client {client} has no order number {number}")
+            }
+            Error::OrderCancelled { client, number } => {
+                write!(f, "order {number} of client {client} is cancelled")
+            }
+            Error::OrderLocked {
+                client,
+                number,
+                status,
+            } => write!(
+                f,
+                "order {number} of client {client} is {status} \
+                and can no longer change this way"
+            ),
+            Error::InvalidTransition { order_id, from, to } => {
+                write!(f, "order {order_id} can not go from {from} to {to}")
+            }
+            Error::InvalidOrder(problems) => {
+                let problems = problems
+                    .iter()
+                    .map(|problem| problem.to_string())
+                    .collect::<Vec<_>>();
+                write!(f, "{}", problems.join("; "))
+            }
+            Error::InvalidEvent { channel, reason } => {
+                write!(f, "invalid {channel} event: {reason}")
+            }
+            Error::InvalidMoney(e) => write!(f, "{e}"),
+            Error::Money(e) => write!(f, "{e}"),
+            Error::RecipeCycle(pieces) => write!(
+                f,
+                "transformations form a cycle: {}",
+                pieces.join(" -> ")
+            ),
+            Error::QuantityOverflow => {
+                write!(f, "too many pieces are needed for the order")
+            }
+            Error::DoubleBooked(slots) => {
+                let slots = slots
+                    .iter()
+                    .map(|slot| slot.to_string())
+                    .collect::<Vec<_>>();
+                write!(f, "double booked: {}", slots.join("; "))
+            }
+            Error::NotFound => write!(f, "row not found"),
+            Error::UniqueViolation { constraint } => match constraint {
+                Some(c) => write!(f, "unique constraint {c} violated"),
+                None => write!(f, "unique constraint violated"),
+            },
+            Error::ForeignKeyViolation { constraint } => match constraint {
+                Some(c) => write!(f, "foreign key {c} violated"),
+                None => write!(f, "foreign key violated"),
+            },
+            Error::Connection(e) => write!(f, "database connection: {e}"),
+            Error::Transient(e) | Error::Database(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::InvalidMoney(e) => Some(e),
+            Error::Money(e) => Some(e),
+            Error::Connection(e) | Error::Transient(e) | Error::Database(e) => {
+                Some(e)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            sqlx::Error::RowNotFound => return Error::NotFound,
+            sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed => return Error::Connection(e),
+            _ => {}
+        }
+
+        let Some(db) = e.as_database_error() else {
+            return Error::Database(e);
+        };
+        let constraint = db.constraint().map(str::to_string);
+        let transient = db
+            .code()
+            .is_some_and(|code| TRANSIENT_CODES.contains(&code.as_ref()));
+        if transient {
+            Error::Transient(e)
+        } else if db.is_unique_violation() {
+            Error::UniqueViolation { constraint }
+        } else if db.is_foreign_key_violation() {
+            Error::ForeignKeyViolation { constraint }
+        } else {
+            Error::Database(e)
+        }
+    }
+}
+
+impl From<sqlx::migrate::MigrateError> for Error {
+    fn from(e: sqlx::migrate::MigrateError) -> Self {
+        Error::Database(e.into())
+    }
+}
+
+impl From<MoneyParseError> for Error {
+    fn from(e: MoneyParseError) -> Self {
+        Error::InvalidMoney(e)
+    }
+}
+
+impl From<MoneyError> for Error {
+    fn from(e: MoneyError) -> Self {
+        Error::Money(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_sqlx_error() {
+        assert!(matches!(
+            Error::from(sqlx::Error::RowNotFound),
+            Error::NotFound
+        ));
+        assert!(matches!(
+            Error::from(sqlx::Error::PoolTimedOut),
+            Error::Connection(_)
+        ));
+        assert!(Error::from(sqlx::Error::PoolClosed).is_transient());
+        assert!(!Error::NotFound.is_transient());
+    }
+
+    #[derive(Debug)]
+    struct FakeDbError(&'static str);
+
+    impl std::fmt::Display for FakeDbError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "error {}", self.0)
+        }
+    }
+
+    impl std::error::Error for FakeDbError {}
+
+    impl sqlx::error::DatabaseError for FakeDbError {
+        fn message(&self) -> &str {
+            self.0
+        }
+
+        fn code(&self) -> Option<std::borrow::Cow<'_, str>> {
+            Some(self.0.into())
+        }
+
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(
+            &mut self,
+        ) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(
+            self: Box<Self>,
+        ) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> sqlx::error::ErrorKind {
+            match self.0 {
+                "23514" => sqlx::error::ErrorKind::CheckViolation,
+                _ => sqlx::error::ErrorKind::Other,
+            }
+        }
+    }
+
+    #[test]
+    fn test_transient_database_errors() {
+        let error = |code| {
+            Error::from(sqlx::Error::Database(Box::new(FakeDbError(code))))
+        };
+
+        for code in TRANSIENT_CODES {
+            assert!(matches!(error(code), Error::Transient(_)), "{code}");
+            assert!(error(code).is_transient());
+        }
+
+        // Check violations and other errors fail the same way every time.
+        assert!(matches!(error("23514"), Error::Database(_)));
+        assert!(!error("23514").is_transient());
+        assert!(!error("42601").is_transient());
+        assert!(!Error::Database(sqlx::Error::ColumnNotFound("x".into()))
+            .is_transient());
+    }
+
+    #[test]
+    fn test_refusals() {
+        let duplicate = Error::DuplicateOrder {
+            client: "c".to_string(),
+            number: 1,
+        };
+        assert!(duplicate.is_refusal());
+        assert!(Error::InvalidOrder(Vec::new()).is_refusal());
+
+        // Failures of the system are not, whether they are transient or
+        // fail the same way until they are fixed.
+        assert!(!Error::from(sqlx::Error::PoolTimedOut).is_refusal());
+        assert!(!Error::Database(sqlx::Error::ColumnNotFound("x".into()))
+            .is_refusal());
+        let unique = Error::UniqueViolation {
+            constraint: Some("bom_order_id_key".to_string()),
+        };
+        assert!(!unique.is_refusal());
+        assert!(!Error::ForeignKeyViolation { constraint: None }.is_refusal());
+        assert!(!Error::UnknownPiece("P0".to_string()).is_refusal());
+    }
+}
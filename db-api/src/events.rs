@@ -0,0 +1,262 @@
+//! Typed payloads of the notification channels.
+//!
+//! Every channel carries one [`Event`] type, sent as JSON along with the
+//! version of its schema:
+//!
+//! ```json
+//! {"version":1,"data":{"order_id":42}}
+//! ```
+//!
+//! Events are sent with [`publish`] and received with a [`Subscriber`].
+
+use crate::{Error, NotificationChannel, OrderStatus, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+
+/// Largest payload Postgres accepts in a `NOTIFY`, in bytes.
+pub const MAX_NOTIFY_PAYLOAD: usize = 7999;
+
+/// Payload of a notification channel.
+pub trait Event: Serialize + DeserializeOwned {
+    const CHANNEL: NotificationChannel;
+    /// Version of the payload schema. Must change whenever the payload
+    /// changes in a way readers of the previous version would misread.
+    const VERSION: u32;
+}
+
+/// A client order was placed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NewOrder {
+    pub order_id: i64,
+}
+
+impl Event for NewOrder {
+    const CHANNEL: NotificationChannel = NotificationChannel::NewOrder;
+    const VERSION: u32 = 1;
+}
+
+/// BOM entries were generated for an order. They can be read with
+/// [`Bom::get_by_order`](crate::Bom::get_by_order).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NewBomEntry {
+    pub order_id: i64,
+}
+
+impl Event for NewBomEntry {
+    const CHANNEL: NotificationChannel = NotificationChannel::NewBomEntry;
+    const VERSION: u32 = 1;
+}
+
+/// A client cancelled an order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderCancelled {
+    pub order_id: i64,
+}
+
+impl Event for OrderCancelled {
+    const CHANNEL: NotificationChannel = NotificationChannel::OrderCancelled;
+    const VERSION: u32 = 1;
+}
+
+/// A client amended an order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderAmended {
+    pub order_id: i64,
+}
+
+impl Event for OrderAmended {
+    const CHANNEL: NotificationChannel = NotificationChannel::OrderAmended;
+    const VERSION: u32 = 1;
+}
+
+/// An order moved to a new status.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderStatusChanged {
+    pub order_id: i64,
+    pub from: OrderStatus,
+    pub to: OrderStatus,
+}
+
+impl Event for OrderStatusChanged {
+    const CHANNEL: NotificationChannel =
+        NotificationChannel::OrderStatusChanged;
+    const VERSION: u32 = 1;
+}
+
+/// An event of any channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnyEvent {
+    NewOrder(NewOrder),
+    NewBomEntry(NewBomEntry),
+    OrderCancelled(OrderCancelled),
+    OrderAmended(OrderAmended),
+    OrderStatusChanged(OrderStatusChanged),
+}
+
+impl AnyEvent {
+    /// Decodes the payload of a notification sent to `channel`.
+    pub fn decode(channel: NotificationChannel, payload: &str) -> Result<Self> {
+        use NotificationChannel as Nc;
+        Ok(match channel {
+            Nc::NewOrder => AnyEvent::NewOrder(decode(payload)?),
+            Nc::NewBomEntry => AnyEvent::NewBomEntry(decode(payload)?),
+            Nc::OrderCancelled => AnyEvent::OrderCancelled(decode(payload)?),
+            Nc::OrderAmended => AnyEvent::OrderAmended(decode(payload)?),
+            Nc::OrderStatusChanged => {
+                AnyEvent::OrderStatusChanged(decode(payload)?)
+            }
+            Nc::Unknown => {
+                return Err(Error::InvalidEvent {
+                    channel,
+                    reason: "unknown channel".to_string(),
+                })
+            }
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, E> {
+    version: u32,
+    data: &'a E,
+}
+
+#[derive(Deserialize)]
+struct Version {
+    version: u32,
+}
+
+#[derive(Deserialize)]
+struct Data<E> {
+    data: E,
+}
+
+/// Encodes an event as the payload of a notification.
+pub fn encode<E: Event>(event: &E) -> String {
+    let envelope = Envelope {
+        version: E::VERSION,
+        data: event,
+    };
+    serde_json::to_string(&envelope).expect("events are valid JSON")
+}
+
+/// Decodes the payload of a notification.
+/// Fails with [`Error::InvalidEvent`] if it is not valid JSON, does not
+/// match the event, or was written for another version of it.
+pub fn decode<E: Event>(payload: &str) -> Result<E> {
+    let invalid = |reason: String| Error::InvalidEvent {
+        channel: E::CHANNEL,
+        reason,
+    };
+
+    let Version { version } =
+        serde_json::from_str(payload).map_err(|e| invalid(e.to_string()))?;
+    if version != E::VERSION {
+        return Err(invalid(format!(
+            "version {version} is not supported, expected {}",
+            E::VERSION
+        )));
+    }
+
+    let Data { data } =
+        serde_json::from_str(payload).map_err(|e| invalid(e.to_string()))?;
+    Ok(data)
+}
+
+/// Sends an event to its channel. Like any notification, it is only
+/// delivered if and when the transaction commits.
+pub async fn publish<E: Event>(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    event: &E,
+) -> Result<()> {
+    let payload = encode(event);
+    if payload.len() > MAX_NOTIFY_PAYLOAD {
+        return Err(Error::InvalidEvent {
+            channel: E::CHANNEL,
+            reason: format!("payload of {} bytes is too large", payload.len()),
+        });
+    }
+
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(E::CHANNEL.to_string())
+        .bind(payload)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+/// Receives the events of the channels it subscribed to.
+pub struct Subscriber {
+    listener: PgListener,
+}
+
+impl Subscriber {
+    pub async fn connect(url: &str) -> Result<Self> {
+        Ok(Self {
+            listener: PgListener::connect(url).await?,
+        })
+    }
+
+    /// Starts receiving the events of type `E`.
+    pub async fn subscribe<E: Event>(&mut self) -> Result<()> {
+        self.listener.listen(&E::CHANNEL.to_string()).await?;
+        Ok(())
+    }
+
+    /// Waits for the next event.
+    /// An event that can not be decoded is returned as an error, and the
+    /// subscriber can keep receiving after it.
+    pub async fn recv(&mut self) -> Result<AnyEvent> {
+        let notification = self.listener.recv().await?;
+        let channel = NotificationChannel::from(notification.channel());
+        AnyEvent::decode(channel, notification.payload())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_round_trip() {
+        let event = OrderStatusChanged {
+            order_id: 42,
+            from: OrderStatus::Received,
+            to: OrderStatus::BomResolved,
+        };
+        let payload = encode(&event);
+        assert_eq!(
+            payload,
+            r#"{"version":1,"data":{"order_id":42,"from":"received","to":"bom_resolved"}}"#
+        );
+
+        let channel = NotificationChannel::OrderStatusChanged;
+        assert_eq!(
+            AnyEvent::decode(channel, &payload).unwrap(),
+            AnyEvent::OrderStatusChanged(event)
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let cases = [
+            "42",
+            r#"{"version":2,"data":{"order_id":42}}"#,
+            r#"{"version":1,"data":{"id":42}}"#,
+        ];
+        for payload in cases {
+            assert!(matches!(
+                decode::<NewOrder>(payload),
+                Err(Error::InvalidEvent {
+                    channel: NotificationChannel::NewOrder,
+                    ..
+                })
+            ));
+        }
+        assert_eq!(
+            decode::<NewOrder>(r#"{"version":1,"data":{"order_id":42}}"#)
+                .unwrap(),
+            NewOrder { order_id: 42 }
+        );
+    }
+}
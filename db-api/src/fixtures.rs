@@ -0,0 +1,62 @@
+use crate::Result;
+
+/// An order added by [`tx_insert_test_order`], for tests and benchmarks
+/// that need rows to refer to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestOrder {
+    pub order_id: i64,
+    /// Ids of the first transformations, one for each step asked for.
+    pub transformation_ids: Vec<i64>,
+}
+
+/// Adds a client named `client` and an order of `quantity` P5 pieces
+/// for it. Meant to be used in a transaction that is rolled back.
+pub async fn tx_insert_test_order(
+    client: &str,
+    quantity: i32,
+    steps: i64,
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<TestOrder> {
+    let client_id = sqlx::query!(
+        "INSERT INTO clients(name) VALUES($1) RETURNING id",
+        client
+    )
+    .fetch_one(&mut **tx)
+    .await?
+    .id;
+    let order_id = sqlx::query!(
+        "INSERT INTO orders(piece_id, client_id, number, quantity, due_date)
+        SELECT id, $1, 1, $2, 10 FROM pieces WHERE name = 'P5'
+        RETURNING id
+        ",
+        client_id,
+        quantity
+    )
+    .fetch_one(&mut **tx)
+    .await?
+    .id;
+    let transformation_ids = sqlx::query!(
+        "SELECT id FROM transformations ORDER BY id LIMIT $1",
+        steps
+    )
+    .fetch_all(&mut **tx)
+    .await?
+    .into_iter()
+    .map(|row| row.id)
+    .collect();
+
+    Ok(TestOrder {
+        order_id,
+        transformation_ids,
+    })
+}
+
+/// Connects to the database of `DATABASE_URL`, which needs the
+/// migrations applied. Tests that need it are ignored by default, run
+/// them with `cargo test -- --ignored`.
+#[cfg(test)]
+pub(crate) async fn test_pool() -> Result<sqlx::PgPool> {
+    dotenv::dotenv().ok();
+    let url = std::env::var("DATABASE_URL").expect("DATABASE_URL is set");
+    Ok(sqlx::PgPool::connect(&url).await?)
+}
@@ -0,0 +1,278 @@
+use crate::{
+    events::{self, AnyEvent, Event},
+    Error, NotificationChannel, Result,
+};
+use sqlx::PgPool;
+
+/// State of a job in the `jobs` table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "job_status", rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+/// A claimed job. Must be finished with [`complete_job`] or [`fail_job`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    pub id: i64,
+    pub channel: NotificationChannel,
+    pub payload: String,
+    /// Number of times the job was claimed, including this one.
+    pub attempts: i32,
+}
+
+impl Job {
+    /// Decodes the event the job was queued with.
+    pub fn event(&self) -> Result<AnyEvent> {
+        AnyEvent::decode(self.channel, &self.payload)
+    }
+}
+
+/// Adds a job to the queue, as part of the transaction that caused it.
+/// The event is published as well, so listeners know there is work to
+/// do without polling. Notifications are lost when nobody is listening,
+/// the job is not.
+pub async fn tx_enqueue_job<E: Event>(
+    event: &E,
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<i64> {
+    let id = sqlx::query!(
+        "INSERT INTO jobs (channel, payload) VALUES ($1, $2) RETURNING id",
+        E::CHANNEL.to_string(),
+        events::encode(event)
+    )
+    .fetch_one(&mut **tx)
+    .await?
+    .id;
+
+    events::publish(tx, event).await?;
+
+    Ok(id)
+}
+
+/// Claims the oldest job that is ready to run, if there is one.
+///
+/// Rows locked by other workers are skipped, so several workers can
+/// share the queue. A claimed job is leased for 5 minutes: if it is not
+/// completed or failed by then, its worker is assumed dead and the job
+/// can be claimed again. A job whose lease ran out on its last attempt
+/// is marked as failed instead, so a job that crashes or hangs its
+/// worker is not retried forever.
+pub async fn claim_job(pool: &PgPool) -> Result<Option<Job>> {
+    let mut tx = pool.begin().await?;
+    let job = tx_claim_job(&mut tx).await?;
+    tx.commit().await?;
+    Ok(job)
+}
+
+/// Same as [`claim_job`]. Use with a transaction type connection
+pub async fn tx_claim_job(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<Option<Job>> {
+    loop {
+        let Some(job) = sqlx::query!(
+            r#"WITH next AS (
+                SELECT
+                    id,
+                    status = 'running' AND attempts >= max_attempts
+                        AS expired
+                FROM jobs
+                WHERE status IN ('pending', 'running') AND run_at <= NOW()
+                ORDER BY id
+                FOR UPDATE SKIP LOCKED
+                LIMIT 1
+            )
+            UPDATE jobs SET
+                status = CASE
+                    WHEN next.expired THEN 'failed'::job_status
+                    ELSE 'running'::job_status
+                END,
+                attempts = attempts + (NOT next.expired)::INT,
+                run_at = NOW() + INTERVAL '5 minutes',
+                last_error = CASE
+                    WHEN next.expired THEN 'lease ran out'
+                    ELSE last_error
+                END
+            FROM next
+            WHERE jobs.id = next.id
+            RETURNING
+                jobs.id,
+                channel,
+                payload,
+                attempts,
+                status AS "status: JobStatus"
+            "#
+        )
+        .fetch_optional(&mut **tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        if job.status == JobStatus::Failed {
+            tracing::error!(
+                "Giving up on {} job {} after {} attempts: lease ran out",
+                job.channel,
+                job.payload,
+                job.attempts
+            );
+            continue;
+        }
+
+        return Ok(Some(Job {
+            id: job.id,
+            channel: NotificationChannel::from(job.channel.as_str()),
+            payload: job.payload,
+            attempts: job.attempts,
+        }));
+    }
+}
+
+/// Marks a job as done.
+/// Fails with [`Error::NotFound`] if the lease of the job ran out and it
+/// was claimed again since, the result is then left to the new claim.
+pub async fn complete_job(job: &Job, pool: &PgPool) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    tx_complete_job(job, &mut tx).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Same as [`complete_job`]. Use with a transaction type connection
+pub async fn tx_complete_job(
+    job: &Job,
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<()> {
+    let done = sqlx::query!(
+        "UPDATE jobs SET status = 'done'
+        WHERE id = $1 AND status = 'running' AND attempts = $2
+        ",
+        job.id,
+        job.attempts
+    )
+    .execute(&mut **tx)
+    .await?
+    .rows_affected();
+    if done == 0 {
+        return Err(Error::NotFound);
+    }
+    Ok(())
+}
+
+/// Records why a job failed.
+/// The job is retried after 2^attempts seconds, or marked as failed for
+/// good once it used all of its attempts. Returns the new status.
+/// Fails with [`Error::NotFound`] if the job was claimed again since, as
+/// [`complete_job`] does.
+pub async fn fail_job(
+    job: &Job,
+    error: &str,
+    pool: &PgPool,
+) -> Result<JobStatus> {
+    let mut tx = pool.begin().await?;
+    let status = tx_fail_job(job, error, &mut tx).await?;
+    tx.commit().await?;
+    Ok(status)
+}
+
+/// Same as [`fail_job`]. Use with a transaction type connection
+pub async fn tx_fail_job(
+    job: &Job,
+    error: &str,
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<JobStatus> {
+    Ok(sqlx::query!(
+        r#"UPDATE jobs SET
+            status = CASE
+                WHEN attempts >= max_attempts THEN 'failed'::job_status
+                ELSE 'pending'::job_status
+            END,
+            run_at = NOW() + make_interval(secs => power(2, attempts)),
+            last_error = $3
+        WHERE id = $1 AND status = 'running' AND attempts = $2
+        RETURNING status AS "status: JobStatus"
+        "#,
+        job.id,
+        job.attempts,
+        error
+    )
+    .fetch_one(&mut **tx)
+    .await?
+    .status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::NewOrder;
+
+    /// Adds a job that was claimed `attempts` times, the last time by a
+    /// worker whose lease already ran out.
+    async fn insert_expired(
+        attempts: i32,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<i64> {
+        Ok(sqlx::query!(
+            "INSERT INTO jobs (channel, payload, status, attempts, run_at)
+            VALUES ('new_order', $1, 'running', $2, NOW() - INTERVAL '1s')
+            RETURNING id
+            ",
+            events::encode(&NewOrder { order_id: 1 }),
+            attempts
+        )
+        .fetch_one(&mut **tx)
+        .await?
+        .id)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_expired_leases() -> Result<()> {
+        let pool = crate::test_pool().await?;
+        let mut tx = pool.begin().await?;
+        // Keep the jobs already in the database out of the way.
+        sqlx::query!("UPDATE jobs SET status = 'done'")
+            .execute(&mut *tx)
+            .await?;
+
+        // Out of attempts, the job is failed instead of claimed.
+        let poison = insert_expired(5, &mut tx).await?;
+        let stale = Job {
+            id: insert_expired(1, &mut tx).await?,
+            channel: NotificationChannel::NewOrder,
+            payload: events::encode(&NewOrder { order_id: 1 }),
+            attempts: 1,
+        };
+        let job = tx_claim_job(&mut tx).await?;
+        assert_eq!(job.as_ref().map(|job| job.id), Some(stale.id));
+        assert!(tx_claim_job(&mut tx).await?.is_none());
+
+        let status = sqlx::query!(
+            r#"SELECT status AS "status: JobStatus" FROM jobs WHERE id = $1"#,
+            poison
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .status;
+        assert_eq!(status, JobStatus::Failed);
+
+        // The worker that lost the job can not finish it.
+        assert!(matches!(
+            tx_complete_job(&stale, &mut tx).await,
+            Err(Error::NotFound)
+        ));
+        assert!(matches!(
+            tx_fail_job(&stale, "too late", &mut tx).await,
+            Err(Error::NotFound)
+        ));
+        let job = job.expect("the job was claimed");
+        assert_eq!(job.attempts, 2);
+        tx_complete_job(&job, &mut tx).await?;
+
+        tx.rollback().await?;
+        Ok(())
+    }
+}
@@ -1,23 +1,60 @@
 // MODULES
 mod bom;
+mod catalog;
+mod clock;
+mod dead_letter;
+mod error;
+pub mod events;
+mod fixtures;
+mod jobs;
+mod money;
+mod order_changes;
+mod order_status;
 mod orders;
 pub mod production;
+mod signing;
+mod storage;
+mod validation;
 
 // RE-EXPORTS
 pub use bom::*;
+pub use catalog::*;
+pub use clock::*;
+pub use dead_letter::*;
+pub use error::*;
+pub use fixtures::*;
+pub use jobs::*;
+pub use money::*;
+pub use order_changes::*;
+pub use order_status::*;
 pub use orders::*;
+pub use signing::*;
+pub use storage::*;
+pub use validation::*;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NotificationChannel {
     NewOrder,
     NewBomEntry,
+    OrderCancelled,
+    OrderAmended,
+    OrderStatusChanged,
     Unknown,
 }
 
 impl NotificationChannel {
     const NEW_ORDER_CHANNEL: &'static str = "new_order";
     const NEW_BOM_ENTRY_CHANNEL: &'static str = "new_bom_entry";
-    pub const ALL_STR: [&'static str; 2] =
-        [Self::NEW_ORDER_CHANNEL, Self::NEW_BOM_ENTRY_CHANNEL];
+    const ORDER_CANCELLED_CHANNEL: &'static str = "order_cancelled";
+    const ORDER_AMENDED_CHANNEL: &'static str = "order_amended";
+    const ORDER_STATUS_CHANGED_CHANNEL: &'static str = "order_status_changed";
+    pub const ALL_STR: [&'static str; 5] = [
+        Self::NEW_ORDER_CHANNEL,
+        Self::NEW_BOM_ENTRY_CHANNEL,
+        Self::ORDER_CANCELLED_CHANNEL,
+        Self::ORDER_AMENDED_CHANNEL,
+        Self::ORDER_STATUS_CHANGED_CHANNEL,
+    ];
 }
 
 impl std::fmt::Display for NotificationChannel {
@@ -26,6 +63,9 @@ impl std::fmt::Display for NotificationChannel {
         match self {
             Nc::NewOrder => write!(f, "new_order"),
             Nc::NewBomEntry => write!(f, "new_bom_entry"),
+            Nc::OrderCancelled => write!(f, "order_cancelled"),
+            Nc::OrderAmended => write!(f, "order_amended"),
+            Nc::OrderStatusChanged => write!(f, "order_status_changed"),
             Nc::Unknown => write!(f, "unknown"),
         }
     }
@@ -37,6 +77,9 @@ impl From<&str> for NotificationChannel {
         match s {
             Nc::NEW_ORDER_CHANNEL => Nc::NewOrder,
             Nc::NEW_BOM_ENTRY_CHANNEL => Nc::NewBomEntry,
+            Nc::ORDER_CANCELLED_CHANNEL => Nc::OrderCancelled,
+            Nc::ORDER_AMENDED_CHANNEL => Nc::OrderAmended,
+            Nc::ORDER_STATUS_CHANGED_CHANNEL => Nc::OrderStatusChanged,
             _ => Nc::Unknown,
         }
     }
@@ -0,0 +1,595 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    postgres::{types::PgMoney, PgArgumentBuffer, PgTypeInfo, PgValueRef},
+    Decode, Encode, Postgres,
+};
+
+/// Currencies the ERP knows about.
+/// Amounts without an explicit currency are taken to be in euros,
+/// the currency the plant keeps its books in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Currency {
+    #[default]
+    Eur,
+    Usd,
+    Gbp,
+}
+
+impl Currency {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Currency::Eur => "EUR",
+            Currency::Usd => "USD",
+            Currency::Gbp => "GBP",
+        }
+    }
+
+    pub fn symbol(&self) -> char {
+        match self {
+            Currency::Eur => '€',
+            Currency::Usd => '$',
+            Currency::Gbp => '£',
+        }
+    }
+
+    fn from_symbol(symbol: char) -> Option<Currency> {
+        match symbol {
+            '€' => Some(Currency::Eur),
+            '$' => Some(Currency::Usd),
+            '£' => Some(Currency::Gbp),
+            _ => None,
+        }
+    }
+
+    fn from_code(code: &str) -> Option<Currency> {
+        match code.to_ascii_uppercase().as_str() {
+            "EUR" => Some(Currency::Eur),
+            "USD" => Some(Currency::Usd),
+            "GBP" => Some(Currency::Gbp),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Currency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+/// An amount of money in cents of the given currency.
+///
+/// Amounts of different currencies never mix: arithmetic between them
+/// fails, and they are not ordered against each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Money {
+    pub cents: i64,
+    pub currency: Currency,
+}
+
+impl Money {
+    pub const fn new(cents: i64, currency: Currency) -> Money {
+        Money { cents, currency }
+    }
+
+    /// An amount in the currency of the books, see [`Currency::default`].
+    pub const fn from_cents(cents: i64) -> Money {
+        Money::new(cents, Currency::Eur)
+    }
+
+    pub const fn zero(currency: Currency) -> Money {
+        Money::new(0, currency)
+    }
+
+    pub fn checked_add(self, other: Money) -> Result<Money, MoneyError> {
+        let currency = self.same_currency(other)?;
+        let cents = self.cents.checked_add(other.cents);
+        Ok(Money::new(cents.ok_or(MoneyError::Overflow)?, currency))
+    }
+
+    pub fn checked_sub(self, other: Money) -> Result<Money, MoneyError> {
+        let currency = self.same_currency(other)?;
+        let cents = self.cents.checked_sub(other.cents);
+        Ok(Money::new(cents.ok_or(MoneyError::Overflow)?, currency))
+    }
+
+    /// The amount of `quantity` units costing `self` each.
+    pub fn checked_mul(self, quantity: i64) -> Result<Money, MoneyError> {
+        let cents = self.cents.checked_mul(quantity);
+        Ok(Money::new(
+            cents.ok_or(MoneyError::Overflow)?,
+            self.currency,
+        ))
+    }
+
+    /// Adds up amounts of `currency`, zero if there are none.
+    pub fn checked_sum(
+        amounts: impl IntoIterator<Item = Money>,
+        currency: Currency,
+    ) -> Result<Money, MoneyError> {
+        amounts
+            .into_iter()
+            .try_fold(Money::zero(currency), Money::checked_add)
+    }
+
+    /// Fails unless the amount is in the currency of the books, the only
+    /// one the database stores.
+    pub fn in_books(self) -> Result<BooksMoney, MoneyError> {
+        self.same_currency(Money::from_cents(0))?;
+        Ok(BooksMoney(self))
+    }
+
+    fn same_currency(self, other: Money) -> Result<Currency, MoneyError> {
+        if self.currency != other.currency {
+            return Err(MoneyError::CurrencyMismatch {
+                left: self.currency,
+                right: other.currency,
+            });
+        }
+        Ok(self.currency)
+    }
+}
+
+impl PartialOrd for Money {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        self.same_currency(*other).ok()?;
+        Some(self.cents.cmp(&other.cents))
+    }
+}
+
+/// Formats the amount like the client orders do, e.g. `€1234,50` or
+/// `-$0,05`, which [`parse_money`] reads back.
+impl std::fmt::Display for Money {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.cents < 0 { "-" } else { "" };
+        let cents = self.cents.unsigned_abs();
+        write!(
+            f,
+            "{sign}{}{},{:02}",
+            self.currency.symbol(),
+            cents / 100,
+            cents % 100
+        )
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        parse_money(&text).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Stored as `MONEY`, which has no currency. Amounts read from the
+/// database are in the currency of the books. Only [`BooksMoney`] can
+/// be written.
+impl sqlx::Type<Postgres> for Money {
+    fn type_info() -> PgTypeInfo {
+        <PgMoney as sqlx::Type<Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <PgMoney as sqlx::Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for Money {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        let PgMoney(cents) = PgMoney::decode(value)?;
+        Ok(Money::from_cents(cents))
+    }
+}
+
+/// An amount in the currency of the books, made with
+/// [`Money::in_books`]. The only kind of amount written to the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BooksMoney(Money);
+
+impl From<BooksMoney> for Money {
+    fn from(BooksMoney(money): BooksMoney) -> Money {
+        money
+    }
+}
+
+impl sqlx::Type<Postgres> for BooksMoney {
+    fn type_info() -> PgTypeInfo {
+        <Money as sqlx::Type<Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <Money as sqlx::Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl Encode<'_, Postgres> for BooksMoney {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+        PgMoney(self.0.cents).encode_by_ref(buf)
+    }
+}
+
+/// Arithmetic on [`Money`] that has no valid result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoneyError {
+    /// The amounts are in different currencies.
+    CurrencyMismatch { left: Currency, right: Currency },
+    /// The result does not fit in 64 bits worth of cents.
+    Overflow,
+}
+
+impl std::fmt::Display for MoneyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MoneyError::CurrencyMismatch { left, right } => {
+                write!(f, "can not mix {left} and {right} amounts")
+            }
+            MoneyError::Overflow => write!(f, "amount is too large"),
+        }
+    }
+}
+
+impl std::error::Error for MoneyError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MoneyParseError {
+    /// There are no digits in the input.
+    MissingAmount,
+    /// A currency symbol or code that is not supported.
+    UnknownCurrency(String),
+    /// The input names a currency more than once.
+    MultipleCurrencies,
+    /// The amount contains characters that are not digits or separators,
+    /// or its separators are not in valid positions.
+    InvalidAmount(String),
+    /// More than two decimal places were given.
+    TooManyDecimals(String),
+    /// The amount does not fit in 64 bits worth of cents.
+    Overflow,
+}
+
+impl std::fmt::Display for MoneyParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use MoneyParseError as Mpe;
+        match self {
+            Mpe::MissingAmount => write!(f, "no amount given"),
+            Mpe::UnknownCurrency(c) => write!(f, "unknown currency \"{c}\""),
+            Mpe::MultipleCurrencies => write!(f, "more than one currency"),
+            Mpe::InvalidAmount(a) => write!(f, "invalid amount \"{a}\""),
+            Mpe::TooManyDecimals(a) => {
+                write!(f, "\"{a}\" has more than two decimal places")
+            }
+            Mpe::Overflow => write!(f, "amount is too large"),
+        }
+    }
+}
+
+impl std::error::Error for MoneyParseError {}
+
+impl std::str::FromStr for Money {
+    type Err = MoneyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_money(s)
+    }
+}
+
+/// Parse a money string into an amount and currency.
+///
+/// The currency may be given as a symbol (`€`, `$`, `£`) or an ISO code
+/// (`EUR`, `USD`, `GBP`), before or after the number, with or without a
+/// space. Without a currency the amount is taken to be in euros.
+///
+/// Both decimal-point and decimal-comma notation are accepted. When both
+/// `.` and `,` appear, the last one is the decimal separator. When only
+/// one of them appears, it is a thousands separator if it is repeated or
+/// followed by exactly three digits, and the decimal separator otherwise.
+/// Spaces and apostrophes may also group thousands.
+///
+/// # Examples:
+/// ```
+/// use db_api::{parse_money, Currency, Money};
+/// assert_eq!(parse_money("$123.45"), Ok(Money::new(12345, Currency::Usd)));
+/// assert_eq!(parse_money("123.45€"), Ok(Money::new(12345, Currency::Eur)));
+/// assert_eq!(parse_money("€5"), Ok(Money::new(500, Currency::Eur)));
+/// assert_eq!(parse_money("1.234,50 EUR"), Ok(Money::new(123450, Currency::Eur)));
+/// ```
+pub fn parse_money(input: &str) -> Result<Money, MoneyParseError> {
+    let mut rest = input.trim();
+    let mut currency = None;
+    let mut negative = false;
+
+    // The sign and the currency may come in either order before the number.
+    loop {
+        if let Some(stripped) = rest.strip_prefix('-') {
+            if negative {
+                return Err(MoneyParseError::InvalidAmount(input.to_string()));
+            }
+            negative = true;
+            rest = stripped.trim_start();
+        } else if let Some((found, stripped)) = strip_currency_prefix(rest)? {
+            set_currency(&mut currency, found)?;
+            rest = stripped.trim_start();
+        } else {
+            break;
+        }
+    }
+
+    if let Some((found, stripped)) = strip_currency_suffix(rest)? {
+        set_currency(&mut currency, found)?;
+        rest = stripped.trim_end();
+    }
+
+    let cents = parse_amount(rest)?;
+    let cents = if negative { -cents } else { cents };
+
+    Ok(Money::new(cents, currency.unwrap_or_default()))
+}
+
+fn set_currency(
+    currency: &mut Option<Currency>,
+    found: Currency,
+) -> Result<(), MoneyParseError> {
+    if currency.replace(found).is_some() {
+        return Err(MoneyParseError::MultipleCurrencies);
+    }
+    Ok(())
+}
+
+fn strip_currency_prefix(
+    s: &str,
+) -> Result<Option<(Currency, &str)>, MoneyParseError> {
+    let Some(first) = s.chars().next() else {
+        return Ok(None);
+    };
+    if let Some(currency) = Currency::from_symbol(first) {
+        return Ok(Some((currency, &s[first.len_utf8()..])));
+    }
+
+    let letters = s.find(|c: char| !c.is_alphabetic()).unwrap_or(s.len());
+    if letters == 0 {
+        return Ok(None);
+    }
+    match Currency::from_code(&s[..letters]) {
+        Some(currency) => Ok(Some((currency, &s[letters..]))),
+        None => Err(MoneyParseError::UnknownCurrency(s[..letters].to_string())),
+    }
+}
+
+fn strip_currency_suffix(
+    s: &str,
+) -> Result<Option<(Currency, &str)>, MoneyParseError> {
+    let Some(last) = s.chars().next_back() else {
+        return Ok(None);
+    };
+    if let Some(currency) = Currency::from_symbol(last) {
+        return Ok(Some((currency, &s[..s.len() - last.len_utf8()])));
+    }
+
+    let start = s
+        .rfind(|c: char| !c.is_alphabetic())
+        .map(|i| i + s[i..].chars().next().map_or(1, char::len_utf8))
+        .unwrap_or(0);
+    if start == s.len() {
+        return Ok(None);
+    }
+    match Currency::from_code(&s[start..]) {
+        Some(currency) => Ok(Some((currency, &s[..start]))),
+        None => Err(MoneyParseError::UnknownCurrency(s[start..].to_string())),
+    }
+}
+
+/// Parses the numeric part of a money string into cents.
+fn parse_amount(amount: &str) -> Result<i64, MoneyParseError> {
+    let invalid = || MoneyParseError::InvalidAmount(amount.to_string());
+
+    if !amount.chars().any(|c| c.is_ascii_digit()) {
+        return Err(MoneyParseError::MissingAmount);
+    }
+    if amount
+        .chars()
+        .any(|c| !c.is_ascii_digit() && !is_separator(c))
+    {
+        return Err(invalid());
+    }
+
+    let decimal_separator = decimal_separator(amount);
+    let (integer, fraction) = match decimal_separator {
+        Some(separator) => {
+            let (integer, fraction) = amount.rsplit_once(separator).unwrap();
+            (integer, fraction)
+        }
+        None => (amount, ""),
+    };
+
+    if !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    if fraction.len() > 2 {
+        return Err(MoneyParseError::TooManyDecimals(amount.to_string()));
+    }
+
+    // Every group after the first must have exactly three digits.
+    let groups = integer.split(is_separator).collect::<Vec<_>>();
+    let grouped = groups.len() > 1;
+    for (index, group) in groups.iter().enumerate() {
+        let valid = match (index, grouped) {
+            (_, false) => !group.is_empty() || decimal_separator.is_some(),
+            (0, true) => (1..=3).contains(&group.len()),
+            (_, true) => group.len() == 3,
+        };
+        if !valid {
+            return Err(invalid());
+        }
+    }
+
+    let units = groups.concat();
+    let units: i64 = if units.is_empty() {
+        0
+    } else {
+        units.parse().map_err(|_| MoneyParseError::Overflow)?
+    };
+    let cents: i64 =
+        format!("{fraction:0<2}").parse().map_err(|_| invalid())?;
+
+    units
+        .checked_mul(100)
+        .and_then(|units| units.checked_add(cents))
+        .ok_or(MoneyParseError::Overflow)
+}
+
+fn is_separator(c: char) -> bool {
+    matches!(c, '.' | ',' | ' ' | '\'' | '\u{a0}' | '\u{202f}')
+}
+
+/// Finds which of `.` and `,`, if any, separates the decimal places.
+fn decimal_separator(amount: &str) -> Option<char> {
+    let last_dot = amount.rfind('.');
+    let last_comma = amount.rfind(',');
+
+    let (separator, position) = match (last_dot, last_comma) {
+        (None, None) => return None,
+        (Some(dot), Some(comma)) => {
+            return Some(if dot > comma { '.' } else { ',' });
+        }
+        (Some(dot), None) => ('.', dot),
+        (None, Some(comma)) => (',', comma),
+    };
+
+    let repeated = amount.matches(separator).count() > 1;
+    let digits_after = amount.len() - position - 1;
+    if repeated || digits_after == 3 {
+        None
+    } else {
+        Some(separator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eur(cents: i64) -> Result<Money, MoneyParseError> {
+        Ok(Money::new(cents, Currency::Eur))
+    }
+
+    #[test]
+    fn test_currency_positions() {
+        assert_eq!(
+            parse_money("$123.45"),
+            Ok(Money::new(12345, Currency::Usd))
+        );
+        assert_eq!(parse_money("123.45€"), eur(12345));
+        assert_eq!(parse_money("€5,74"), eur(574));
+        assert_eq!(parse_money("EUR 5,74"), eur(574));
+        assert_eq!(parse_money("5.74 gbp"), Ok(Money::new(574, Currency::Gbp)));
+        assert_eq!(parse_money("-€5"), eur(-500));
+        assert_eq!(parse_money("€ -5"), eur(-500));
+        assert_eq!(parse_money("5.7"), eur(570));
+    }
+
+    #[test]
+    fn test_separators() {
+        assert_eq!(parse_money("€5"), eur(500));
+        assert_eq!(parse_money("1.234,50"), eur(123450));
+        assert_eq!(parse_money("1,234.50"), eur(123450));
+        assert_eq!(parse_money("1.234"), eur(123400));
+        assert_eq!(parse_money("1 234 567,8"), eur(123456780));
+        assert_eq!(parse_money("1'234.05"), eur(123405));
+        assert_eq!(parse_money(",50"), eur(50));
+    }
+
+    #[test]
+    fn test_errors() {
+        use MoneyParseError as Mpe;
+        assert_eq!(parse_money(""), Err(Mpe::MissingAmount));
+        assert_eq!(parse_money("€"), Err(Mpe::MissingAmount));
+        assert_eq!(
+            parse_money("5 BTC"),
+            Err(Mpe::UnknownCurrency("BTC".to_string()))
+        );
+        assert_eq!(parse_money("€5 EUR"), Err(Mpe::MultipleCurrencies));
+        assert_eq!(
+            parse_money("5.7400"),
+            Err(Mpe::TooManyDecimals("5.7400".to_string()))
+        );
+        assert_eq!(
+            parse_money("12.34.5"),
+            Err(Mpe::InvalidAmount("12.34.5".to_string()))
+        );
+        assert_eq!(
+            parse_money("5x"),
+            Err(Mpe::UnknownCurrency("x".to_string()))
+        );
+        assert_eq!(
+            parse_money("5#"),
+            Err(Mpe::InvalidAmount("5#".to_string()))
+        );
+        assert_eq!(parse_money("99999999999999999999"), Err(Mpe::Overflow));
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let a = Money::from_cents(574);
+        let b = Money::from_cents(6632);
+        let usd = Money::new(100, Currency::Usd);
+
+        assert_eq!(a.checked_add(b), Ok(Money::from_cents(7206)));
+        assert_eq!(a.checked_sub(b), Ok(Money::from_cents(-6058)));
+        assert_eq!(a.checked_mul(3), Ok(Money::from_cents(1722)));
+        assert_eq!(
+            Money::checked_sum([a, b, a], Currency::Eur),
+            Ok(Money::from_cents(7780))
+        );
+
+        assert_eq!(
+            a.checked_add(usd),
+            Err(MoneyError::CurrencyMismatch {
+                left: Currency::Eur,
+                right: Currency::Usd
+            })
+        );
+        assert!(usd.in_books().is_err());
+        assert_eq!(a.in_books().map(Money::from), Ok(a));
+        assert_eq!(
+            Money::from_cents(i64::MAX).checked_add(a),
+            Err(MoneyError::Overflow)
+        );
+        assert_eq!(a.checked_mul(i64::MAX), Err(MoneyError::Overflow));
+
+        assert!(a < b);
+        assert_eq!(a.partial_cmp(&usd), None);
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        let cases = [
+            (Money::from_cents(574), "€5,74"),
+            (Money::from_cents(123450), "€1234,50"),
+            (Money::new(-5, Currency::Usd), "-$0,05"),
+            (Money::new(100_000_000, Currency::Gbp), "£1000000,00"),
+        ];
+        for (money, text) in cases {
+            assert_eq!(money.to_string(), text);
+            assert_eq!(parse_money(text), Ok(money));
+
+            let json = serde_json::to_string(&money).unwrap();
+            assert_eq!(json, format!("\"{text}\""));
+        }
+
+        let money: Money = serde_json::from_str("\"1.234,50 EUR\"").unwrap();
+        assert_eq!(money, Money::from_cents(123450));
+        assert!(serde_json::from_str::<Money>("\"5 BTC\"").is_err());
+    }
+}
@@ -0,0 +1,292 @@
+use crate::{BooksMoney, Client, Error, Money, OrderStatus, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+/// Identifies an existing order of a client by its number.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct OrderNumber {
+    pub number: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct CancelOrder {
+    pub client: Client,
+    pub order: OrderNumber,
+    /// HMAC of the message, see [`CancelOrder::signing_string`].
+    #[serde(default)]
+    pub signature: Option<String>,
+    /// Unix time the message was signed at, in seconds.
+    #[serde(default)]
+    pub timestamp: Option<i64>,
+    /// A value the client never signs twice, see
+    /// [`ClientOrder::nonce`](crate::ClientOrder::nonce).
+    #[serde(default)]
+    pub nonce: Option<String>,
+}
+
+impl CancelOrder {
+    /// The text a client signs to prove it sent this cancellation.
+    pub fn signing_string(&self) -> String {
+        let timestamp = self.timestamp.map(|t| t.to_string());
+        crate::signing_string([
+            Some("CancelOrder"),
+            Some(self.client.name_id.as_str()),
+            Some(self.order.number.to_string().as_str()),
+            timestamp.as_deref(),
+            self.nonce.as_deref(),
+        ])
+    }
+}
+
+/// Changes to an existing order. Fields left out are kept as they are.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct OrderAmendment {
+    pub number: i32,
+    pub quantity: Option<i32>,
+    pub due_date: Option<i32>,
+    pub late_pen: Option<String>,
+    pub early_pen: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct AmendOrder {
+    pub client: Client,
+    pub order: OrderAmendment,
+    /// HMAC of the message, see [`AmendOrder::signing_string`].
+    #[serde(default)]
+    pub signature: Option<String>,
+    /// Unix time the message was signed at, in seconds.
+    #[serde(default)]
+    pub timestamp: Option<i64>,
+    /// A value the client never signs twice, see
+    /// [`ClientOrder::nonce`](crate::ClientOrder::nonce).
+    #[serde(default)]
+    pub nonce: Option<String>,
+}
+
+impl AmendOrder {
+    /// The text a client signs to prove it sent this amendment.
+    /// Fields the amendment leaves unchanged are signed as left out.
+    pub fn signing_string(&self) -> String {
+        let OrderAmendment {
+            number,
+            quantity,
+            due_date,
+            late_pen,
+            early_pen,
+        } = &self.order;
+        let quantity = quantity.map(|q| q.to_string());
+        let due_date = due_date.map(|d| d.to_string());
+        let timestamp = self.timestamp.map(|t| t.to_string());
+        crate::signing_string([
+            Some("AmendOrder"),
+            Some(self.client.name_id.as_str()),
+            Some(number.to_string().as_str()),
+            quantity.as_deref(),
+            due_date.as_deref(),
+            late_pen.as_deref(),
+            early_pen.as_deref(),
+            timestamp.as_deref(),
+            self.nonce.as_deref(),
+        ])
+    }
+}
+
+struct LockedOrder {
+    id: i64,
+    status: OrderStatus,
+    quantity: i32,
+}
+
+/// Finds an order of a client by number and locks it for the
+/// rest of the transaction.
+/// Fails with [`Error::OrderCancelled`] or [`Error::OrderLocked`] if
+/// the order can no longer change.
+async fn tx_lock_client_order(
+    client: &Client,
+    number: i32,
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<LockedOrder> {
+    let order = sqlx::query_as!(
+        LockedOrder,
+        r#"SELECT o.id, o.status AS "status: OrderStatus", o.quantity
+        FROM orders o
+        INNER JOIN clients c ON c.id = o.client_id
+        WHERE c.name = $1 AND o.number = $2
+        FOR UPDATE OF o
+        "#,
+        client.name_id,
+        number
+    )
+    .fetch_optional(&mut **tx)
+    .await?;
+
+    match order {
+        None => Err(Error::UnknownOrder {
+            client: client.name_id.clone(),
+            number,
+        }),
+        Some(LockedOrder {
+            status: OrderStatus::Cancelled,
+            ..
+        }) => Err(Error::OrderCancelled {
+            client: client.name_id.clone(),
+            number,
+        }),
+        Some(LockedOrder { status, .. }) if !status.can_change() => {
+            Err(Error::OrderLocked {
+                client: client.name_id.clone(),
+                number,
+                status,
+            })
+        }
+        Some(order) => Ok(order),
+    }
+}
+
+/// Checks that an amendment only changes the quantity of an order
+/// that has not been scheduled yet, as its plan is made for the old one.
+fn check_quantity_change(
+    client: &Client,
+    number: i32,
+    order: &LockedOrder,
+    quantity: Option<i32>,
+) -> Result<()> {
+    match quantity {
+        Some(quantity)
+            if quantity != order.quantity
+                && !order.status.can_change_quantity() =>
+        {
+            Err(Error::OrderLocked {
+                client: client.name_id.clone(),
+                number,
+                status: order.status,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Cancel an order of a client.
+/// Fails with [`Error::UnknownOrder`], [`Error::OrderCancelled`] or
+/// [`Error::OrderLocked`] if there is no order that can be cancelled.
+/// A job is queued on the `OrderCancelled` channel with the
+/// id of the cancelled order, so its BOM entries can be dropped.
+pub async fn cancel_client_order(
+    pool: &PgPool,
+    CancelOrder { client, order, .. }: &CancelOrder,
+) -> Result<i64> {
+    let mut tx = pool.begin().await?;
+    let order_id = tx_lock_client_order(client, order.number, &mut tx)
+        .await?
+        .id;
+
+    crate::tx_set_order_status(order_id, OrderStatus::Cancelled, &mut tx)
+        .await?;
+
+    let event = crate::events::OrderCancelled { order_id };
+    crate::tx_enqueue_job(&event, &mut tx).await?;
+    tx.commit().await?;
+
+    Ok(order_id)
+}
+
+/// Amend the quantity, due date or penalties of an order of a client.
+/// The new values must follow the same rules as a new order,
+/// otherwise it fails with [`Error::InvalidOrder`]. Orders whose
+/// production already started fail with [`Error::OrderLocked`], and so
+/// do quantity changes of orders that are already scheduled.
+/// A job is queued on the `OrderAmended` channel with the
+/// id of the amended order, so its BOM entries can be regenerated.
+pub async fn amend_client_order(
+    pool: &PgPool,
+    amendment: &AmendOrder,
+) -> Result<i64> {
+    let problems = crate::validate_order_amendment(pool, amendment).await?;
+    if !problems.is_empty() {
+        return Err(Error::InvalidOrder(problems));
+    }
+
+    let AmendOrder { client, order, .. } = amendment;
+    // Penalties were validated above, so they parse.
+    let parse_penalty = |penalty: &Option<String>| {
+        penalty
+            .as_deref()
+            .and_then(|p| crate::parse_money(p).ok())
+            .map(Money::in_books)
+            .transpose()
+    };
+    let late_pen = parse_penalty(&order.late_pen)?;
+    let early_pen = parse_penalty(&order.early_pen)?;
+
+    let mut tx = pool.begin().await?;
+    let locked = tx_lock_client_order(client, order.number, &mut tx).await?;
+    check_quantity_change(client, order.number, &locked, order.quantity)?;
+    let order_id = locked.id;
+
+    sqlx::query!(
+        "UPDATE orders SET
+            quantity = COALESCE($2, quantity),
+            due_date = COALESCE($3, due_date),
+            late_pen = COALESCE($4, late_pen),
+            early_pen = COALESCE($5, early_pen)
+        WHERE id = $1
+        ",
+        order_id,
+        order.quantity,
+        order.due_date,
+        late_pen as Option<BooksMoney>,
+        early_pen as Option<BooksMoney>
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let event = crate::events::OrderAmended { order_id };
+    crate::tx_enqueue_job(&event, &mut tx).await?;
+    tx.commit().await?;
+
+    Ok(order_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locked(status: OrderStatus) -> LockedOrder {
+        LockedOrder {
+            id: 1,
+            status,
+            quantity: 10,
+        }
+    }
+
+    #[test]
+    fn test_check_quantity_change() {
+        use OrderStatus as Os;
+        let client = Client {
+            name_id: "Client AA".to_string(),
+        };
+        let check = |status, quantity| {
+            check_quantity_change(&client, 7, &locked(status), quantity)
+        };
+
+        assert!(check(Os::Received, Some(20)).is_ok());
+        assert!(check(Os::BomResolved, Some(20)).is_ok());
+        assert!(matches!(
+            check(Os::Scheduled, Some(20)),
+            Err(Error::OrderLocked {
+                number: 7,
+                status: Os::Scheduled,
+                ..
+            })
+        ));
+
+        // Scheduled orders can still change anything but the quantity.
+        assert!(check(Os::Scheduled, None).is_ok());
+        assert!(check(Os::Scheduled, Some(10)).is_ok());
+    }
+}
@@ -0,0 +1,212 @@
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgPool,
+};
+
+/// Where an order is in its lifecycle.
+///
+/// Orders go through every status from `Received` to `Shipped`, in the
+/// order they are declared. Until its production starts, an order can
+/// also be amended or `Cancelled`, though its quantity only until it is
+/// `Scheduled`.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::Type,
+)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "order_status", rename_all = "snake_case")]
+pub enum OrderStatus {
+    Received,
+    BomResolved,
+    Scheduled,
+    InProduction,
+    Completed,
+    Shipped,
+    Cancelled,
+}
+
+impl OrderStatus {
+    /// Whether an order can go from this status to `next`.
+    pub fn can_transition_to(self, next: OrderStatus) -> bool {
+        use OrderStatus as Os;
+        matches!(
+            (self, next),
+            (Os::Received, Os::BomResolved)
+                | (Os::BomResolved, Os::Scheduled)
+                | (Os::Scheduled, Os::InProduction)
+                | (Os::InProduction, Os::Completed)
+                | (Os::Completed, Os::Shipped)
+                | (
+                    Os::Received | Os::BomResolved | Os::Scheduled,
+                    Os::Cancelled
+                )
+        )
+    }
+
+    /// Whether the client can still amend or cancel the order.
+    pub fn can_change(self) -> bool {
+        self.can_transition_to(OrderStatus::Cancelled)
+    }
+
+    /// Whether the quantity of the order can still change. Once it is
+    /// scheduled, its production plan is made for the quantity it has.
+    pub fn can_change_quantity(self) -> bool {
+        matches!(self, OrderStatus::Received | OrderStatus::BomResolved)
+    }
+}
+
+impl std::fmt::Display for OrderStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use OrderStatus as Os;
+        match self {
+            Os::Received => write!(f, "received"),
+            Os::BomResolved => write!(f, "bom_resolved"),
+            Os::Scheduled => write!(f, "scheduled"),
+            Os::InProduction => write!(f, "in_production"),
+            Os::Completed => write!(f, "completed"),
+            Os::Shipped => write!(f, "shipped"),
+            Os::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// One entry of the status history of an order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrderStatusChange {
+    pub id: i64,
+    pub order_id: i64,
+    /// `None` for the entry written when the order was placed.
+    pub from_status: Option<OrderStatus>,
+    pub to_status: OrderStatus,
+    pub changed_at: DateTime<Utc>,
+}
+
+/// Moves an order to a new status.
+/// Fails with [`Error::InvalidTransition`] if the order can not go from
+/// its current status to `status`, and [`Error::NotFound`] if there
+/// is no such order.
+///
+/// The change is written to the status history, and published as an
+/// [`OrderStatusChanged`](crate::events::OrderStatusChanged) event.
+pub async fn set_order_status(
+    order_id: i64,
+    status: OrderStatus,
+    pool: &PgPool,
+) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    tx_set_order_status(order_id, status, &mut tx).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Same as [`set_order_status`]. Use with a transaction type connection
+pub async fn tx_set_order_status(
+    order_id: i64,
+    status: OrderStatus,
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<()> {
+    let current = sqlx::query!(
+        r#"SELECT status AS "status: OrderStatus"
+        FROM orders
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+        order_id
+    )
+    .fetch_one(&mut **tx)
+    .await?
+    .status;
+
+    if !current.can_transition_to(status) {
+        return Err(Error::InvalidTransition {
+            order_id,
+            from: current,
+            to: status,
+        });
+    }
+
+    sqlx::query!(
+        "UPDATE orders SET status = $2 WHERE id = $1",
+        order_id,
+        status as OrderStatus
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    sqlx::query!(
+        "INSERT INTO order_status_history (order_id, from_status, to_status)
+        VALUES ($1, $2, $3)
+        ",
+        order_id,
+        current as OrderStatus,
+        status as OrderStatus
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    let event = crate::events::OrderStatusChanged {
+        order_id,
+        from: current,
+        to: status,
+    };
+    crate::events::publish(tx, &event).await?;
+
+    Ok(())
+}
+
+/// Gets the status history of an order, oldest first.
+pub async fn get_order_status_history(
+    order_id: i64,
+    pool: &PgPool,
+) -> Result<Vec<OrderStatusChange>> {
+    Ok(sqlx::query_as!(
+        OrderStatusChange,
+        r#"SELECT
+            id,
+            order_id,
+            from_status AS "from_status: OrderStatus",
+            to_status AS "to_status: OrderStatus",
+            changed_at
+        FROM order_status_history
+        WHERE order_id = $1
+        ORDER BY id
+        "#,
+        order_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order_status_transitions() {
+        use OrderStatus as Os;
+        let lifecycle = [
+            Os::Received,
+            Os::BomResolved,
+            Os::Scheduled,
+            Os::InProduction,
+            Os::Completed,
+            Os::Shipped,
+        ];
+        for step in lifecycle.windows(2) {
+            assert!(step[0].can_transition_to(step[1]));
+            assert!(!step[1].can_transition_to(step[0]));
+        }
+
+        assert!(!Os::Received.can_transition_to(Os::Scheduled));
+        assert!(!Os::Received.can_transition_to(Os::Received));
+        assert!(Os::Scheduled.can_change());
+        assert!(!Os::InProduction.can_change());
+        assert!(!Os::Cancelled.can_change());
+        assert!(Os::BomResolved.can_change_quantity());
+        assert!(!Os::Scheduled.can_change_quantity());
+        assert!(lifecycle
+            .iter()
+            .all(|status| !Os::Cancelled.can_transition_to(*status)));
+    }
+}
@@ -1,31 +1,13 @@
+use crate::{BooksMoney, Catalog, Error, Money, OrderStatus, PieceId, Result};
 use serde::{Deserialize, Serialize};
-use sqlx::{error::BoxDynError, postgres::types::PgMoney, PgPool};
+use sqlx::PgPool;
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all(deserialize = "PascalCase"))]
-pub enum WorkPieces {
-    P5,
-    P6,
-    P7,
-    P9,
-}
-
-impl std::fmt::Display for WorkPieces {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            WorkPieces::P5 => write!(f, "P5"),
-            WorkPieces::P6 => write!(f, "P6"),
-            WorkPieces::P7 => write!(f, "P7"),
-            WorkPieces::P9 => write!(f, "P9"),
-        }
-    }
-}
-
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all(deserialize = "PascalCase"))]
+#[serde(rename_all = "PascalCase")]
 pub struct Order {
     pub number: i32,
-    pub work_piece: WorkPieces,
+    /// Name of a piece of the catalog, see [`Catalog`](crate::Catalog).
+    pub work_piece: String,
     pub quantity: i32,
     pub due_date: i32,
     pub late_pen: String,
@@ -33,48 +15,105 @@ pub struct Order {
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all(deserialize = "PascalCase"))]
+#[serde(rename_all = "PascalCase")]
 pub struct Client {
     pub name_id: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all(deserialize = "PascalCase"))]
+#[serde(rename_all = "PascalCase")]
 pub struct ClientOrder {
     pub client: Client,
     pub order: Order,
+    /// HMAC of the message, see [`ClientOrder::signing_string`].
+    #[serde(default)]
+    pub signature: Option<String>,
+    /// Unix time the message was signed at, in seconds.
+    #[serde(default)]
+    pub timestamp: Option<i64>,
+    /// A value the client never signs twice, so the message can not be
+    /// sent again by someone else.
+    #[serde(default)]
+    pub nonce: Option<String>,
+}
+
+impl ClientOrder {
+    /// The text a client signs to prove it sent this order.
+    pub fn signing_string(&self) -> String {
+        let Order {
+            number,
+            work_piece,
+            quantity,
+            due_date,
+            late_pen,
+            early_pen,
+        } = &self.order;
+        let timestamp = self.timestamp.map(|t| t.to_string());
+        crate::signing_string([
+            Some("ClientOrder"),
+            Some(self.client.name_id.as_str()),
+            Some(number.to_string().as_str()),
+            Some(work_piece.as_str()),
+            Some(quantity.to_string().as_str()),
+            Some(due_date.to_string().as_str()),
+            Some(late_pen.as_str()),
+            Some(early_pen.as_str()),
+            timestamp.as_deref(),
+            self.nonce.as_deref(),
+        ])
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PgOrder {
     pub id: i64,
-    pub piece_id: i64,
+    pub piece_id: PieceId,
     pub client_id: i64,
     pub number: i32,
     pub quantity: i32,
     pub due_date: i32,
-    pub late_pen: PgMoney,
-    pub early_pen: PgMoney,
+    pub late_pen: Money,
+    pub early_pen: Money,
+    pub status: OrderStatus,
 }
 
 /// Place a new order for a client with the given order details.
 /// If the client does not exist, it will be created.
-/// Money strings are expected to be in the format "$123.45" or "123.45€"
+/// Money strings are parsed with [`parse_money`](crate::parse_money),
+/// e.g. "$123.45", "123,45€" or "EUR 1.234,50".
+///
+/// The order is checked with
+/// [`validate_client_order`](crate::validate_client_order) first and
+/// fails with [`Error::InvalidOrder`] if it breaks any rule.
+/// Fails with [`Error::DuplicateOrder`] if the client already placed an
+/// order with the same number and [`Error::UnknownPiece`] if the work
+/// piece is not in `catalog`.
+/// A `NewOrder` job is queued with the id of the new order, so its
+/// BOM entries can be generated.
 pub async fn place_client_order(
     pool: &PgPool,
-    ClientOrder { client, order }: &ClientOrder,
-) -> Result<i64, BoxDynError> {
-    let late_penalty = parse_money_string(&order.late_pen)?;
-    let early_penalty = parse_money_string(&order.early_pen)?;
+    catalog: &Catalog,
+    client_order: &ClientOrder,
+) -> Result<i64> {
+    let problems =
+        crate::validate_client_order(pool, catalog, client_order).await?;
+    if !problems.is_empty() {
+        return Err(Error::InvalidOrder(problems));
+    }
+
+    let ClientOrder { client, order, .. } = client_order;
+    let late_penalty = crate::parse_money(&order.late_pen)?;
+    let early_penalty = crate::parse_money(&order.early_pen)?;
+
+    let piece_id = catalog.piece(&order.work_piece)?.id;
 
     let mut tx = pool.begin().await?;
-    let piece_id = tx_get_piece_id(&order.work_piece, &mut tx).await?;
     let client_id = match tx_get_client_id(client, &mut tx).await {
         Ok(id) => {
             tracing::debug!("Client found! ID: {}", id);
             id
         }
-        Err(_) => {
+        Err(Error::NotFound) => {
             tracing::debug!("Client not found! Creating new client");
             sqlx::query!(
                 "INSERT INTO clients(name) VALUES($1) RETURNING id",
@@ -84,6 +123,7 @@ pub async fn place_client_order(
             .await?
             .id
         }
+        Err(e) => return Err(e),
     };
 
     let order = PgOrder {
@@ -93,19 +133,26 @@ pub async fn place_client_order(
         number: order.number,
         quantity: order.quantity,
         due_date: order.due_date,
-        late_pen: PgMoney(late_penalty),
-        early_pen: PgMoney(early_penalty),
+        late_pen: late_penalty,
+        early_pen: early_penalty,
+        status: OrderStatus::Received,
     };
 
     tracing::debug!("Placing order: {:#?}", order);
+    let number = order.number;
     let order_id = match place_new_order(order, &mut tx).await {
         Ok(id) => id,
-        Err(e) => return Err(e.into()),
+        Err(Error::UniqueViolation { .. }) => {
+            return Err(Error::DuplicateOrder {
+                client: client.name_id.clone(),
+                number,
+            })
+        }
+        Err(e) => return Err(e),
     };
 
-    let channel = crate::NotificationChannel::NewOrder;
-    let query = format!("NOTIFY {}, '{}'", channel, order_id);
-    sqlx::query(&query).execute(&mut *tx).await?;
+    let event = crate::events::NewOrder { order_id };
+    crate::tx_enqueue_job(&event, &mut tx).await?;
     tx.commit().await?;
 
     Ok(order_id)
@@ -114,8 +161,11 @@ pub async fn place_client_order(
 async fn place_new_order(
     order: PgOrder,
     tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-) -> sqlx::Result<i64> {
-    Ok(sqlx::query!(
+) -> Result<i64> {
+    let late_pen = order.late_pen.in_books()?;
+    let early_pen = order.early_pen.in_books()?;
+
+    let order_id = sqlx::query!(
         "INSERT INTO orders (
             piece_id,
             client_id,
@@ -123,30 +173,44 @@ async fn place_new_order(
             quantity,
             due_date,
             late_pen,
-            early_pen
+            early_pen,
+            status
         )
         VALUES
-            ($1, $2, $3, $4, $5, $6, $7)
+            ($1, $2, $3, $4, $5, $6, $7, $8)
         RETURNING id
         ",
-        order.piece_id,
+        order.piece_id as PieceId,
         order.client_id,
         order.number,
         order.quantity,
         order.due_date,
-        order.late_pen,
-        order.early_pen
+        late_pen as BooksMoney,
+        early_pen as BooksMoney,
+        order.status as OrderStatus
     )
     .fetch_one(&mut **tx)
     .await?
-    .id)
+    .id;
+
+    sqlx::query!(
+        "INSERT INTO order_status_history (order_id, to_status)
+        VALUES ($1, $2)
+        ",
+        order_id,
+        order.status as OrderStatus
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(order_id)
 }
 
 /// Get the id of a client. Use with a transaction type connection
 pub async fn tx_get_client_id(
     client: &Client,
     tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-) -> Result<i64, sqlx::Error> {
+) -> Result<i64> {
     Ok(
         sqlx::query!("SELECT id FROM clients WHERE name = $1", client.name_id)
             .fetch_one(&mut **tx)
@@ -155,63 +219,83 @@ pub async fn tx_get_client_id(
     )
 }
 
-/// Get the id of a piece. Use with a transaction type connection
-pub async fn tx_get_piece_id(
-    piece_name: &WorkPieces,
-    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-) -> sqlx::Result<i64> {
+/// Get the numbers of every order a client has placed.
+/// Returns an empty list if the client does not exist.
+pub async fn get_client_order_numbers(
+    client: &Client,
+    pool: &PgPool,
+) -> Result<Vec<i32>> {
     Ok(sqlx::query!(
-        "SELECT id FROM pieces WHERE name = $1",
-        piece_name.to_string()
+        "SELECT o.number
+        FROM orders o
+        INNER JOIN clients c ON c.id = o.client_id
+        WHERE c.name = $1
+        ",
+        client.name_id
     )
-    .fetch_one(&mut **tx)
+    .fetch_all(pool)
     .await?
-    .id)
+    .into_iter()
+    .map(|row| row.number)
+    .collect())
 }
 
-/// Parse a money string into a number of cents.
-/// Non-digit characters are ignored.
-/// This function assumes that the input is a valid money string.
-///
-/// # Examples:
-/// ```
-/// use db_api::parse_money_string;
-/// let money = "$123.45";
-/// assert_eq!(parse_money_string(money), Ok(12345));
-///
-/// let money = "123.45€";
-/// assert_eq!(parse_money_string(money), Ok(12345));
-/// ```
-pub fn parse_money_string(money: &str) -> Result<i64, std::num::ParseIntError> {
-    money
-        .chars()
-        .filter(|c| c.is_ascii_digit())
-        .collect::<String>()
-        .parse()
+/// Get the shared secret used to verify the messages of a client.
+/// Returns `None` if the client does not exist or has no secret.
+pub async fn get_client_secret(
+    client: &Client,
+    pool: &PgPool,
+) -> Result<Option<String>> {
+    Ok(sqlx::query!(
+        "SELECT secret FROM clients WHERE name = $1",
+        client.name_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .and_then(|row| row.secret))
 }
 
-pub async fn run_migrations(pool: &PgPool) -> sqlx::Result<()> {
-    sqlx::migrate!("./migrations").run(pool).await?;
+/// Set the shared secret of a client, creating the client if needed.
+pub async fn set_client_secret(
+    client: &Client,
+    secret: &str,
+    pool: &PgPool,
+) -> Result<()> {
+    sqlx::query!(
+        "INSERT INTO clients (name, secret) VALUES ($1, $2)
+        ON CONFLICT (name) DO UPDATE SET secret = EXCLUDED.secret
+        ",
+        client.name_id,
+        secret
+    )
+    .execute(pool)
+    .await?;
     Ok(())
 }
 
-pub async fn get_order(
-    new_order_id: i64,
-    pool: &PgPool,
-) -> sqlx::Result<PgOrder> {
-    sqlx::query_as!(PgOrder, "SELECT * FROM orders WHERE id = $1", new_order_id)
-        .fetch_one(pool)
-        .await
+pub async fn run_migrations(pool: &PgPool) -> Result<()> {
+    sqlx::migrate!("./migrations").run(pool).await?;
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    #[test]
-    fn test_parse_money_string() {
-        let money = "$123.45";
-        assert_eq!(parse_money_string(money), Ok(12345));
-        let money = "123.45€";
-        assert_eq!(parse_money_string(money), Ok(12345));
-    }
+pub async fn get_order(new_order_id: i64, pool: &PgPool) -> Result<PgOrder> {
+    Ok(sqlx::query_as!(
+        PgOrder,
+        r#"SELECT
+            id,
+            piece_id AS "piece_id: PieceId",
+            client_id,
+            number,
+            quantity,
+            due_date,
+            late_pen AS "late_pen: Money",
+            early_pen AS "early_pen: Money",
+            status AS "status: OrderStatus"
+        FROM orders
+        WHERE id = $1
+        "#,
+        new_order_id
+    )
+    .fetch_one(pool)
+    .await?)
 }
@@ -1,20 +1,75 @@
+use crate::{Error, Result};
+use sqlx::{Acquire, PgPool};
+use std::{collections::BTreeMap, ops::Range};
+
+/// A BOM step planned on a production line, for `duration` timeslots
+/// from a timeslot of a day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Production {
     pub id: i64,
     pub order_id: i64,
     pub bom_id: i64,
     pub day: i32,
     pub timeslot: i32,
+    /// Timeslots the run takes, see
+    /// [`Transformation::timeslots_on`](crate::Transformation::timeslots_on).
+    /// Runs may go on into the next day.
+    pub duration: i32,
     pub prod_line_id: i64,
 }
 
+/// BOM steps whose runs overlap on a production line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DoubleBooking {
+    /// The first timeslot used by more than one run.
+    pub day: i32,
+    pub timeslot: i32,
+    pub prod_line_id: i64,
+    /// The BOM steps of the overlapping runs, in id order.
+    pub bom_ids: Vec<i64>,
+}
+
+impl std::fmt::Display for DoubleBooking {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bom_ids = self
+            .bom_ids
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>();
+        write!(
+            f,
+            "line {} from day {}, timeslot {} has overlapping BOM entries {}",
+            self.prod_line_id,
+            self.day,
+            self.timeslot,
+            bom_ids.join(", ")
+        )
+    }
+}
+
+/// Selects rows of the plan. Fields left as `None` match every row.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProductionFilter {
+    pub day: Option<i32>,
+    pub timeslot: Option<i32>,
+    pub prod_line_id: Option<i64>,
+    pub order_id: Option<i64>,
+}
+
 impl Production {
+    /// Seconds in a timeslot.
     pub const TIME_SLOT_UNIT: i32 = 5;
+    /// Timeslots in a day, numbered from 1.
+    pub const TIMESLOTS_PER_DAY: i32 = 12;
+    /// Exclusion constraint that keeps runs on a line from overlapping.
+    const LINE_RUNS_CONSTRAINT: &'static str = "production_line_runs";
 
     pub fn new(
         order_id: i64,
         bom_id: i64,
         day: i32,
         timeslot: i32,
+        duration: i32,
         prod_line_id: i64,
     ) -> Production {
         Production {
@@ -23,7 +78,457 @@ impl Production {
             bom_id,
             day,
             timeslot,
+            duration,
             prod_line_id,
         }
     }
+
+    /// The timeslots the run takes, counted from the first timeslot of
+    /// day 1, same as `production_run` in the schema.
+    pub fn run(&self) -> Range<i32> {
+        let start =
+            (self.day - 1) * Production::TIMESLOTS_PER_DAY + self.timeslot;
+        start..start + self.duration
+    }
+
+    /// Stores a production plan, in a single transaction.
+    ///
+    /// Fails with [`Error::DoubleBooked`] if runs of the plan overlap on
+    /// a line, or overlap runs that are already planned, and nothing is
+    /// stored. The schema enforces this, so of two plans stored at the
+    /// same time only one can take a timeslot.
+    /// Fails with [`Error::ForeignKeyViolation`] if a line is not in the
+    /// `production_lines` table.
+    /// Returns the ids given to the rows, in the order of the plan.
+    pub async fn insert_batch(
+        plan: &[Production],
+        pool: &PgPool,
+    ) -> Result<Vec<i64>> {
+        let mut tx = pool.begin().await?;
+        let ids = Production::tx_insert_batch(plan, &mut tx).await?;
+        tx.commit().await?;
+        Ok(ids)
+    }
+
+    /// Same as [`Production::insert_batch`]. Use with a transaction type
+    /// connection, which should be rolled back if it fails.
+    pub async fn tx_insert_batch(
+        plan: &[Production],
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<Vec<i64>> {
+        let double_booked = double_bookings(plan);
+        if !double_booked.is_empty() {
+            return Err(Error::DoubleBooked(double_booked));
+        }
+
+        let mut order_ids = Vec::with_capacity(plan.len());
+        let mut bom_ids = Vec::with_capacity(plan.len());
+        let mut days = Vec::with_capacity(plan.len());
+        let mut timeslots = Vec::with_capacity(plan.len());
+        let mut durations = Vec::with_capacity(plan.len());
+        let mut prod_line_ids = Vec::with_capacity(plan.len());
+        for row in plan {
+            order_ids.push(row.order_id);
+            bom_ids.push(row.bom_id);
+            days.push(row.day);
+            timeslots.push(row.timeslot);
+            durations.push(row.duration);
+            prod_line_ids.push(row.prod_line_id);
+        }
+
+        // A failed insert aborts the transaction, the savepoint keeps it
+        // usable to look up the clashing rows.
+        let mut savepoint = tx.begin().await?;
+
+        // Same as `Bom::tx_insert_batch`, the sorted ids follow the
+        // order of the plan.
+        let inserted = sqlx::query!(
+            "INSERT INTO production(
+                order_id,
+                bom_id,
+                day,
+                timeslot,
+                duration,
+                prod_line_id
+            )
+            SELECT order_id, bom_id, day, timeslot, duration, prod_line_id
+            FROM UNNEST(
+                $1::BIGINT[],
+                $2::BIGINT[],
+                $3::INT[],
+                $4::INT[],
+                $5::INT[],
+                $6::BIGINT[]
+            ) WITH ORDINALITY AS plan(
+                order_id,
+                bom_id,
+                day,
+                timeslot,
+                duration,
+                prod_line_id,
+                position
+            )
+            ORDER BY position
+            RETURNING id
+            ",
+            &order_ids,
+            &bom_ids,
+            &days,
+            &timeslots,
+            &durations,
+            &prod_line_ids
+        )
+        .fetch_all(&mut *savepoint)
+        .await;
+
+        let rows = match inserted {
+            Ok(rows) => rows,
+            Err(e) => {
+                savepoint.rollback().await?;
+                let overlaps = e
+                    .as_database_error()
+                    .and_then(|db| db.constraint())
+                    .is_some_and(|c| c == Production::LINE_RUNS_CONSTRAINT);
+                if !overlaps {
+                    return Err(e.into());
+                }
+                let stored = Production::tx_get_overlapping(plan, tx).await?;
+                let runs = [stored, plan.to_vec()].concat();
+                return Err(Error::DoubleBooked(double_bookings(&runs)));
+            }
+        };
+        savepoint.commit().await?;
+
+        let mut ids = rows.into_iter().map(|row| row.id).collect::<Vec<_>>();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    /// Gets the stored rows whose runs overlap runs of `plan`.
+    async fn tx_get_overlapping(
+        plan: &[Production],
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<Vec<Production>> {
+        let days = plan.iter().map(|row| row.day).collect::<Vec<_>>();
+        let timeslots = plan.iter().map(|row| row.timeslot).collect::<Vec<_>>();
+        let durations = plan.iter().map(|row| row.duration).collect::<Vec<_>>();
+        let prod_line_ids =
+            plan.iter().map(|row| row.prod_line_id).collect::<Vec<_>>();
+
+        Ok(sqlx::query_as!(
+            Production,
+            "SELECT * FROM production p
+            WHERE EXISTS (
+                SELECT FROM UNNEST(
+                    $1::INT[],
+                    $2::INT[],
+                    $3::INT[],
+                    $4::BIGINT[]
+                ) AS plan(day, timeslot, duration, prod_line_id)
+                WHERE plan.prod_line_id = p.prod_line_id
+                    AND production_run(plan.day, plan.timeslot, plan.duration)
+                        && production_run(p.day, p.timeslot, p.duration)
+            )
+            ",
+            &days,
+            &timeslots,
+            &durations,
+            &prod_line_ids
+        )
+        .fetch_all(&mut **tx)
+        .await?)
+    }
+
+    /// Gets the rows of the plan that match the filter, ordered by day,
+    /// timeslot and line. The timeslot is the one a run starts in.
+    pub async fn get(
+        filter: &ProductionFilter,
+        pool: &PgPool,
+    ) -> Result<Vec<Production>> {
+        Ok(sqlx::query_as!(
+            Production,
+            "SELECT * FROM production
+            WHERE ($1::INT IS NULL OR day = $1)
+                AND ($2::INT IS NULL OR timeslot = $2)
+                AND ($3::BIGINT IS NULL OR prod_line_id = $3)
+                AND ($4::BIGINT IS NULL OR order_id = $4)
+            ORDER BY day, timeslot, prod_line_id, id
+            ",
+            filter.day,
+            filter.timeslot,
+            filter.prod_line_id,
+            filter.order_id
+        )
+        .fetch_all(pool)
+        .await?)
+    }
+
+    /// Gets the plan of an order, ordered by day, timeslot and line.
+    pub async fn get_by_order(
+        order_id: i64,
+        pool: &PgPool,
+    ) -> Result<Vec<Production>> {
+        let filter = ProductionFilter {
+            order_id: Some(order_id),
+            ..Default::default()
+        };
+        Production::get(&filter, pool).await
+    }
+
+    /// Deletes the plan of an order.
+    /// Returns the number of deleted rows.
+    pub async fn delete_by_order(order_id: i64, pool: &PgPool) -> Result<u64> {
+        Ok(
+            sqlx::query!(
+                "DELETE FROM production WHERE order_id = $1",
+                order_id
+            )
+            .execute(pool)
+            .await?
+            .rows_affected(),
+        )
+    }
+
+    /// Finds every run that overlaps another one on its line.
+    /// The `production_line_runs` constraint refuses them, so this is
+    /// only a check of the stored plan and should find none.
+    pub async fn get_double_booked(
+        pool: &PgPool,
+    ) -> Result<Vec<DoubleBooking>> {
+        let overlapping = sqlx::query_as!(
+            Production,
+            "SELECT * FROM production p
+            WHERE EXISTS (
+                SELECT FROM production q
+                WHERE q.id <> p.id
+                    AND q.prod_line_id = p.prod_line_id
+                    AND production_run(q.day, q.timeslot, q.duration)
+                        && production_run(p.day, p.timeslot, p.duration)
+            )
+            "
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(double_bookings(&overlapping))
+    }
+}
+
+/// Finds the runs of a plan that overlap on a line, ordered by the day,
+/// timeslot and line the overlap starts at.
+/// Runs are grouped while each one starts before the ones before it
+/// end, so a group may hold runs that do not overlap each other.
+pub fn double_bookings(plan: &[Production]) -> Vec<DoubleBooking> {
+    let mut lines = BTreeMap::<_, Vec<&Production>>::new();
+    for row in plan {
+        lines.entry(row.prod_line_id).or_default().push(row);
+    }
+
+    let mut double_booked = Vec::new();
+    for (prod_line_id, mut runs) in lines {
+        runs.sort_by_key(|row| (row.run().start, row.bom_id));
+
+        let mut group = Vec::<&Production>::new();
+        let mut end = i32::MIN;
+        for row in runs {
+            if row.run().start >= end {
+                double_booked.extend(overlap(prod_line_id, &group));
+                group.clear();
+            }
+            end = end.max(row.run().end);
+            group.push(row);
+        }
+        double_booked.extend(overlap(prod_line_id, &group));
+    }
+
+    double_booked
+        .sort_by_key(|slot| (slot.day, slot.timeslot, slot.prod_line_id));
+    double_booked
+}
+
+/// The double booking of a group of overlapping runs, sorted by start,
+/// `None` if there is a single run.
+fn overlap(prod_line_id: i64, group: &[&Production]) -> Option<DoubleBooking> {
+    // Runs are sorted by start, the second one is where they overlap.
+    let start = group.get(1)?.run().start;
+    let mut bom_ids = group.iter().map(|row| row.bom_id).collect::<Vec<_>>();
+    bom_ids.sort_unstable();
+
+    let slots = Production::TIMESLOTS_PER_DAY;
+    Some(DoubleBooking {
+        day: (start - 1) / slots + 1,
+        timeslot: (start - 1) % slots + 1,
+        prod_line_id,
+        bom_ids,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Bom;
+    use sqlx::{Postgres, Transaction};
+
+    /// Adds an order with `steps` BOM entries, returns the ids of the
+    /// order and of its entries.
+    async fn setup(
+        steps: i32,
+        tx: &mut Transaction<'_, Postgres>,
+    ) -> Result<(i64, Vec<i64>)> {
+        let order =
+            crate::tx_insert_test_order("production test", 1, steps.into(), tx)
+                .await?;
+        let batch = order
+            .transformation_ids
+            .iter()
+            .zip(1..)
+            .map(|(transformation_id, step)| {
+                Bom::new(order.order_id, *transformation_id, 1, 1, step, steps)
+            })
+            .collect::<Vec<_>>();
+        let bom_ids = Bom::tx_insert_batch(&batch, tx).await?;
+        Ok((order.order_id, bom_ids))
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_insert_batch_refuses_double_booking() -> Result<()> {
+        let pool = crate::test_pool().await?;
+        let mut tx = pool.begin().await?;
+        let (order_id, bom) = setup(4, &mut tx).await?;
+
+        // Day 1, timeslots 1 to 3 and 4.
+        let plan = [
+            Production::new(order_id, bom[0], 1, 1, 3, 1),
+            Production::new(order_id, bom[1], 1, 4, 1, 1),
+        ];
+        let ids = Production::tx_insert_batch(&plan, &mut tx).await?;
+        assert_eq!(ids.len(), 2);
+
+        // Starts inside a stored run.
+        let clash = [
+            Production::new(order_id, bom[2], 1, 1, 1, 2),
+            Production::new(order_id, bom[3], 1, 3, 1, 1),
+        ];
+        match Production::tx_insert_batch(&clash, &mut tx).await {
+            Err(Error::DoubleBooked(slots)) => assert_eq!(
+                slots,
+                vec![DoubleBooking {
+                    day: 1,
+                    timeslot: 3,
+                    prod_line_id: 1,
+                    bom_ids: vec![bom[0], bom[3]],
+                }]
+            ),
+            other => panic!("expected a double booking, got {other:?}"),
+        }
+
+        // Overlaps within the plan.
+        let clash = [
+            Production::new(order_id, bom[2], 2, 1, 2, 1),
+            Production::new(order_id, bom[3], 2, 2, 1, 1),
+        ];
+        assert!(matches!(
+            Production::tx_insert_batch(&clash, &mut tx).await,
+            Err(Error::DoubleBooked(_))
+        ));
+
+        // Nothing of the refused plans was kept.
+        let free = [
+            Production::new(order_id, bom[2], 1, 1, 1, 2),
+            Production::new(order_id, bom[3], 1, 5, 12, 1),
+        ];
+        let ids = Production::tx_insert_batch(&free, &mut tx).await?;
+        assert_eq!(ids.len(), 2);
+
+        tx.rollback().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_insert_batch_needs_known_lines() -> Result<()> {
+        let pool = crate::test_pool().await?;
+        let mut tx = pool.begin().await?;
+        let (order_id, bom) = setup(1, &mut tx).await?;
+
+        let plan = [Production::new(order_id, bom[0], 1, 1, 1, i64::MAX)];
+        assert!(matches!(
+            Production::tx_insert_batch(&plan, &mut tx).await,
+            Err(Error::ForeignKeyViolation { .. })
+        ));
+
+        tx.rollback().await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_double_bookings() {
+        let plan = [
+            Production::new(1, 12, 3, 1, 1, 2),
+            Production::new(1, 11, 3, 1, 1, 2),
+            Production::new(1, 13, 3, 2, 1, 2),
+            Production::new(2, 20, 3, 1, 1, 1),
+            Production::new(2, 21, 1, 5, 1, 6),
+            Production::new(2, 22, 1, 5, 1, 6),
+            Production::new(2, 23, 1, 5, 1, 6),
+        ];
+
+        assert_eq!(
+            double_bookings(&plan),
+            vec![
+                DoubleBooking {
+                    day: 1,
+                    timeslot: 5,
+                    prod_line_id: 6,
+                    bom_ids: vec![21, 22, 23],
+                },
+                DoubleBooking {
+                    day: 3,
+                    timeslot: 1,
+                    prod_line_id: 2,
+                    bom_ids: vec![11, 12],
+                },
+            ]
+        );
+        assert!(double_bookings(&plan[2..5]).is_empty());
+        assert_eq!(
+            double_bookings(&plan[..2])[0].to_string(),
+            "line 2 from day 3, timeslot 1 has overlapping BOM entries 11, 12"
+        );
+    }
+
+    #[test]
+    fn test_overlapping_runs() {
+        let plan = [
+            // Day 1, timeslots 10 to 12 and day 2, timeslot 1.
+            Production::new(1, 10, 1, 10, 4, 1),
+            Production::new(1, 11, 2, 1, 2, 1),
+            // Starts where the run before it ends.
+            Production::new(1, 12, 2, 3, 1, 1),
+            // Overlaps on another line.
+            Production::new(1, 13, 1, 1, 9, 2),
+            Production::new(1, 14, 1, 9, 1, 2),
+            Production::new(1, 15, 1, 5, 2, 2),
+        ];
+
+        assert_eq!(
+            double_bookings(&plan),
+            vec![
+                DoubleBooking {
+                    day: 1,
+                    timeslot: 5,
+                    prod_line_id: 2,
+                    bom_ids: vec![13, 14, 15],
+                },
+                DoubleBooking {
+                    day: 2,
+                    timeslot: 1,
+                    prod_line_id: 1,
+                    bom_ids: vec![10, 11],
+                },
+            ]
+        );
+        assert!(double_bookings(&plan[1..4]).is_empty());
+        assert_eq!(plan[0].run(), 10..14);
+    }
 }
@@ -0,0 +1,75 @@
+use crate::{Client, Result};
+use sqlx::PgPool;
+
+/// Builds the text a client signs from the fields of a message.
+///
+/// Each field is written as a netstring, `<length>:<field>,`, and fields
+/// the message leaves out as `~`, so the fields can always be told apart
+/// whatever characters they hold.
+pub(crate) fn signing_string<'a>(
+    fields: impl IntoIterator<Item = Option<&'a str>>,
+) -> String {
+    fields
+        .into_iter()
+        .map(|field| match field {
+            Some(field) => format!("{}:{field},", field.len()),
+            None => "~".to_string(),
+        })
+        .collect()
+}
+
+/// Records the nonce of a signed message of a client.
+/// Returns `false` if the client already sent a message with the same
+/// nonce in the last `max_age_secs` seconds, i.e. the message is a replay.
+/// Older nonces are forgotten, messages that old are refused by their
+/// timestamp instead.
+pub async fn record_message_nonce(
+    client: &Client,
+    nonce: &str,
+    max_age_secs: i64,
+    pool: &PgPool,
+) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!(
+        "DELETE FROM message_nonces
+        WHERE seen_at < now() - make_interval(secs => $1)
+        ",
+        max_age_secs as f64
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let recorded = sqlx::query!(
+        "INSERT INTO message_nonces (client, nonce) VALUES ($1, $2)
+        ON CONFLICT DO NOTHING
+        ",
+        client.name_id,
+        nonce
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    tx.commit().await?;
+    Ok(recorded == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signing_string() {
+        assert_eq!(
+            signing_string([Some("CancelOrder"), Some("Kling Inc"), None]),
+            "11:CancelOrder,9:Kling Inc,~"
+        );
+
+        // Separators in a field do not shift it into the next one.
+        let joined = signing_string([Some("a,1:b"), Some("")]);
+        let split = signing_string([Some("a"), Some("b")]);
+        assert_ne!(joined, split);
+        assert_ne!(signing_string([Some("")]), signing_string([None]));
+    }
+}
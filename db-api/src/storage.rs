@@ -0,0 +1,557 @@
+use crate::{
+    events::{self, AnyEvent, Event},
+    Bom, Catalog, Error, Job, JobStatus, Money, OrderStatus, PgOrder, Piece,
+    PieceId, PieceKind, ProdLine, Recipe, Result, Tool, ToolId, Transformation,
+};
+use sqlx::PgPool;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    future::Future,
+    sync::Mutex,
+};
+
+/// Data access used by the production resolver.
+///
+/// [`PgPool`] implements it with the functions of this crate, and
+/// [`MemoryStorage`] keeps everything in memory so the resolver can be
+/// tested without a database.
+pub trait Storage {
+    fn get_order(
+        &self,
+        order_id: i64,
+    ) -> impl Future<Output = Result<PgOrder>> + Send;
+
+    /// See [`set_order_status`](crate::set_order_status).
+    fn set_order_status(
+        &self,
+        order_id: i64,
+        status: OrderStatus,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// See [`get_repice_to_root`](crate::get_repice_to_root).
+    fn get_repice_to_root(
+        &self,
+        final_piece_id: PieceId,
+    ) -> impl Future<Output = Result<Recipe>> + Send;
+
+    fn get_bom(&self, id: i64) -> impl Future<Output = Result<Bom>> + Send;
+
+    fn get_bom_by_order(
+        &self,
+        order_id: i64,
+    ) -> impl Future<Output = Result<Vec<Bom>>> + Send;
+
+    fn delete_bom_by_order(
+        &self,
+        order_id: i64,
+    ) -> impl Future<Output = Result<u64>> + Send;
+
+    /// See [`Bom::resolve_order`].
+    fn resolve_order_bom(
+        &self,
+        order_id: i64,
+        batch: &[Bom],
+    ) -> impl Future<Output = Result<Vec<i64>>> + Send;
+
+    /// See [`claim_job`](crate::claim_job).
+    fn claim_job(&self) -> impl Future<Output = Result<Option<Job>>> + Send;
+
+    /// See [`complete_job`](crate::complete_job).
+    fn complete_job(
+        &self,
+        job: &Job,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// See [`fail_job`](crate::fail_job).
+    fn fail_job(
+        &self,
+        job: &Job,
+        error: &str,
+    ) -> impl Future<Output = Result<JobStatus>> + Send;
+}
+
+impl Storage for PgPool {
+    async fn get_order(&self, order_id: i64) -> Result<PgOrder> {
+        crate::get_order(order_id, self).await
+    }
+
+    async fn set_order_status(
+        &self,
+        order_id: i64,
+        status: OrderStatus,
+    ) -> Result<()> {
+        crate::set_order_status(order_id, status, self).await
+    }
+
+    async fn get_repice_to_root(
+        &self,
+        final_piece_id: PieceId,
+    ) -> Result<Recipe> {
+        crate::get_repice_to_root(final_piece_id, self).await
+    }
+
+    async fn get_bom(&self, id: i64) -> Result<Bom> {
+        Bom::get_by_id(id, self).await
+    }
+
+    async fn get_bom_by_order(&self, order_id: i64) -> Result<Vec<Bom>> {
+        Bom::get_by_order(order_id, self).await
+    }
+
+    async fn delete_bom_by_order(&self, order_id: i64) -> Result<u64> {
+        Bom::delete_by_order(order_id, self).await
+    }
+
+    async fn resolve_order_bom(
+        &self,
+        order_id: i64,
+        batch: &[Bom],
+    ) -> Result<Vec<i64>> {
+        Bom::resolve_order(order_id, batch, self).await
+    }
+
+    async fn claim_job(&self) -> Result<Option<Job>> {
+        crate::claim_job(self).await
+    }
+
+    async fn complete_job(&self, job: &Job) -> Result<()> {
+        crate::complete_job(job, self).await
+    }
+
+    async fn fail_job(&self, job: &Job, error: &str) -> Result<JobStatus> {
+        crate::fail_job(job, error, self).await
+    }
+}
+
+#[derive(Debug, Default)]
+struct Tables {
+    orders: BTreeMap<i64, PgOrder>,
+    transformations: Vec<Transformation>,
+    bom: BTreeMap<i64, Bom>,
+    next_order_id: i64,
+    next_bom_id: i64,
+    events: Vec<AnyEvent>,
+    jobs: Vec<(Job, JobStatus)>,
+}
+
+impl Tables {
+    fn publish<E: Event>(&mut self, event: &E) {
+        let payload = events::encode(event);
+        let event = AnyEvent::decode(E::CHANNEL, &payload)
+            .expect("encoded events can be decoded");
+        self.events.push(event);
+    }
+
+    fn enqueue_job<E: Event>(&mut self, event: &E) {
+        let job = Job {
+            id: self.jobs.len() as i64 + 1,
+            channel: E::CHANNEL,
+            payload: events::encode(event),
+            attempts: 0,
+        };
+        self.jobs.push((job, JobStatus::Pending));
+        self.publish(event);
+    }
+}
+
+/// In memory [`Storage`], seeded with the pieces, tools, production
+/// lines and transformations the migrations insert.
+///
+/// Piece ids follow the order of `002_t_pieces.sql`, so `P1` is 1 and
+/// `P9` is 9, and tool ids the order of `019_catalogs.sql`.
+/// Events are recorded instead of published and can be taken with
+/// [`MemoryStorage::take_events`]. Failed jobs are retried right away,
+/// for at most [`MemoryStorage::MAX_ATTEMPTS`].
+#[derive(Debug)]
+pub struct MemoryStorage {
+    tables: Mutex<Tables>,
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryStorage {
+    /// Names and kinds of the pieces, in id order.
+    pub const PIECES: [(&'static str, PieceKind); 9] = [
+        ("P1", PieceKind::RawMaterial),
+        ("P2", PieceKind::RawMaterial),
+        ("P3", PieceKind::Intermediate),
+        ("P4", PieceKind::Intermediate),
+        ("P5", PieceKind::FinalProduct),
+        ("P6", PieceKind::FinalProduct),
+        ("P7", PieceKind::FinalProduct),
+        ("P8", PieceKind::Intermediate),
+        ("P9", PieceKind::FinalProduct),
+    ];
+
+    /// Names of the tools, in id order.
+    pub const TOOLS: [&'static str; 6] = ["T1", "T2", "T3", "T4", "T5", "T6"];
+    /// Names of the production lines, in id order.
+    pub const PROD_LINES: [&'static str; 6] =
+        ["L1", "L2", "L3", "L4", "L5", "L6"];
+
+    /// Times a job is tried before it is marked as failed.
+    pub const MAX_ATTEMPTS: i32 = 5;
+
+    pub fn new() -> Self {
+        // Same rows, in the same order, as `009_t_transformations.sql`,
+        // with the processing times of `021_processing_times.sql`.
+        let seed = [
+            ("P8", "P9", "T5", 1, 45, 45),
+            ("P8", "P7", "T6", 1, 15, 15),
+            ("P2", "P8", "T1", 1, 45, 45),
+            ("P4", "P7", "T3", 1, 15, 15),
+            ("P4", "P6", "T2", 1, 25, 25),
+            ("P4", "P5", "T4", 1, 25, 25),
+            ("P3", "P4", "T3", 1, 25, 25),
+            ("P3", "P4", "T2", 1, 15, 15),
+            ("P1", "P3", "T1", 1, 45, 45),
+        ];
+
+        let transformations = seed
+            .into_iter()
+            .enumerate()
+            .map(|(index, (from, to, tool, quantity, cost, time))| {
+                Transformation {
+                    id: index as i64 + 1,
+                    from_piece: Self::piece_id(from).expect("seeded piece"),
+                    to_piece: Self::piece_id(to).expect("seeded piece"),
+                    tool: Self::tool_id(tool).expect("seeded tool"),
+                    quantity,
+                    cost: Money::from_cents(cost * 100),
+                    processing_time: time,
+                    setup_time: 0,
+                    line_times: Vec::new(),
+                }
+            })
+            .collect();
+
+        Self {
+            tables: Mutex::new(Tables {
+                transformations,
+                next_order_id: 1,
+                next_bom_id: 1,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// The seeded pieces, tools and production lines.
+    pub fn catalog() -> Catalog {
+        let pieces = Self::PIECES
+            .into_iter()
+            .enumerate()
+            .map(|(index, (name, kind))| Piece {
+                id: PieceId(index as i64 + 1),
+                name: name.to_string(),
+                kind,
+            })
+            .collect();
+        let tools = Self::TOOLS
+            .into_iter()
+            .enumerate()
+            .map(|(index, name)| Tool {
+                id: ToolId(index as i64 + 1),
+                name: name.to_string(),
+            })
+            .collect();
+        let prod_lines = Self::PROD_LINES
+            .into_iter()
+            .enumerate()
+            .map(|(index, name)| ProdLine {
+                id: index as i64 + 1,
+                name: name.to_string(),
+            })
+            .collect();
+        Catalog::new(pieces, tools, prod_lines)
+    }
+
+    pub fn piece_id(name: &str) -> Option<PieceId> {
+        let index =
+            Self::PIECES.iter().position(|(piece, _)| *piece == name)?;
+        Some(PieceId(index as i64 + 1))
+    }
+
+    pub fn tool_id(name: &str) -> Option<ToolId> {
+        let index = Self::TOOLS.iter().position(|tool| *tool == name)?;
+        Some(ToolId(index as i64 + 1))
+    }
+
+    /// Adds an order, ignoring its id. Returns the id it was given.
+    pub fn insert_order(&self, order: PgOrder) -> i64 {
+        let mut tables = self.tables.lock().unwrap();
+        let id = tables.next_order_id;
+        tables.next_order_id += 1;
+        tables.orders.insert(id, PgOrder { id, ..order });
+        id
+    }
+
+    /// Changes an order in place, as an amendment or cancellation would.
+    pub fn update_order(
+        &self,
+        order_id: i64,
+        update: impl FnOnce(&mut PgOrder),
+    ) -> Result<()> {
+        let mut tables = self.tables.lock().unwrap();
+        let order = tables.orders.get_mut(&order_id).ok_or(Error::NotFound)?;
+        update(order);
+        Ok(())
+    }
+
+    /// Name of a seeded piece, or its id if there is no such piece.
+    fn piece_name(id: i64) -> String {
+        usize::try_from(id - 1)
+            .ok()
+            .and_then(|index| Self::PIECES.get(index))
+            .map_or_else(|| id.to_string(), |(name, _)| name.to_string())
+    }
+
+    /// Changes a seeded transformation in place.
+    pub fn update_transformation(
+        &self,
+        id: i64,
+        update: impl FnOnce(&mut Transformation),
+    ) -> Result<()> {
+        let mut tables = self.tables.lock().unwrap();
+        let transformation = tables
+            .transformations
+            .iter_mut()
+            .find(|transformation| transformation.id == id)
+            .ok_or(Error::NotFound)?;
+        update(transformation);
+        Ok(())
+    }
+
+    /// Every BOM entry, ordered by id.
+    pub fn bom(&self) -> Vec<Bom> {
+        let tables = self.tables.lock().unwrap();
+        tables.bom.values().copied().collect()
+    }
+
+    /// Queues a job, as the functions that write to the database would.
+    pub fn enqueue_job<E: Event>(&self, event: &E) {
+        let mut tables = self.tables.lock().unwrap();
+        tables.enqueue_job(event);
+    }
+
+    /// Every job with its status, in the order they were queued.
+    pub fn jobs(&self) -> Vec<(Job, JobStatus)> {
+        let tables = self.tables.lock().unwrap();
+        tables.jobs.clone()
+    }
+
+    /// Removes and returns the events published so far.
+    pub fn take_events(&self) -> Vec<AnyEvent> {
+        let mut tables = self.tables.lock().unwrap();
+        std::mem::take(&mut tables.events)
+    }
+}
+
+impl Storage for MemoryStorage {
+    async fn get_order(&self, order_id: i64) -> Result<PgOrder> {
+        let tables = self.tables.lock().unwrap();
+        tables.orders.get(&order_id).cloned().ok_or(Error::NotFound)
+    }
+
+    async fn set_order_status(
+        &self,
+        order_id: i64,
+        status: OrderStatus,
+    ) -> Result<()> {
+        let mut tables = self.tables.lock().unwrap();
+        let order = tables.orders.get_mut(&order_id).ok_or(Error::NotFound)?;
+        if !order.status.can_transition_to(status) {
+            return Err(Error::InvalidTransition {
+                order_id,
+                from: order.status,
+                to: status,
+            });
+        }
+        let event = events::OrderStatusChanged {
+            order_id,
+            from: order.status,
+            to: status,
+        };
+        order.status = status;
+        tables.publish(&event);
+        Ok(())
+    }
+
+    async fn get_repice_to_root(
+        &self,
+        final_piece_id: PieceId,
+    ) -> Result<Recipe> {
+        let tables = self.tables.lock().unwrap();
+        let made_into = |piece: PieceId| {
+            let mut transformations = tables
+                .transformations
+                .iter()
+                .filter(|t| t.to_piece == piece)
+                .collect::<Vec<_>>();
+            transformations.sort_by_key(|t| t.id);
+            transformations
+        };
+
+        // Same walk as `get_recipe_steps`, one depth at a time. Each step
+        // keeps the pieces from the final piece down to its input.
+        let mut steps = made_into(final_piece_id)
+            .into_iter()
+            .map(|t| (t, vec![t.to_piece.0, t.from_piece.0]))
+            .collect::<Vec<_>>();
+        let mut recipe = Recipe::new();
+        let mut seen = HashSet::new();
+
+        while !steps.is_empty() {
+            let mut next = Vec::new();
+            for (transformation, path) in steps {
+                let (input, made) = path.split_last().expect("paths are set");
+                if made.contains(input) {
+                    let cycle = crate::bom::cycle_of(&path)
+                        .into_iter()
+                        .map(Self::piece_name)
+                        .collect();
+                    return Err(Error::RecipeCycle(cycle));
+                }
+
+                if seen.insert(transformation.id) {
+                    recipe.push(transformation.clone());
+                }
+                for t in made_into(transformation.from_piece) {
+                    let mut path = path.clone();
+                    path.push(t.from_piece.0);
+                    next.push((t, path));
+                }
+            }
+            next.sort_by_key(|(t, _)| t.id);
+            steps = next;
+        }
+
+        Ok(recipe)
+    }
+
+    async fn get_bom(&self, id: i64) -> Result<Bom> {
+        let tables = self.tables.lock().unwrap();
+        tables.bom.get(&id).copied().ok_or(Error::NotFound)
+    }
+
+    async fn get_bom_by_order(&self, order_id: i64) -> Result<Vec<Bom>> {
+        let tables = self.tables.lock().unwrap();
+        let mut entries = tables
+            .bom
+            .values()
+            .filter(|entry| entry.order_id == order_id)
+            .copied()
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| (entry.piece_number, entry.step_number));
+        Ok(entries)
+    }
+
+    async fn delete_bom_by_order(&self, order_id: i64) -> Result<u64> {
+        let mut tables = self.tables.lock().unwrap();
+        let before = tables.bom.len();
+        tables.bom.retain(|_, entry| entry.order_id != order_id);
+        Ok((before - tables.bom.len()) as u64)
+    }
+
+    async fn resolve_order_bom(
+        &self,
+        order_id: i64,
+        batch: &[Bom],
+    ) -> Result<Vec<i64>> {
+        let mut tables = self.tables.lock().unwrap();
+        let status =
+            tables.orders.get(&order_id).ok_or(Error::NotFound)?.status;
+        if !status.can_change_quantity() {
+            return Err(Error::InvalidTransition {
+                order_id,
+                from: status,
+                to: OrderStatus::BomResolved,
+            });
+        }
+        for entry in batch {
+            if !tables.orders.contains_key(&entry.order_id) {
+                return Err(Error::ForeignKeyViolation {
+                    constraint: Some("bom_order_id_fkey".to_string()),
+                });
+            }
+        }
+
+        tables.bom.retain(|_, entry| entry.order_id != order_id);
+        let mut ids = Vec::with_capacity(batch.len());
+        for entry in batch {
+            let id = tables.next_bom_id;
+            tables.next_bom_id += 1;
+            tables.bom.insert(id, Bom { id, ..*entry });
+            ids.push(id);
+        }
+
+        let orders = batch
+            .iter()
+            .map(|entry| entry.order_id)
+            .collect::<BTreeSet<_>>();
+        for order_id in orders {
+            tables.enqueue_job(&events::NewBomEntry { order_id });
+        }
+
+        if status == OrderStatus::Received {
+            let resolved = OrderStatus::BomResolved;
+            tables
+                .orders
+                .get_mut(&order_id)
+                .expect("order exists")
+                .status = resolved;
+            tables.publish(&events::OrderStatusChanged {
+                order_id,
+                from: status,
+                to: resolved,
+            });
+        }
+        Ok(ids)
+    }
+
+    async fn claim_job(&self) -> Result<Option<Job>> {
+        let mut tables = self.tables.lock().unwrap();
+        let claimable = tables
+            .jobs
+            .iter_mut()
+            .find(|(_, status)| *status == JobStatus::Pending);
+        Ok(claimable.map(|(job, status)| {
+            job.attempts += 1;
+            *status = JobStatus::Running;
+            job.clone()
+        }))
+    }
+
+    async fn complete_job(&self, job: &Job) -> Result<()> {
+        let mut tables = self.tables.lock().unwrap();
+        let (_, status) = tables
+            .jobs
+            .iter_mut()
+            .find(|(claimed, status)| {
+                claimed == job && *status == JobStatus::Running
+            })
+            .ok_or(Error::NotFound)?;
+        *status = JobStatus::Done;
+        Ok(())
+    }
+
+    async fn fail_job(&self, job: &Job, _error: &str) -> Result<JobStatus> {
+        let mut tables = self.tables.lock().unwrap();
+        let (job, status) = tables
+            .jobs
+            .iter_mut()
+            .find(|(claimed, status)| {
+                claimed == job && *status == JobStatus::Running
+            })
+            .ok_or(Error::NotFound)?;
+        *status = if job.attempts >= Self::MAX_ATTEMPTS {
+            JobStatus::Failed
+        } else {
+            JobStatus::Pending
+        };
+        Ok(*status)
+    }
+}
@@ -0,0 +1,340 @@
+use crate::{
+    parse_money, AmendOrder, Catalog, ClientOrder, Currency, Order,
+    OrderAmendment, PieceKind, Result,
+};
+use sqlx::PgPool;
+
+/// Largest quantity a single order may ask for.
+pub const MAX_ORDER_QUANTITY: i32 = 1000;
+
+/// Kind of the pieces that clients are allowed to order.
+pub const ORDERABLE_PIECE_KIND: PieceKind = PieceKind::FinalProduct;
+
+/// A reason for refusing an order before it reaches the database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrderProblem {
+    QuantityOutOfRange {
+        quantity: i32,
+    },
+    DueDateNotInFuture {
+        due_date: i32,
+        current_day: i32,
+    },
+    UnknownPiece {
+        piece: String,
+    },
+    NotFinalProduct {
+        piece: String,
+        kind: PieceKind,
+    },
+    InvalidPenalty {
+        field: &'static str,
+        value: String,
+        reason: String,
+    },
+    EmptyAmendment,
+}
+
+impl std::fmt::Display for OrderProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use OrderProblem as Op;
+        match self {
+            Op::QuantityOutOfRange { quantity } => write!(
+                f,
+                "quantity {quantity} is not between 1 and {MAX_ORDER_QUANTITY}"
+            ),
+            Op::DueDateNotInFuture {
+                due_date,
+                current_day,
+            } => write!(
+                f,
+                "due date {due_date} is not after the current day {current_day}"
+            ),
+            Op::UnknownPiece { piece } => {
+                write!(f, "work piece {piece} does not exist")
+            }
+            Op::NotFinalProduct { piece, kind } => {
+                write!(f, "work piece {piece} is not a final product ({kind})")
+            }
+            Op::InvalidPenalty {
+                field,
+                value,
+                reason,
+            } => write!(f, "{field} \"{value}\" is not valid: {reason}"),
+            Op::EmptyAmendment => {
+                write!(f, "amendment does not change anything")
+            }
+        }
+    }
+}
+
+/// Checks an order against the plant's rules before it is placed.
+/// Every problem found is returned, an empty list means the order is valid.
+/// The work piece is looked up in `catalog`.
+pub async fn validate_client_order(
+    pool: &PgPool,
+    catalog: &Catalog,
+    ClientOrder { order, .. }: &ClientOrder,
+) -> Result<Vec<OrderProblem>> {
+    let current_day = crate::get_current_day(pool).await?;
+    let piece_kind = catalog
+        .piece(&order.work_piece)
+        .ok()
+        .map(|piece| piece.kind);
+
+    Ok(check_order(order, current_day, piece_kind))
+}
+
+/// Checks an amendment against the same rules as a new order.
+/// The rules only apply to the fields the amendment changes.
+pub async fn validate_order_amendment(
+    pool: &PgPool,
+    AmendOrder { order, .. }: &AmendOrder,
+) -> Result<Vec<OrderProblem>> {
+    let current_day = crate::get_current_day(pool).await?;
+    Ok(check_amendment(order, current_day))
+}
+
+/// Validation rules that do not depend on the database.
+/// `piece_kind` is the kind of the ordered piece, if the piece exists.
+fn check_order(
+    order: &Order,
+    current_day: i32,
+    piece_kind: Option<PieceKind>,
+) -> Vec<OrderProblem> {
+    let mut problems = Vec::new();
+
+    problems.extend(check_quantity(order.quantity));
+    problems.extend(check_due_date(order.due_date, current_day));
+
+    let piece = order.work_piece.clone();
+    match piece_kind {
+        None => problems.push(OrderProblem::UnknownPiece { piece }),
+        Some(kind) if kind != ORDERABLE_PIECE_KIND => {
+            problems.push(OrderProblem::NotFinalProduct { piece, kind })
+        }
+        Some(_) => {}
+    }
+
+    problems.extend(check_penalty("LatePen", &order.late_pen));
+    problems.extend(check_penalty("EarlyPen", &order.early_pen));
+
+    problems
+}
+
+fn check_amendment(
+    amendment: &OrderAmendment,
+    current_day: i32,
+) -> Vec<OrderProblem> {
+    let OrderAmendment {
+        number: _,
+        quantity,
+        due_date,
+        late_pen,
+        early_pen,
+    } = amendment;
+
+    if quantity.is_none()
+        && due_date.is_none()
+        && late_pen.is_none()
+        && early_pen.is_none()
+    {
+        return vec![OrderProblem::EmptyAmendment];
+    }
+
+    let mut problems = Vec::new();
+    problems.extend(quantity.and_then(check_quantity));
+    problems.extend(due_date.and_then(|d| check_due_date(d, current_day)));
+    problems
+        .extend(late_pen.as_ref().and_then(|p| check_penalty("LatePen", p)));
+    problems.extend(
+        early_pen
+            .as_ref()
+            .and_then(|p| check_penalty("EarlyPen", p)),
+    );
+    problems
+}
+
+fn check_quantity(quantity: i32) -> Option<OrderProblem> {
+    if (1..=MAX_ORDER_QUANTITY).contains(&quantity) {
+        return None;
+    }
+    Some(OrderProblem::QuantityOutOfRange { quantity })
+}
+
+fn check_due_date(due_date: i32, current_day: i32) -> Option<OrderProblem> {
+    if due_date > current_day {
+        return None;
+    }
+    Some(OrderProblem::DueDateNotInFuture {
+        due_date,
+        current_day,
+    })
+}
+
+fn check_penalty(field: &'static str, value: &str) -> Option<OrderProblem> {
+    let reason = match parse_money(value) {
+        Err(e) => e.to_string(),
+        Ok(money) if money.cents < 0 => "penalty is negative".to_string(),
+        Ok(money) if money.currency != Currency::default() => {
+            format!("only {} is accepted", Currency::default())
+        }
+        Ok(_) => return None,
+    };
+    Some(OrderProblem::InvalidPenalty {
+        field,
+        value: value.to_string(),
+        reason,
+    })
+}
+
+/// Keeps a record of an order that failed validation, with the reasons.
+pub async fn record_rejected_order(
+    pool: &PgPool,
+    ClientOrder { client, order, .. }: &ClientOrder,
+    problems: &[OrderProblem],
+) -> Result<i64> {
+    let reasons = problems.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+
+    Ok(sqlx::query!(
+        "INSERT INTO rejected_orders (
+            client_name,
+            number,
+            work_piece,
+            quantity,
+            due_date,
+            late_pen,
+            early_pen,
+            reasons
+        )
+        VALUES
+            ($1, $2, $3, $4, $5, $6, $7, $8)
+        RETURNING id
+        ",
+        client.name_id,
+        order.number,
+        order.work_piece,
+        order.quantity,
+        order.due_date,
+        order.late_pen,
+        order.early_pen,
+        &reasons
+    )
+    .fetch_one(pool)
+    .await?
+    .id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(quantity: i32, due_date: i32, late_pen: &str) -> Order {
+        Order {
+            number: 1,
+            work_piece: "P9".to_string(),
+            quantity,
+            due_date,
+            late_pen: late_pen.to_string(),
+            early_pen: "€66,32".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_valid_order() {
+        let order = order(3, 4, "€5,74");
+        assert!(
+            check_order(&order, 1, Some(PieceKind::FinalProduct)).is_empty()
+        );
+    }
+
+    #[test]
+    fn test_every_problem_is_reported() {
+        let order = order(0, 2, "free");
+        let problems = check_order(&order, 2, Some(PieceKind::Intermediate));
+
+        assert_eq!(
+            problems,
+            vec![
+                OrderProblem::QuantityOutOfRange { quantity: 0 },
+                OrderProblem::DueDateNotInFuture {
+                    due_date: 2,
+                    current_day: 2
+                },
+                OrderProblem::NotFinalProduct {
+                    piece: "P9".to_string(),
+                    kind: PieceKind::Intermediate
+                },
+                OrderProblem::InvalidPenalty {
+                    field: "LatePen",
+                    value: "free".to_string(),
+                    reason: "unknown currency \"free\"".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_penalty_must_be_positive_euros() {
+        let problems = check_order(
+            &order(3, 4, "$5.74"),
+            1,
+            Some(PieceKind::FinalProduct),
+        );
+        assert_eq!(
+            problems,
+            vec![OrderProblem::InvalidPenalty {
+                field: "LatePen",
+                value: "$5.74".to_string(),
+                reason: "only EUR is accepted".to_string(),
+            }]
+        );
+
+        let problems =
+            check_order(&order(3, 4, "-5€"), 1, Some(PieceKind::FinalProduct));
+        assert_eq!(problems.len(), 1);
+    }
+
+    #[test]
+    fn test_amendment_only_checks_changed_fields() {
+        let mut amendment = OrderAmendment {
+            number: 1,
+            quantity: None,
+            due_date: None,
+            late_pen: None,
+            early_pen: None,
+        };
+        assert_eq!(
+            check_amendment(&amendment, 1),
+            vec![OrderProblem::EmptyAmendment]
+        );
+
+        amendment.quantity = Some(5);
+        assert!(check_amendment(&amendment, 1).is_empty());
+
+        amendment.due_date = Some(1);
+        assert_eq!(
+            check_amendment(&amendment, 1),
+            vec![OrderProblem::DueDateNotInFuture {
+                due_date: 1,
+                current_day: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn test_unknown_piece_and_quantity_limit() {
+        let order = order(MAX_ORDER_QUANTITY + 1, 4, "€5,74");
+        assert_eq!(
+            check_order(&order, 1, None),
+            vec![
+                OrderProblem::QuantityOutOfRange {
+                    quantity: MAX_ORDER_QUANTITY + 1
+                },
+                OrderProblem::UnknownPiece {
+                    piece: "P9".to_string()
+                },
+            ]
+        );
+    }
+}